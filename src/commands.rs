@@ -0,0 +1,429 @@
+use crate::modes;
+use crate::protocol::{self, ProtocolError};
+use crate::settings::Mode;
+
+use log::debug;
+use serde_derive::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+// Queries masterpower_api doesn't provide, issued over the raw protocol
+
+/// QFLAG - Device flag status inquiry
+/// The response lists enabled flags after `E` and disabled ones after `D`, e.g. `EakxyDbjuvz`.
+#[derive(Serialize, Debug, Default)]
+pub struct QFlag {
+    pub buzzer: bool,
+    pub overload_bypass: bool,
+    pub power_saving: bool,
+    pub lcd_escape_to_default: bool,
+    pub overload_restart: bool,
+    pub over_temperature_restart: bool,
+    pub lcd_backlight: bool,
+    pub primary_source_interrupt_alarm: bool,
+    pub fault_code_record: bool,
+}
+
+impl QFlag {
+    pub async fn query<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> Result<Self, ProtocolError> {
+        let response = protocol::query(stream, "QFLAG").await?;
+        QFlag::parse(&response)
+    }
+
+    fn parse(response: &str) -> Result<Self, ProtocolError> {
+        let mut flags = QFlag::default();
+        let mut enabled = None;
+        for letter in response.chars() {
+            match (letter, enabled) {
+                ('E', _) => enabled = Some(true),
+                ('D', _) => enabled = Some(false),
+                ('a', Some(state)) => flags.buzzer = state,
+                ('b', Some(state)) => flags.overload_bypass = state,
+                ('j', Some(state)) => flags.power_saving = state,
+                ('k', Some(state)) => flags.lcd_escape_to_default = state,
+                ('u', Some(state)) => flags.overload_restart = state,
+                ('v', Some(state)) => flags.over_temperature_restart = state,
+                ('x', Some(state)) => flags.lcd_backlight = state,
+                ('y', Some(state)) => flags.primary_source_interrupt_alarm = state,
+                ('z', Some(state)) => flags.fault_code_record = state,
+                _ => return Err(ProtocolError::Malformed(response.to_string())),
+            }
+        }
+        Ok(flags)
+    }
+}
+
+/// QPIGS - Device general status parameters inquiry.
+/// Firmware revisions differ in how many fields follow the documented 21, so only the leading 17 are required,
+/// the rest are optional and anything past them is kept as is in `raw_tail`.
+#[derive(Serialize, Debug)]
+pub struct QPigs {
+    pub grid_voltage: f32,
+    pub grid_frequency: f32,
+    pub ac_out_voltage: f32,
+    pub ac_out_frequency: f32,
+    pub ac_out_apparent_power: u32,
+    pub ac_out_active_power: u32,
+    pub out_load_percent: u32,
+    pub bus_voltage: u32,
+    pub battery_voltage: f32,
+    pub battery_charge_current: u32,
+    pub battery_capacity: u32,
+    pub inverter_heat_sink_temp: i32,
+    pub pv_input_current: f32,
+    pub pv_input_voltage: f32,
+    pub battery_scc_voltage: f32,
+    pub battery_discharge_current: u32,
+    pub device_status: DeviceStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub battery_voltage_offset_for_fans_on: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub eeprom_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pv_charging_power: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_status2: Option<String>,
+    // Fields past the documented ones, unparsed
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub raw_tail: Vec<String>,
+}
+
+/// The QPIGS device status bits, b7 first
+#[derive(Serialize, Debug)]
+pub struct DeviceStatus {
+    pub sbu_priority_version: bool,
+    pub configuration_changed: bool,
+    pub scc_firmware_updated: bool,
+    pub active_load: bool,
+    pub battery_voltage_steady: bool,
+    pub charging: bool,
+    pub scc_charging: bool,
+    pub ac_charging: bool,
+    // not_charging, scc, ac or scc_and_ac
+    pub charge_status: String,
+}
+
+// Fields in the documented QPIGS response
+const QPIGS_FIELDS: usize = 21;
+
+// Fields a response must have to be usable, up to and including the device status
+const QPIGS_REQUIRED_FIELDS: usize = 17;
+
+/// The QPIGS AC readings and the topics they're also published to under qpigs/. The response leads with the grid
+/// (input) voltage and frequency, then the AC output voltage and frequency - BBB.B CC.C DDD.D EE.E in the protocol.
+pub const QPIGS_AC_FIELDS: [(&str, &str); 4] = [("grid_voltage", "ac_in_voltage"), ("grid_frequency", "ac_in_freq"), ("ac_out_voltage", "ac_out_voltage"), ("ac_out_frequency", "ac_out_freq")];
+
+impl QPigs {
+    pub async fn query<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> Result<Self, ProtocolError> {
+        let response = protocol::query(stream, "QPIGS").await?;
+        QPigs::parse(&response)
+    }
+
+    fn parse(response: &str) -> Result<Self, ProtocolError> {
+        let malformed = || ProtocolError::Malformed(response.to_string());
+        let fields: Vec<&str> = response.split_whitespace().collect();
+        if fields.len() < QPIGS_REQUIRED_FIELDS {
+            return Err(malformed());
+        }
+        if fields.len() != QPIGS_FIELDS {
+            debug!("QPIGS returned {} fields where {} are documented: {}", fields.len(), QPIGS_FIELDS, response);
+        }
+        let status = fields[16];
+        if status.len() != 8 || !status.bytes().all(|bit| bit == b'0' || bit == b'1') {
+            return Err(malformed());
+        }
+        let bit = |index: usize| status.as_bytes()[index] == b'1';
+        let charge_status = match &status[5..] {
+            "110" => "scc",
+            "101" => "ac",
+            "111" => "scc_and_ac",
+            _ => "not_charging",
+        };
+        // Optional fields are left out rather than failing the whole response when they don't parse
+        let optional = |index: usize| fields.get(index).and_then(|field| field.parse().ok());
+        Ok(QPigs {
+            grid_voltage: fields[0].parse().map_err(|_| malformed())?,
+            grid_frequency: fields[1].parse().map_err(|_| malformed())?,
+            ac_out_voltage: fields[2].parse().map_err(|_| malformed())?,
+            ac_out_frequency: fields[3].parse().map_err(|_| malformed())?,
+            ac_out_apparent_power: fields[4].parse().map_err(|_| malformed())?,
+            ac_out_active_power: fields[5].parse().map_err(|_| malformed())?,
+            out_load_percent: fields[6].parse().map_err(|_| malformed())?,
+            bus_voltage: fields[7].parse().map_err(|_| malformed())?,
+            battery_voltage: fields[8].parse().map_err(|_| malformed())?,
+            battery_charge_current: fields[9].parse().map_err(|_| malformed())?,
+            battery_capacity: fields[10].parse().map_err(|_| malformed())?,
+            inverter_heat_sink_temp: fields[11].parse().map_err(|_| malformed())?,
+            pv_input_current: fields[12].parse().map_err(|_| malformed())?,
+            pv_input_voltage: fields[13].parse().map_err(|_| malformed())?,
+            battery_scc_voltage: fields[14].parse().map_err(|_| malformed())?,
+            battery_discharge_current: fields[15].parse().map_err(|_| malformed())?,
+            device_status: DeviceStatus {
+                sbu_priority_version: bit(0),
+                configuration_changed: bit(1),
+                scc_firmware_updated: bit(2),
+                active_load: bit(3),
+                battery_voltage_steady: bit(4),
+                charging: bit(5),
+                scc_charging: bit(6),
+                ac_charging: bit(7),
+                charge_status: charge_status.to_string(),
+            },
+            battery_voltage_offset_for_fans_on: optional(17),
+            eeprom_version: fields.get(18).map(|field| field.to_string()),
+            pv_charging_power: optional(19),
+            device_status2: fields.get(20).map(|field| field.to_string()),
+            raw_tail: fields.iter().skip(QPIGS_FIELDS).map(|field| field.to_string()).collect(),
+        })
+    }
+}
+
+/// QPIGS2 - Second PV input status inquiry, only answered by units with two trackers
+#[derive(Serialize, Debug)]
+pub struct QPigs2 {
+    pub pv2_input_current: f32,
+    pub pv2_input_voltage: f32,
+    pub pv2_charging_power: u32,
+}
+
+impl QPigs2 {
+    pub async fn query<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> Result<Self, ProtocolError> {
+        let response = protocol::query(stream, "QPIGS2").await?;
+        QPigs2::parse(&response)
+    }
+
+    fn parse(response: &str) -> Result<Self, ProtocolError> {
+        let malformed = || ProtocolError::Malformed(response.to_string());
+        let fields: Vec<&str> = response.split_whitespace().collect();
+        if fields.len() < 3 {
+            return Err(malformed());
+        }
+        Ok(QPigs2 {
+            pv2_input_current: fields[0].parse().map_err(|_| malformed())?,
+            pv2_input_voltage: fields[1].parse().map_err(|_| malformed())?,
+            pv2_charging_power: fields[2].parse().map_err(|_| malformed())?,
+        })
+    }
+}
+
+/// QMOD - Device mode inquiry, the response is a single letter such as `L` for line mode
+#[derive(Serialize, Debug)]
+pub struct QMod {
+    pub mode: String,
+    pub description: String,
+}
+
+impl QMod {
+    pub async fn query<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, mode: Mode) -> Result<Self, ProtocolError> {
+        let response = protocol::query(stream, "QMOD").await?;
+        QMod::parse(&response, mode)
+    }
+
+    fn parse(response: &str, mode: Mode) -> Result<Self, ProtocolError> {
+        let code = response.trim();
+        if code.is_empty() {
+            return Err(ProtocolError::Malformed(response.to_string()));
+        }
+        Ok(QMod {
+            mode: code.to_string(),
+            description: modes::describe(mode, code).to_string(),
+        })
+    }
+}
+
+/// QMN - Model name inquiry, e.g. `VMII-5000`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QMn {
+    pub model: String,
+}
+
+// Model name prefixes and the mode their protocol needs
+const MODEL_MODES: [(&str, Mode); 6] = [("PSW-H", Mode::Phocos), ("ANY-GRID", Mode::Phocos), ("VMII", Mode::Standard), ("VMIII", Mode::Standard), ("MKS", Mode::Standard), ("MAX", Mode::Standard)];
+
+impl QMn {
+    pub async fn query<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> Result<Self, ProtocolError> {
+        let response = protocol::query(stream, "QMN").await?;
+        QMn::parse(&response)
+    }
+
+    fn parse(response: &str) -> Result<Self, ProtocolError> {
+        let model = response.trim();
+        if model.is_empty() {
+            return Err(ProtocolError::Malformed(response.to_string()));
+        }
+        Ok(QMn { model: model.to_string() })
+    }
+
+    /// The mode to run in for this model, if it's one we know
+    pub fn mode(&self) -> Option<Mode> {
+        let model = self.model.to_ascii_uppercase();
+        MODEL_MODES.iter().find(|(prefix, _)| model.starts_with(prefix)).map(|(_, mode)| *mode)
+    }
+}
+
+/// QT - Device time inquiry, the response is the inverter's local time as `YYYYMMDDHHMMSS`
+#[derive(Serialize, Debug)]
+pub struct QT {
+    pub time: String,
+    // Positive when the inverter is ahead of the system clock
+    pub clock_drift_seconds: i64,
+}
+
+impl QT {
+    pub async fn query<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> Result<Self, ProtocolError> {
+        let response = protocol::query(stream, "QT").await?;
+        QT::parse(&response)
+    }
+
+    fn parse(response: &str) -> Result<Self, ProtocolError> {
+        let malformed = || ProtocolError::Malformed(response.to_string());
+        let digits = response.trim();
+        if digits.len() != 14 || !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+            return Err(malformed());
+        }
+        let field = |range: std::ops::Range<usize>| digits[range].parse::<i32>().map_err(|_| malformed());
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        tm.tm_year = field(0..4)? - 1900;
+        tm.tm_mon = field(4..6)? - 1;
+        tm.tm_mday = field(6..8)?;
+        tm.tm_hour = field(8..10)?;
+        tm.tm_min = field(10..12)?;
+        tm.tm_sec = field(12..14)?;
+        // Let mktime work out daylight saving for the inverter's local time
+        tm.tm_isdst = -1;
+        let inverter = unsafe { libc::mktime(&mut tm) };
+        if inverter == -1 {
+            return Err(malformed());
+        }
+        Ok(QT {
+            time: format!("{}-{}-{}T{}:{}:{}", &digits[0..4], &digits[4..6], &digits[6..8], &digits[8..10], &digits[10..12], &digits[12..14]),
+            clock_drift_seconds: i64::from(inverter) - unix_now(),
+        })
+    }
+}
+
+/// The system's local time in the `YYMMDDHHMMSS` form DAT sets the inverter clock with
+pub fn local_time_for_dat() -> String {
+    let now = unsafe { libc::time(std::ptr::null_mut()) };
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&now, &mut tm) };
+    format!("{:02}{:02}{:02}{:02}{:02}{:02}", tm.tm_year % 100, tm.tm_mon + 1, tm.tm_mday, tm.tm_hour, tm.tm_min, tm.tm_sec)
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs() as i64).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A different value in each AC position so readings landing in the wrong field show up
+    const QPIGS: &str = "231.5 49.9 229.8 50.1 0161 0119 003 460 57.50 012 100 0069 0014 103.8 57.45 00000 00110110 00 00 00856 010";
+
+    #[test]
+    fn qpigs_ac_readings_are_in_protocol_order() {
+        let qpigs = QPigs::parse(QPIGS).unwrap();
+        assert_eq!(qpigs.grid_voltage, 231.5);
+        assert_eq!(qpigs.grid_frequency, 49.9);
+        assert_eq!(qpigs.ac_out_voltage, 229.8);
+        assert_eq!(qpigs.ac_out_frequency, 50.1);
+    }
+
+    #[test]
+    fn qpigs_ac_fields_follow_the_response_positions() {
+        let qpigs = serde_json::to_value(QPigs::parse(QPIGS).unwrap()).unwrap();
+        let fields: Vec<&str> = QPIGS.split_whitespace().collect();
+        for (position, (field, name)) in QPIGS_AC_FIELDS.iter().enumerate() {
+            let expected: f32 = fields[position].parse().unwrap();
+            assert_eq!(qpigs[field].as_f64(), Some(f64::from(expected)), "{} isn't field {} of the response", field, position);
+            // The input readings lead, then the output ones
+            assert_eq!(name.starts_with("ac_in_"), position < 2, "{} is published from field {}", name, position);
+        }
+    }
+
+    #[test]
+    fn parses_qpigs() {
+        let qpigs = QPigs::parse(QPIGS).unwrap();
+        assert_eq!(qpigs.battery_voltage, 57.5);
+        assert_eq!(qpigs.inverter_heat_sink_temp, 69);
+        assert_eq!(qpigs.pv_input_voltage, 103.8);
+        assert!(qpigs.device_status.scc_charging && !qpigs.device_status.ac_charging);
+        assert_eq!(qpigs.device_status.charge_status, "scc");
+        assert_eq!(qpigs.pv_charging_power, Some(856));
+        assert!(qpigs.raw_tail.is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_qpigs() {
+        // Cut off before the device status
+        assert!(QPigs::parse("231.5 49.9 229.8 50.1 0161 0119 003 460 57.50 012 100 0069 0014 103.8 57.45 00000").is_err());
+        // A status that isn't 8 bits
+        assert!(QPigs::parse(&QPIGS.replace("00110110", "0011011")).is_err());
+        assert!(QPigs::parse(&QPIGS.replace("231.5", "23I.5")).is_err());
+    }
+
+    #[test]
+    fn parses_qpigs2() {
+        let qpigs2 = QPigs2::parse("03.1 327.3 01026").unwrap();
+        assert_eq!(qpigs2.pv2_input_current, 3.1);
+        assert_eq!(qpigs2.pv2_input_voltage, 327.3);
+        assert_eq!(qpigs2.pv2_charging_power, 1026);
+    }
+
+    #[test]
+    fn rejects_malformed_qpigs2() {
+        assert!(QPigs2::parse("03.1 327.3").is_err());
+        assert!(QPigs2::parse("03.1 327.3 -1026").is_err());
+    }
+
+    #[test]
+    fn parses_qflag() {
+        let flags = QFlag::parse("EakxyDbjuvz").unwrap();
+        assert!(flags.buzzer && flags.lcd_escape_to_default && flags.lcd_backlight && flags.primary_source_interrupt_alarm);
+        assert!(!flags.overload_bypass && !flags.power_saving && !flags.overload_restart && !flags.over_temperature_restart && !flags.fault_code_record);
+    }
+
+    #[test]
+    fn rejects_malformed_qflag() {
+        // A flag before saying whether it's enabled
+        assert!(QFlag::parse("aEkx").is_err());
+        assert!(QFlag::parse("EaqDb").is_err());
+    }
+
+    #[test]
+    fn parses_qmod() {
+        let qmod = QMod::parse("B", Mode::Standard).unwrap();
+        assert_eq!(qmod.mode, "B");
+        assert_eq!(qmod.description, "Battery Mode");
+    }
+
+    #[test]
+    fn rejects_malformed_qmod() {
+        assert!(QMod::parse(" ", Mode::Standard).is_err());
+    }
+
+    #[test]
+    fn parses_qmn() {
+        let qmn = QMn::parse("VMII-5000").unwrap();
+        assert_eq!(qmn.model, "VMII-5000");
+        assert_eq!(qmn.mode(), Some(Mode::Standard));
+    }
+
+    #[test]
+    fn rejects_malformed_qmn() {
+        assert!(QMn::parse("").is_err());
+    }
+
+    #[test]
+    fn parses_qt() {
+        // The drift depends on when the test runs, so only the time is checked
+        assert_eq!(QT::parse("20240101120000").unwrap().time, "2024-01-01T12:00:00");
+    }
+
+    #[test]
+    fn rejects_malformed_qt() {
+        assert!(QT::parse("202401011200").is_err());
+        assert!(QT::parse("2024010112000A").is_err());
+    }
+}
@@ -0,0 +1,244 @@
+use crate::settings::{DerivedSensorSettings, Settings};
+
+use serde_json::Value;
+use std::collections::HashMap;
+
+// Values computed from other commands' fields and published to {topic}/{name}.
+// Expressions are arithmetic (+ - * / and brackets) over numbers and command.field references, e.g.
+// `qpigs.pv_input_voltage * qpigs.pv_input_current`.
+
+/// The derived sensors every install gets, ahead of any from the settings
+pub fn built_in(settings: &Settings) -> Vec<DerivedSensorSettings> {
+    let mut pv_power = String::from("qpigs.pv_input_voltage * qpigs.pv_input_current");
+    if settings.has_second_pv {
+        pv_power.push_str(" + qpigs2.pv2_input_voltage * qpigs2.pv2_input_current");
+    }
+    vec![DerivedSensorSettings {
+        name: String::from("pv_power_total"),
+        expression: pv_power,
+        unit: Some(String::from("W")),
+        device_class: Some(String::from("power")),
+    }]
+}
+
+/// Built in and configured derived sensors together, as discovery registers them
+pub fn all(settings: &Settings) -> Vec<DerivedSensorSettings> {
+    built_in(settings).into_iter().chain(settings.derived.iter().cloned()).collect()
+}
+
+#[derive(Debug)]
+pub enum Expr {
+    Number(f64),
+    Field { command: String, field: String },
+    Negate(Box<Expr>),
+    Binary(Box<Expr>, char, Box<Expr>),
+}
+
+impl Expr {
+    /// Evaluates against the latest readings, None while a field is missing or the result isn't a number
+    fn eval(&self, readings: &HashMap<String, Value>) -> Option<f64> {
+        let value = match self {
+            Expr::Number(number) => *number,
+            Expr::Field { command, field } => readings.get(command)?.get(field)?.as_f64()?,
+            Expr::Negate(expr) => -expr.eval(readings)?,
+            Expr::Binary(left, op, right) => {
+                let (left, right) = (left.eval(readings)?, right.eval(readings)?);
+                match op {
+                    '+' => left + right,
+                    '-' => left - right,
+                    '*' => left * right,
+                    _ => left / right,
+                }
+            }
+        };
+        Some(value).filter(|value| value.is_finite())
+    }
+
+    fn uses(&self, name: &str) -> bool {
+        match self {
+            Expr::Number(_) => false,
+            Expr::Field { command, .. } => command == name,
+            Expr::Negate(expr) => expr.uses(name),
+            Expr::Binary(left, _, right) => left.uses(name) || right.uses(name),
+        }
+    }
+}
+
+/// Parses an expression, the error says what was expected where
+pub fn parse(expression: &str) -> Result<Expr, String> {
+    let mut parser = Parser { chars: expression.chars().collect(), position: 0 };
+    let expr = parser.sum()?;
+    parser.skip_whitespace();
+    match parser.peek() {
+        None => Ok(expr),
+        Some(c) => Err(format!("unexpected {:?} at {}", c, parser.position)),
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).cloned()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().map_or(false, char::is_whitespace) {
+            self.position += 1;
+        }
+    }
+
+    fn sum(&mut self) -> Result<Expr, String> {
+        let mut expr = self.product()?;
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                Some(op) if op == '+' || op == '-' => {
+                    self.position += 1;
+                    expr = Expr::Binary(Box::new(expr), op, Box::new(self.product()?));
+                }
+                _ => return Ok(expr),
+            }
+        }
+    }
+
+    fn product(&mut self) -> Result<Expr, String> {
+        let mut expr = self.term()?;
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                Some(op) if op == '*' || op == '/' => {
+                    self.position += 1;
+                    expr = Expr::Binary(Box::new(expr), op, Box::new(self.term()?));
+                }
+                _ => return Ok(expr),
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr, String> {
+        self.skip_whitespace();
+        let start = self.position;
+        match self.peek() {
+            Some('-') => {
+                self.position += 1;
+                Ok(Expr::Negate(Box::new(self.term()?)))
+            }
+            Some('(') => {
+                self.position += 1;
+                let expr = self.sum()?;
+                self.skip_whitespace();
+                if self.peek() != Some(')') {
+                    return Err(format!("expected ')' at {}", self.position));
+                }
+                self.position += 1;
+                Ok(expr)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let number = self.take_while(|c| c.is_ascii_digit() || c == '.');
+                number.parse().map(Expr::Number).map_err(|_| format!("invalid number {:?} at {}", number, start))
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                let reference = self.take_while(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+                let parts: Vec<&str> = reference.split('.').collect();
+                match parts.as_slice() {
+                    [command, field] if !field.is_empty() => Ok(Expr::Field { command: command.to_string(), field: field.to_string() }),
+                    _ => Err(format!("expected command.field at {} (got {:?})", start, reference)),
+                }
+            }
+            Some(c) => Err(format!("unexpected {:?} at {}", c, start)),
+            None => Err(String::from("unexpected end of expression")),
+        }
+    }
+
+    fn take_while<F: Fn(char) -> bool>(&mut self, accept: F) -> String {
+        let start = self.position;
+        while self.peek().map_or(false, &accept) {
+            self.position += 1;
+        }
+        self.chars[start..self.position].iter().collect()
+    }
+}
+
+/// Keeps the latest reading of each command and works out the derived sensors that depend on it
+pub struct Derived {
+    sensors: Vec<(String, Expr)>,
+    readings: HashMap<String, Value>,
+}
+
+impl Derived {
+    /// Expressions were checked when the settings loaded, so any that don't parse here are left out
+    pub fn new(settings: &Settings) -> Self {
+        Derived {
+            sensors: all(settings).into_iter().filter_map(|sensor| parse(&sensor.expression).ok().map(|expr| (sensor.name, expr))).collect(),
+            readings: HashMap::new(),
+        }
+    }
+
+    /// Stores a command's reading and returns the derived sensors it changed, rounded to 2 decimal places
+    pub fn record(&mut self, command: &str, json: &str) -> Vec<(String, f64)> {
+        match serde_json::from_str(json) {
+            Ok(value) => self.readings.insert(command.to_string(), value),
+            Err(_) => return Vec::new(),
+        };
+        let readings = &self.readings;
+        self.sensors.iter().filter(|(_, expr)| expr.uses(command)).filter_map(|(name, expr)| expr.eval(readings).map(|value| (name.clone(), (value * 100.0).round() / 100.0))).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn readings(command: &str, json: &str) -> HashMap<String, Value> {
+        let mut readings = HashMap::new();
+        readings.insert(command.to_string(), serde_json::from_str(json).unwrap());
+        readings
+    }
+
+    #[test]
+    fn evaluates_with_precedence_and_brackets() {
+        let none = HashMap::new();
+        assert_eq!(parse("1 + 2 * 3").unwrap().eval(&none), Some(7.0));
+        assert_eq!(parse("(1 + 2) * 3").unwrap().eval(&none), Some(9.0));
+        assert_eq!(parse("10 - 4 - 3").unwrap().eval(&none), Some(3.0));
+        assert_eq!(parse("-2 * -(1.5)").unwrap().eval(&none), Some(3.0));
+    }
+
+    #[test]
+    fn evaluates_field_references() {
+        let expr = parse("qpigs.pv_input_voltage * qpigs.pv_input_current").unwrap();
+        assert_eq!(expr.eval(&readings("qpigs", r#"{"pv_input_voltage": 100.0, "pv_input_current": 2.5}"#)), Some(250.0));
+        // Missing or non-numeric fields, and results that aren't numbers, give nothing rather than a wrong value
+        assert_eq!(expr.eval(&readings("qpigs", r#"{"pv_input_voltage": 100.0}"#)), None);
+        assert_eq!(expr.eval(&readings("qpigs", r#"{"pv_input_voltage": 100.0, "pv_input_current": "2.5"}"#)), None);
+        assert_eq!(parse("1 / 0").unwrap().eval(&HashMap::new()), None);
+    }
+
+    #[test]
+    fn rejects_malformed_expressions() {
+        assert_eq!(parse("1 +").unwrap_err(), "unexpected end of expression");
+        assert_eq!(parse("(1 + 2").unwrap_err(), "expected ')' at 6");
+        assert_eq!(parse("1 2").unwrap_err(), "unexpected '2' at 2");
+        assert_eq!(parse("qpigs").unwrap_err(), "expected command.field at 0 (got \"qpigs\")");
+        assert_eq!(parse("1.2.3").unwrap_err(), "invalid number \"1.2.3\" at 0");
+        assert_eq!(parse("2 % 3").unwrap_err(), "unexpected '%' at 2");
+    }
+
+    #[test]
+    fn records_only_the_sensors_a_command_changes() {
+        let mut derived = Derived {
+            sensors: vec![
+                (String::from("pv_power"), parse("qpigs.pv_input_voltage * qpigs.pv_input_current").unwrap()),
+                (String::from("pv2_power"), parse("qpigs2.pv2_input_voltage * qpigs2.pv2_input_current").unwrap()),
+            ],
+            readings: HashMap::new(),
+        };
+        assert_eq!(derived.record("qpigs", r#"{"pv_input_voltage": 103.8, "pv_input_current": 1.4}"#), vec![(String::from("pv_power"), 145.32)]);
+        assert_eq!(derived.record("qpigs2", r#"{"pv2_input_voltage": 327.3, "pv2_input_current": 3.1}"#), vec![(String::from("pv2_power"), 1014.63)]);
+        assert!(derived.record("qpigs", "not json").is_empty());
+    }
+}
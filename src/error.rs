@@ -0,0 +1,117 @@
+use crate::queue::{CommandError, QueueError};
+use crate::settings::SettingsError;
+
+use config::ConfigError;
+use std::fmt;
+
+/// Everything that can stop an inverter's pipeline, split by where it came from so the loops can tell a
+/// flaky response from a lost serial line or broker
+#[derive(Debug)]
+pub enum MpqttError {
+    // A command failed on the serial line - a NAK, an unexpected response, the line dropping or the queue stopping
+    Serial(CommandError),
+    // A command's response was still garbled (bad crc or unparseable) after every retry
+    Crc(CommandError),
+    // A command went unanswered
+    Timeout(CommandError),
+    // Connecting, publishing or subscribing to the broker failed
+    MqttPublish(mqtt_async_client::Error),
+    // Bad settings, simulation fixtures or certificates
+    Config(String),
+    // Opening the inverter device or other local IO
+    Io(std::io::Error),
+    // A payload couldn't be encoded or decoded
+    Json(serde_json::Error),
+}
+
+impl MpqttError {
+    /// The failed command, for errors that came from one
+    pub fn command(&self) -> Option<&CommandError> {
+        match self {
+            MpqttError::Serial(error) | MpqttError::Crc(error) | MpqttError::Timeout(error) => Some(error),
+            _ => None,
+        }
+    }
+
+    /// Whether the serial line itself has failed (e.g. the USB adapter re-enumerated) and the device needs reopening
+    pub fn is_disconnected(&self) -> bool {
+        matches!(self, MpqttError::Serial(CommandError { error: QueueError::Io(_), .. }))
+    }
+
+    /// The expected and received crc, when a response failed its crc check
+    pub fn crc_mismatch(&self) -> Option<(u16, u16)> {
+        match self {
+            MpqttError::Crc(CommandError { error: QueueError::Crc { expected, actual }, .. }) => Some((*expected, *actual)),
+            _ => None,
+        }
+    }
+
+    /// Whether the inverter refused the command, usually because the model doesn't support it
+    pub fn is_nak(&self) -> bool {
+        matches!(self, MpqttError::Serial(CommandError { error: QueueError::Nak, .. }))
+    }
+}
+
+impl fmt::Display for MpqttError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MpqttError::Serial(error) | MpqttError::Crc(error) | MpqttError::Timeout(error) => write!(f, "{}", error),
+            MpqttError::MqttPublish(error) => write!(f, "{}", error),
+            MpqttError::Config(message) => write!(f, "{}", message),
+            MpqttError::Io(error) => write!(f, "{}", error),
+            MpqttError::Json(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for MpqttError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MpqttError::Serial(error) | MpqttError::Crc(error) | MpqttError::Timeout(error) => Some(error),
+            MpqttError::MqttPublish(error) => Some(error),
+            MpqttError::Config(_) => None,
+            MpqttError::Io(error) => Some(error),
+            MpqttError::Json(error) => Some(error),
+        }
+    }
+}
+
+impl From<CommandError> for MpqttError {
+    fn from(error: CommandError) -> Self {
+        match error.error {
+            QueueError::Crc { .. } | QueueError::Corrupt(_) => MpqttError::Crc(error),
+            QueueError::Timeout(_) => MpqttError::Timeout(error),
+            _ => MpqttError::Serial(error),
+        }
+    }
+}
+
+impl From<mqtt_async_client::Error> for MpqttError {
+    fn from(error: mqtt_async_client::Error) -> Self {
+        MpqttError::MqttPublish(error)
+    }
+}
+
+impl From<SettingsError> for MpqttError {
+    fn from(error: SettingsError) -> Self {
+        MpqttError::Config(error.to_string())
+    }
+}
+
+impl From<ConfigError> for MpqttError {
+    fn from(error: ConfigError) -> Self {
+        MpqttError::Config(error.to_string())
+    }
+}
+
+impl From<std::io::Error> for MpqttError {
+    fn from(error: std::io::Error) -> Self {
+        MpqttError::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for MpqttError {
+    fn from(error: serde_json::Error) -> Self {
+        MpqttError::Json(error)
+    }
+}
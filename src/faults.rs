@@ -0,0 +1,91 @@
+// Descriptions for the fault code QPGS reports per parallel unit, per protocol mode so another protocol can list its own codes
+
+use crate::settings::Mode;
+
+use serde_derive::Serialize;
+use serde_json::Value;
+use std::convert::TryFrom;
+
+pub struct Fault {
+    pub code: u8,
+    pub description: &'static str,
+}
+
+const STANDARD_FAULTS: &[Fault] = &[
+    Fault { code: 0, description: "No fault" },
+    Fault {
+        code: 1,
+        description: "Fan is locked when inverter is off",
+    },
+    Fault { code: 2, description: "Over temperature" },
+    Fault { code: 3, description: "Battery voltage is too high" },
+    Fault { code: 4, description: "Battery voltage is too low" },
+    Fault {
+        code: 5,
+        description: "Output short circuited or over temperature",
+    },
+    Fault { code: 6, description: "Output voltage is too high" },
+    Fault { code: 7, description: "Overload time out" },
+    Fault { code: 8, description: "Bus voltage is too high" },
+    Fault { code: 9, description: "Bus soft start failed" },
+    Fault { code: 11, description: "Main relay failed" },
+    Fault { code: 51, description: "Over current or surge" },
+    Fault { code: 52, description: "Bus voltage is too low" },
+    Fault { code: 53, description: "Inverter soft start failed" },
+    Fault { code: 55, description: "Over DC voltage in AC output" },
+    Fault { code: 57, description: "Current sensor failed" },
+    Fault { code: 58, description: "Output voltage is too low" },
+    Fault { code: 59, description: "PV voltage is over the limit" },
+    Fault { code: 60, description: "Power feedback protection" },
+    Fault {
+        code: 71,
+        description: "Firmware versions differ between units",
+    },
+    Fault { code: 72, description: "Current sharing fault" },
+    Fault { code: 80, description: "CAN fault" },
+    Fault { code: 81, description: "Host loss" },
+    Fault { code: 82, description: "Synchronisation loss" },
+    Fault {
+        code: 83,
+        description: "Battery voltage detected differently between units",
+    },
+    Fault {
+        code: 84,
+        description: "AC input voltage or frequency detected differently between units",
+    },
+    Fault { code: 85, description: "AC output current unbalanced" },
+    Fault {
+        code: 86,
+        description: "AC output mode set differently between units",
+    },
+];
+
+fn table(mode: Mode) -> &'static [Fault] {
+    match mode {
+        Mode::Standard | Mode::Phocos => STANDARD_FAULTS,
+    }
+}
+
+/// The description of a fault code, or "Unknown fault" for codes the protocol's table doesn't list
+pub fn describe(mode: Mode, code: u8) -> &'static str {
+    table(mode).iter().find(|known| known.code == code).map_or("Unknown fault", |known| known.description)
+}
+
+// Published to {topic}/qpgs<n>/fault_text
+#[derive(Serialize, Debug)]
+pub struct FaultText {
+    pub code: u8,
+    pub text: &'static str,
+}
+
+/// Decodes the fault_code of a published QPGS payload, None when it's missing or not a code
+pub fn decode(mode: Mode, json: &str) -> Option<FaultText> {
+    let value: Value = serde_json::from_str(json).ok()?;
+    let code = match value.get("fault_code")? {
+        Value::Number(number) => number.as_u64()?,
+        Value::String(text) => text.trim().parse().ok()?,
+        _ => return None,
+    };
+    let code = u8::try_from(code).ok()?;
+    Some(FaultText { code, text: describe(mode, code) })
+}
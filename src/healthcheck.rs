@@ -0,0 +1,53 @@
+use crate::settings::Settings;
+
+use log::warn;
+use std::time::{Duration, SystemTime};
+
+// `mpqtt --healthcheck` for container HEALTHCHECKs - passes while every inverter's heartbeat file
+// has been touched recently, without needing the MQTT broker
+
+/// Checks the heartbeat files and returns the process exit code
+pub fn run(config_path: &str) -> i32 {
+    let settings = match Settings::new(config_path) {
+        Ok(settings) => settings,
+        Err(e) => {
+            println!("Error loading configuration file {}: {}", config_path, e);
+            return 1;
+        }
+    };
+    let mut healthy = true;
+    for settings in settings.split_inverters() {
+        match check(&settings) {
+            Ok(age) => println!("{} updated {}s ago", settings.heartbeat_file.as_deref().unwrap_or_default(), age.as_secs()),
+            Err(message) => {
+                println!("{}", message);
+                healthy = false;
+            }
+        }
+    }
+    if healthy {
+        0
+    } else {
+        1
+    }
+}
+
+fn check(settings: &Settings) -> Result<Duration, String> {
+    let path = match settings.heartbeat_file {
+        Some(ref path) => path,
+        None => return Err(String::from("heartbeat_file isn't set so there is nothing to check")),
+    };
+    let modified = std::fs::metadata(path).and_then(|metadata| metadata.modified()).map_err(|err| format!("{} can't be read: {}", path, err))?;
+    let age = SystemTime::now().duration_since(modified).unwrap_or_default();
+    if age > Duration::from_secs(settings.heartbeat_max_age) {
+        return Err(format!("{} is {}s old - polling has stalled", path, age.as_secs()));
+    }
+    Ok(age)
+}
+
+/// Marks a successful update. Failing to write the file is only logged since it doesn't affect polling.
+pub async fn touch(path: &str) {
+    if let Err(err) = tokio::fs::write(path, format!("{:?}\n", SystemTime::now())).await {
+        warn!("Could not update heartbeat file {}: {}", path, err);
+    }
+}
@@ -0,0 +1,149 @@
+use crate::publisher;
+use crate::queue::{CommandQueue, Query, QueueError};
+use crate::settings::{HttpSettings, Mode, MqttSettings};
+
+use log::{error, info, warn};
+use mqtt_async_client::client::{Client, Publish as PublishOpts};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+struct Endpoint {
+    queue: CommandQueue,
+    mqtt: MqttSettings,
+    mode: Mode,
+    // False until init succeeds and while the update loop is recovering from an error
+    ready: Arc<AtomicBool>,
+}
+
+/// Inverters the HTTP API can reach, keyed by inverter id ("" for a single unnamed inverter)
+#[derive(Clone, Default)]
+pub struct Api {
+    endpoints: Arc<Mutex<HashMap<String, Endpoint>>>,
+}
+
+/// Keeps an inverter reachable over HTTP until dropped.
+/// Dropping it releases the queue handle, which lets the owner task stop with the poll loop.
+pub struct Registration {
+    api: Api,
+    id: String,
+    ready: Arc<AtomicBool>,
+}
+
+impl Registration {
+    pub fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.api.endpoints.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl Api {
+    pub fn register(&self, id: &str, queue: CommandQueue, mqtt: &MqttSettings, mode: Mode) -> Registration {
+        let ready = Arc::new(AtomicBool::new(false));
+        let endpoint = Endpoint { queue, mqtt: mqtt.clone(), mode, ready: ready.clone() };
+        self.endpoints.lock().unwrap().insert(id.to_string(), endpoint);
+        Registration { api: self.clone(), id: id.to_string(), ready }
+    }
+
+    // Picks the inverter named in `?inverter=`, or the only one registered
+    fn lookup(&self, id: Option<&str>) -> Option<(CommandQueue, MqttSettings, String, bool)> {
+        let endpoints = self.endpoints.lock().unwrap();
+        let endpoint = match id {
+            Some(id) => endpoints.get(id),
+            None if endpoints.len() == 1 => endpoints.values().next(),
+            None => None,
+        }?;
+        Some((endpoint.queue.clone(), endpoint.mqtt.clone(), endpoint.mode, endpoint.ready.load(Ordering::Relaxed)))
+    }
+}
+
+/// Serves `GET /query/<command>` until the process exits, publishing with an already connected client. Responses are
+/// published to MQTT as well as returned.
+pub async fn spawn_server(settings: &HttpSettings, client: Client, api: Api) -> Result<(), Box<dyn std::error::Error>> {
+    let address = format!("{}:{}", settings.bind, settings.port);
+    let mut listener = TcpListener::bind(&address).await?;
+    info!("Serving the query API on http://{}/query/<command>", address);
+
+    let client = Arc::new(client);
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(err) => {
+                    error!("Error accepting HTTP connection: {}", err);
+                    continue;
+                }
+            };
+            let api = api.clone();
+            let client = client.clone();
+            tokio::spawn(async move {
+                let mut request = [0u8; 1024];
+                let read = match socket.read(&mut request).await {
+                    Ok(read) => read,
+                    Err(_) => return,
+                };
+                let request = String::from_utf8_lossy(&request[..read]).to_string();
+                let (status, body) = handle(&request, &api, &client).await;
+                let response = format!("HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body);
+                let _ = socket.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+    Ok(())
+}
+
+async fn handle(request: &str, api: &Api, client: &Client) -> (&'static str, String) {
+    let path = match request.lines().next().and_then(|line| line.strip_prefix("GET ")).and_then(|line| line.split(' ').next()) {
+        Some(path) => path,
+        None => return ("405 Method Not Allowed", error_body("only GET is supported")),
+    };
+    let (path, params) = match path.find('?') {
+        Some(split) => (&path[..split], &path[split + 1..]),
+        None => (path, ""),
+    };
+    let name = match path.strip_prefix("/query/") {
+        Some(name) => name.to_ascii_lowercase(),
+        None => return ("404 Not Found", error_body("not found")),
+    };
+    let inverter = params.split('&').find_map(|param| param.strip_prefix("inverter="));
+
+    let (queue, mqtt, mode, ready) = match api.lookup(inverter) {
+        Some(endpoint) => endpoint,
+        None => return ("503 Service Unavailable", error_body("inverter is not available")),
+    };
+    let query = match Query::from_name(&name, mode) {
+        Some(query) => query,
+        None => return ("404 Not Found", error_body(&format!("unknown command {}", name))),
+    };
+    if !ready {
+        return ("503 Service Unavailable", error_body("inverter is reconnecting"));
+    }
+
+    match queue.execute(query).await {
+        Ok(json) => {
+            let (qos, retain) = mqtt.publish.resolve(&name);
+            let payload = if mqtt.timestamp_payloads { publisher::timestamped(&json, SystemTime::now()) } else { json.clone() };
+            let mut msg = PublishOpts::new(mqtt.state_topic(&name), payload.into_bytes());
+            msg.set_qos(qos);
+            msg.set_retain(retain);
+            if let Err(err) = client.publish(&msg).await {
+                warn!("Failed to publish {} from the query API: {}", name, err);
+            }
+            ("200 OK", json)
+        }
+        Err(err @ QueueError::Timeout(_)) | Err(err @ QueueError::Closed) => ("503 Service Unavailable", error_body(&err.to_string())),
+        Err(err) => ("502 Bad Gateway", error_body(&err.to_string())),
+    }
+}
+
+fn error_body(message: &str) -> String {
+    serde_json::json!({ "error": message }).to_string()
+}
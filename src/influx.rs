@@ -0,0 +1,78 @@
+use crate::settings::InfluxSettings;
+
+use serde_json::Value;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "influx")]
+use log::{debug, warn};
+
+/// Buffers readings as InfluxDB line protocol and writes them in one request per update cycle.
+/// Without the influx feature, or with it disabled in the settings, nothing is recorded.
+#[cfg_attr(not(feature = "influx"), allow(dead_code))]
+pub struct Influx {
+    #[cfg(feature = "influx")]
+    client: reqwest::Client,
+    enabled: bool,
+    write_url: String,
+    token: String,
+    // Tag added to every line, left off for a single unnamed inverter
+    inverter: String,
+    lines: Vec<String>,
+}
+
+impl Influx {
+    pub fn new(settings: &InfluxSettings, inverter: &str) -> Self {
+        Influx {
+            #[cfg(feature = "influx")]
+            client: reqwest::Client::new(),
+            enabled: settings.enabled && cfg!(feature = "influx"),
+            write_url: format!("{}/api/v2/write?org={}&bucket={}&precision=ns", settings.url.trim_end_matches('/'), settings.org, settings.bucket),
+            token: settings.token.clone(),
+            inverter: inverter.to_string(),
+            lines: Vec::new(),
+        }
+    }
+
+    /// Queues a line holding every numeric field of the response, with the command as the measurement
+    pub fn record(&mut self, command: &str, json: &str) {
+        if !self.enabled {
+            return;
+        }
+        let response = match serde_json::from_str::<Value>(json) {
+            Ok(Value::Object(response)) => response,
+            _ => return,
+        };
+        let fields: Vec<String> = response.iter().filter_map(|(name, value)| value.as_f64().map(|value| format!("{}={}", escape(name), value))).collect();
+        if fields.is_empty() {
+            return;
+        }
+        let mut series = escape(command);
+        if !self.inverter.is_empty() {
+            series.push_str(&format!(",inverter={}", escape(&self.inverter)));
+        }
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_nanos()).unwrap_or_default();
+        self.lines.push(format!("{} {} {}", series, fields.join(","), timestamp));
+    }
+
+    /// Writes everything queued since the last flush. Failures are only logged so MQTT publishing carries on.
+    pub async fn flush(&mut self) {
+        #[cfg(feature = "influx")]
+        {
+            if self.lines.is_empty() {
+                return;
+            }
+            let count = self.lines.len();
+            let body = self.lines.drain(..).collect::<Vec<String>>().join("\n");
+            let result = self.client.post(&self.write_url).header("Authorization", format!("Token {}", self.token)).body(body).send().await.and_then(|response| response.error_for_status());
+            match result {
+                Ok(_) => debug!("Wrote {} lines to InfluxDB", count),
+                Err(err) => warn!("Failed to write {} lines to InfluxDB: {}", count, err),
+            }
+        }
+    }
+}
+
+// Measurements, tag values and field keys can't hold unescaped commas, spaces or equals signs
+fn escape(name: &str) -> String {
+    name.replace(',', "\\,").replace(' ', "\\ ").replace('=', "\\=")
+}
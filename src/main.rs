@@ -1,280 +1,1452 @@
 #![warn(clippy::all)]
 
+mod commands;
+mod derived;
+mod error;
+mod faults;
+mod healthcheck;
+mod http;
+mod influx;
+mod metrics;
+mod modes;
 mod mqtt_discovery;
+mod on_demand;
+mod protocol;
+mod publisher;
+mod queue;
+mod reload;
+mod replay;
+mod sanity;
+mod scheduler;
+mod serial;
+mod set_commands;
 mod settings;
+mod shutdown;
+mod simulator;
+mod smoothing;
+mod state;
+mod tls;
+mod warnings;
+use crate::commands::{QMn, QPIGS_AC_FIELDS};
+use crate::derived::Derived;
+use crate::error::MpqttError;
+use crate::http::Api;
+use crate::influx::Influx;
+use crate::metrics::Metrics;
 use crate::mqtt_discovery::run_mqtt_discovery;
-use crate::settings::MqttSettings;
-use settings::Settings;
-
-use masterpower_api::commands::qid::QID;
-use masterpower_api::commands::qmod::QMOD;
-use masterpower_api::commands::qpgs::{QPGS0, QPGS1, QPGS2, QPGS3, QPGS4, QPGS5, QPGS6, QPGS7, QPGS8, QPGS9};
-use masterpower_api::commands::qpi::QPI;
-use masterpower_api::commands::qpigs::QPIGS;
-use masterpower_api::commands::qpiri::QPIRIReduced;
-use masterpower_api::commands::qpiri::QPIRI;
-use masterpower_api::commands::qpiws::QPIWS;
-use masterpower_api::commands::qvfw::QVFW;
-// use masterpower_api::commands::qvfw2::QVFW2;
-// use masterpower_api::commands::qvfw3::QVFW3;
-use masterpower_api::inverter::Inverter;
-
-use libc::{open, O_RDWR};
-use log::{debug, error, info};
-use mqtt_async_client::client::{Client as MQTTClient, KeepAlive, Publish as PublishOpts, QoS};
+use crate::on_demand::OnDemand;
+use crate::publisher::{Outgoing, Publisher};
+use crate::queue::{is_recoverable_error, CommandError, CommandQueue, Query, QueueError};
+use crate::reload::{ReloadWatch, Reloads};
+use crate::scheduler::Scheduler;
+use crate::serial::{SerialCounters, SerialStream};
+use crate::set_commands::SetHandler;
+use crate::settings::{ClearError, Mode, MqttSettings, PublishMode};
+use replay::Replay;
+use settings::{ErrorFormat, LogFormat, Settings};
+use shutdown::Shutdown;
+use simulator::Simulator;
+use smoothing::Smoother;
+
+use futures::future::{join, join_all};
+use log::{debug, error, info, warn};
+use mqtt_async_client::client::{Client as MQTTClient, KeepAlive, QoS};
 use serde_derive::Serialize;
-use std::os::unix::ffi::OsStrExt;
-use std::os::unix::io::FromRawFd;
-use std::path::Path;
-use std::thread::sleep;
-use std::time::Instant;
-use tokio::fs::File;
-use tokio::time::Duration;
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio::time::{delay_for, Duration};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(message) => {
+            println!("{}", message);
+            println!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+    if args.help {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+    if args.version {
+        println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        return Ok(());
+    }
+    let config_path = settings::config_path(args.config.as_deref());
+    if args.healthcheck {
+        std::process::exit(healthcheck::run(&config_path));
+    }
+    if args.validate_config {
+        match Settings::new(&config_path) {
+            Ok(_) => println!("Configuration file {} is valid", config_path),
+            Err(e) => {
+                println!("Error loading configuration file {}: {}", config_path, e);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
     println!("Starting {} version {}", env!("CARGO_PKG_NAME").to_ascii_uppercase(), env!("CARGO_PKG_VERSION"));
 
     // Load configuration
-    let settings = match Settings::new() {
+    // Flags that stand in for config values, applied before it's validated
+    let mut overrides = Vec::new();
+    if let Some(ref capture) = args.replay {
+        overrides.push(("replay", capture.as_str()));
+    }
+    let mut settings = match Settings::with_overrides(&config_path, &overrides) {
         Ok(settings) => settings,
         Err(e) => {
-            println!("Error loading configuration file: {}", e);
+            println!("Error loading configuration file {}: {}", config_path, e);
             std::process::exit(1);
         }
     };
 
-    // Enable debugging
-    if settings.debug {
-        std::env::set_var("RUST_LOG", "warn,mpqtt=trace,masterpower_api=trace");
-        info!("Enabled debug output");
-    } else {
-        std::env::set_var("RUST_LOG", "error,mpqtt=info,masterpower_api=info");
+    settings.run_once |= args.once;
+
+    // Set up logging - an externally set RUST_LOG wins over the config file
+    let filter = std::env::var("RUST_LOG").unwrap_or_else(|_| settings.log_filter());
+    match settings.log_format {
+        LogFormat::Pretty => pretty_env_logger::formatted_timed_builder().parse_filters(&filter).init(),
+        LogFormat::Json => env_logger::Builder::new()
+            .parse_filters(&filter)
+            .format(|buf, record| {
+                let line = serde_json::json!({
+                    "timestamp": buf.timestamp().to_string(),
+                    "level": record.level().to_string(),
+                    "target": record.target(),
+                    "message": record.args().to_string(),
+                });
+                writeln!(buf, "{}", line)
+            })
+            .init(),
     }
-    pretty_env_logger::init_timed();
+    debug!("Logging with filter {}", filter);
 
-    // Create MQTT Connection
-    info!("Connecting to MQTT Broker at: {}:{}", settings.mqtt.host, settings.mqtt.port);
-    let mut builder = mqtt_async_client::client::Client::builder();
-    let mut mqtt_client = match builder
-        .set_host(settings.mqtt.host.clone())
-        .set_port(settings.mqtt.port)
-        .set_username(Option::from(settings.mqtt.username.clone()))
-        .set_password(Option::from(settings.mqtt.password.as_bytes().to_vec()))
-        .set_client_id(Option::from(settings.mqtt.client_id.clone()))
-        .set_connect_retry_delay(Duration::from_secs(1))
-        .set_keep_alive(KeepAlive::from_secs(5))
-        .set_operation_timeout(Duration::from_secs(10))
-        .set_automatic_connect(true)
-        .build()
+    // Serve metrics for Prometheus
+    let metrics = Metrics::default();
+    #[cfg(feature = "metrics")]
     {
+        if settings.metrics.enabled {
+            metrics::spawn_server(&settings.metrics, metrics.clone()).await?;
+        }
+    }
+
+    // Serve single queries over HTTP
+    let api = Api::default();
+    if settings.http.enabled {
+        let mut http_client = build_mqtt_client(&settings.mqtt, format!("{}-http", settings.mqtt.client_id))?;
+        connect_with_retry(&mut http_client, &settings.mqtt).await?;
+        http::spawn_server(&settings.http, http_client, api.clone()).await?;
+    }
+
+    // Reload the timing on SIGHUP
+    let overrides = overrides.into_iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+    let reloads = Reloads::listen(config_path.clone(), overrides, settings.clone())?;
+
+    // Poll every inverter side by side - an error on one port leaves the others running
+    let runs = settings.split_inverters().into_iter().map(|settings| {
+        let id = settings.inverter().id.clone().unwrap_or_default();
+        let metrics = metrics.for_inverter(&id);
+        let api = api.clone();
+        let reload = reloads.watch();
+        async move {
+            let result = run(settings, metrics, api, reload).await;
+            if let Err(ref error) = result {
+                error!("Stopped polling inverter {}: {}", id, error);
+            }
+            result.is_ok()
+        }
+    });
+    if !join_all(runs).await.into_iter().all(|ok| ok) {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+const USAGE: &str = "Usage: mpqtt [--config <path>] [--once] [--replay <path>] [--healthcheck] [--validate-config] [--version] [--help]
+
+  --config <path>     configuration file, defaults to $MPQTT_CONFIG then the packaged config
+  --once              run init and a single update then exit, 1 if the update failed
+  --replay <path>     answer commands with the frames of a captured serial log instead of the inverter
+  --healthcheck       exit 0 if the running instance updated recently, 1 otherwise
+  --validate-config   load and validate the configuration then exit 0 if it's valid, 1 otherwise
+  --version           print the version and exit
+  --help              print this help and exit";
+
+// Command line flags, there are few enough that a parser crate isn't worth it
+#[derive(Default)]
+struct Args {
+    // Overrides $MPQTT_CONFIG and the built in config path
+    config: Option<String>,
+    healthcheck: bool,
+    // Load the config and exit without connecting to anything
+    validate_config: bool,
+    // Same as run_once in the config
+    once: bool,
+    // Same as replay in the config
+    replay: Option<String>,
+    version: bool,
+    help: bool,
+}
+
+impl Args {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, String> {
+        let mut parsed = Args::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--healthcheck" => parsed.healthcheck = true,
+                "--validate-config" => parsed.validate_config = true,
+                "--once" => parsed.once = true,
+                "--version" | "-V" => parsed.version = true,
+                "--help" | "-h" => parsed.help = true,
+                "--config" => parsed.config = Some(args.next().ok_or("--config needs a path")?),
+                "--replay" => parsed.replay = Some(args.next().ok_or("--replay needs a path")?),
+                _ => match arg.strip_prefix("--config=") {
+                    Some(path) => parsed.config = Some(path.to_string()),
+                    None => return Err(format!("Unknown argument {}", arg)),
+                },
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+// Runs the whole pipeline for one inverter, from connecting to MQTT through to the update loop
+async fn run(settings: Settings, metrics: Metrics, api: Api, mut reload: ReloadWatch) -> Result<(), MpqttError> {
+    // Create MQTT Connection
+    info!("Connecting to MQTT Broker at: {}:{} as {}", settings.mqtt.host, settings.mqtt.port(), settings.mqtt.client_id);
+    let mut client = match build_mqtt_client(&settings.mqtt, settings.mqtt.client_id.clone()) {
         Ok(val) => val,
         Err(err) => {
             error!("Problem with MQTT client builder: {}", err);
-            std::process::exit(0);
+            return Err(err);
         }
     };
 
-    mqtt_client.connect().await?;
+    // mqtt-async-client (0.1.7, and every release since) always connects with `last_will: None` and has no setter
+    // for one, so no will is registered. The offline state is published explicitly on the paths where we stop
+    // updating, and a crash or dropped connection leaves `payload_online` retained until the next start.
+    connect_with_retry(&mut client, &settings.mqtt).await?;
+    let mut mqtt_client = Publisher::new(client, settings.mqtt.republish_on_reconnect);
     info!("Connected to MQTT Broker");
+    publish_event(&mqtt_client, &settings.mqtt, Event::Started).await?;
+    publish_event(&mqtt_client, &settings.mqtt, Event::MqttConnected).await?;
 
-    // Run MQTT Discovery
-    run_mqtt_discovery(&mqtt_client, &settings.mqtt, settings.inverter_count, &settings.mode).await?;
+    if settings.mqtt.message_expiry_secs > 0 {
+        warn!("mqtt.message_expiry_secs is set but the MQTT client only supports v3.1.1, which has no message expiry - retained topics are published without one");
+    }
+    if settings.mqtt.legacy_stats_topics {
+        warn!("inner_stats and outer_stats are deprecated and will be removed next release - read stats/inner and stats/outer instead, and set mqtt.legacy_stats_topics to false");
+    }
 
-    // Open inverter tty device -
-    // TODO wrap open call in for loop with timeout and a break on success
-    let stream = match raw_open(settings.inverter.path.clone()) {
-        Ok(stream) => stream,
-        Err(err) => {
-            // Handle error opening inverter
-            // TODO wrap in loop to retry publish on fails
-            publish_error(&mqtt_client, &settings.mqtt, err.to_string()).await?;
-            error!("Could not open inverter communication {}", err);
-            todo!("implement retrying on file not found or couldn't open with warn! before error!");
-        }
+    // Run MQTT Discovery, with an automatic device_id it waits for the serial number from init
+    if settings.mqtt.discovery.auto_device_id() {
+        debug!("Discovery waits for the serial number from QID");
+    } else {
+        run_mqtt_discovery(
+            &mqtt_client,
+            &settings.mqtt,
+            &settings.qpgs_indexes(),
+            settings.mode,
+            settings.has_second_pv,
+            &settings.disabled_commands,
+            &derived::all(&settings),
+            settings.stale_threshold_secs > 0,
+        )
+        .await?;
+    }
+    if settings.mqtt.publish_info {
+        publish_info(&mqtt_client, &settings).await?;
+    }
+
+    // Listen for write commands on a separate client so subscriptions don't need the publishing client
+    let (set_sender, set_receiver) = mpsc::channel(16);
+    if settings.write_commands {
+        let set_client = build_mqtt_client(&settings.mqtt, format!("{}-set", settings.mqtt.client_id))?;
+        set_commands::spawn_listener(set_client, settings.mqtt.topic.clone(), set_sender).await?;
+    }
+
+    // Messages on {topic}/poll run the next update straight away
+    let mut on_demand = if settings.poll_on_demand {
+        let poll_client = build_mqtt_client(&settings.mqtt, format!("{}-poll", settings.mqtt.client_id))?;
+        OnDemand::listen(poll_client, &settings.mqtt).await?
+    } else {
+        OnDemand::default()
     };
 
-    // Clear previous errors
-    // TODO wrap in loop to retry publish on fails
-    clear_error(&mqtt_client, &settings.mqtt).await?;
+    // Open inverter tty device
+    debug!(
+        "Inverter at {} - {} baud, {} data bits, {:?} parity, {} stop bits",
+        settings.inverter().path,
+        settings.inverter().baud_rate,
+        settings.inverter().data_bits,
+        settings.inverter().parity,
+        settings.inverter().stop_bits
+    );
+    let mut shutdown = Shutdown::listen()?;
+    let mut set_handler = SetHandler::new(set_receiver);
+    let mut reconnects = 0;
+    let serial_counters = SerialCounters::default();
+    loop {
+        let stream = match open_inverter(&mqtt_client, &settings, &mut shutdown).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                publish_availability(&mqtt_client, &settings.mqtt, false).await?;
+                if shutdown.is_requested() {
+                    mqtt_client.disconnect().await?;
+                    return Ok(());
+                }
+                error!("Could not open inverter communication {}", err);
+                return Err(err);
+            }
+        };
+
+        publish_event(&mqtt_client, &settings.mqtt, Event::SerialOpened).await?;
+
+        let startup_delay = settings.inverter().startup_delay_secs;
+        if startup_delay > 0 {
+            info!("Waiting {}s for the inverter to be ready before init", startup_delay);
+            if shutdown.sleep(Duration::from_secs(startup_delay)).await {
+                break;
+            }
+        }
+
+        // Clear previous errors
+        // TODO wrap in loop to retry publish on fails
+        clear_error(&mqtt_client, &settings.mqtt).await?;
+
+        // Hand the inverter to the owner task
+        let (queue, owner) = queue::new(stream, Duration::from_millis(settings.command_timeout_ms), serial_counters.clone());
+
+        // poll consumes the queue handle, so the owner stops (closing the device) once polling does
+        let ((), session) = join(owner.run(), poll(queue, &mut set_handler, &mut on_demand, &mqtt_client, &settings, &mut shutdown, &metrics, &api, &mut reload)).await;
+        match session? {
+            Session::Stopped => break,
+            Session::Disconnected { error, initialised } => {
+                if initialised {
+                    reconnects = 0;
+                }
+                reconnects += 1;
+                publish_event(&mqtt_client, &settings.mqtt, Event::SerialLost).await?;
+                publish_availability(&mqtt_client, &settings.mqtt, false).await?;
+                if reconnects > settings.inverter().max_reconnect_attempts {
+                    error!("Lost the inverter ({}) and gave up after {} reconnects", error, settings.inverter().max_reconnect_attempts);
+                    return Err(error);
+                }
+                warn!("Lost the inverter ({}) - reconnecting (attempt {}/{})", error, reconnects, settings.inverter().max_reconnect_attempts);
+            }
+        }
+    }
+
+    // Shut down - every publish above has been awaited so there's nothing left in flight
+    info!("Stopping updates");
+    if let Err(error) = publish_event(&mqtt_client, &settings.mqtt, Event::Shutdown).await {
+        error!("Failed to publish shutdown event: {}", error);
+    }
+    if let Err(error) = publish_availability(&mqtt_client, &settings.mqtt, false).await {
+        error!("Failed to publish offline availability: {}", error);
+    }
+    if settings.mqtt.discovery.clear_on_exit {
+        mqtt_client.clear_discovery().await;
+    }
+    mqtt_client.disconnect().await?;
+    info!("Disconnected from MQTT Broker");
+    Ok(())
+}
 
-    // Create inverter instance
-    let mut inverter = Inverter::from_stream(stream);
+// How a poll session ended
+enum Session {
+    // Shutdown was requested
+    Stopped,
+    // The serial line failed and needs reopening. initialised is false if it failed before init completed.
+    Disconnected { error: MpqttError, initialised: bool },
+}
 
+// Initialises the inverter then runs the update loop until shutdown or the serial line fails
+#[allow(clippy::too_many_arguments)]
+async fn poll(queue: CommandQueue, set_handler: &mut SetHandler, on_demand: &mut OnDemand, mqtt_client: &Publisher, settings: &Settings, shutdown: &mut Shutdown, metrics: &Metrics, api: &Api, reload: &mut ReloadWatch) -> Result<Session, MpqttError> {
     // Start
-    let init_res = init(&mut inverter, &mqtt_client, &settings).await;
-    if let Err(error) = init_res {
-        publish_error(&mqtt_client, &settings.mqtt, error.to_string()).await?;
-        error!("Error initialising inverter: {}", error);
-        todo!("implement retrying on file not found or couldn't open with warn! before error!");
-        // std::process::exit(1);
+    let init_res = if settings.skip_init {
+        debug!("Skipping init commands");
+        Ok((None, None))
+    } else {
+        init(&queue, &mqtt_client, &settings).await
+    };
+    let (model, serial) = match init_res {
+        Ok(init) => init,
+        Err(error) => {
+            let (command, message) = describe_error(&error);
+            publish_error(&mqtt_client, &settings.mqtt, command, message, error.crc_mismatch()).await?;
+            publish_availability(&mqtt_client, &settings.mqtt, false).await?;
+            error!("Error initialising inverter: {}", error);
+            if error.is_disconnected() {
+                return Ok(Session::Disconnected { error, initialised: false });
+            }
+            return Err(error);
+        }
+    };
+
+    // A recognised model picks the mode, otherwise the configured one is used
+    let mut detected = None;
+    match model.as_ref().map(|model| (model, model.mode())) {
+        Some((model, Some(mode))) if mode != settings.mode => {
+            warn!("Inverter model {} needs {} mode - using it instead of the configured {} mode", model.model, mode, settings.mode);
+            let mut with_mode = settings.clone();
+            with_mode.mode = mode;
+            detected = Some(with_mode);
+        }
+        Some((model, None)) => info!("Inverter model {} isn't recognised - using the configured {} mode", model.model, settings.mode),
+        _ => (),
+    }
+    // An automatic device_id is the serial number, which discovery was held back for
+    if settings.mqtt.discovery.auto_device_id() {
+        let device_id = match serial {
+            Some(serial) => {
+                info!("Registering discovery under the inverter's serial number {}", serial);
+                serial
+            }
+            None => {
+                warn!("No serial number from QID - registering discovery under the client_id {} instead", settings.mqtt.client_id);
+                settings.mqtt.client_id.clone()
+            }
+        };
+        detected.get_or_insert_with(|| settings.clone()).mqtt.discovery.device_id = device_id;
+    }
+
+    let detected_settings;
+    let settings = match detected {
+        Some(detected) => {
+            run_mqtt_discovery(
+                &mqtt_client,
+                &detected.mqtt,
+                &detected.qpgs_indexes(),
+                detected.mode,
+                detected.has_second_pv,
+                &detected.disabled_commands,
+                &derived::all(&detected),
+                detected.stale_threshold_secs > 0,
+            )
+            .await?;
+            detected_settings = detected;
+            &detected_settings
+        }
+        None => settings,
+    };
+
+    let registration = api.register(settings.inverter().id.as_deref().unwrap_or_default(), queue.clone(), &settings.mqtt, settings.mode);
+    publish_availability(&mqtt_client, &settings.mqtt, true).await?;
+    registration.set_ready(true);
+
+    // The loop works on its own copy so a SIGHUP can swap its timing, the watchdogs keep the settings it started with
+    let watched = settings;
+    let mut settings = settings.clone();
+    if let Some(reloaded) = reload.current() {
+        reload::apply(&mut settings, reloaded);
     }
 
     // Update loop
-    loop {
-        match update(&mut inverter, &mqtt_client, &settings).await {
-            Err(error) => {
-                publish_error(&mqtt_client, &settings.mqtt, error.to_string()).await?;
-                error!("Published error: {} - sleeping for {}", error, settings.error_delay);
-                // hopefully this can help it sort itself out on errors
-                // before going straight back into the next update
-                sleep(Duration::from_secs(settings.error_delay));
-            }
-            Ok(()) => match clear_error(&mqtt_client, &settings.mqtt).await {
-                Ok(()) => (),
+    let mut scheduler = Scheduler::new(&settings);
+    let mut smoother = Smoother::new(&settings.smoothing);
+    let mut influx = Influx::new(&settings.influx, settings.inverter().id.as_deref().unwrap_or_default());
+    let mut derived = Derived::new(&settings);
+    let mut runtime_stats = RuntimeStats::new();
+    if !settings.state_file.is_empty() {
+        runtime_stats.restore(state::load(&settings.state_file));
+    }
+    let mut repeated_errors = RepeatedErrors::default();
+    let mut consecutive_errors = 0;
+    let last_success = Arc::new(Mutex::new(Instant::now()));
+    let mut last_init = Instant::now();
+    let polling = async {
+        while !shutdown.is_requested() {
+            if let Some(reloaded) = reload.changed() {
+                reload::apply(&mut settings, &reloaded);
+                scheduler.reconfigure(&settings);
+            }
+            let updating = update(&queue, &mqtt_client, &settings, shutdown, set_handler, on_demand, &mut scheduler, &mut smoother, &mut influx, &mut derived, &metrics);
+            let result = if settings.mqtt.pipeline_publishes { pipelined(&mqtt_client, &settings.mqtt, updating).await } else { updating.await };
+            registration.set_ready(result.is_ok());
+            if let Err(error) = on_demand.acknowledge(&mqtt_client, &settings.mqtt, result.is_ok()).await {
+                error!("Failed to acknowledge the requested poll: {}", error);
+            }
+            match result {
+                Ok(()) => runtime_stats.record_success(),
+                Err(ref error) => runtime_stats.record_error(error, &metrics),
+            }
+            runtime_stats.uptime = runtime_stats.started.elapsed().as_secs();
+            if let Err(error) = publish_update(&mqtt_client, &settings.mqtt, "status", serde_json::to_string(&runtime_stats)?).await {
+                runtime_stats.publish_failures += 1;
+                metrics.record_publish_failure();
+                error!("Failed to publish status: {}", error);
+            }
+            if let Err(error) = flush_bundle(&mqtt_client, &settings.mqtt).await {
+                runtime_stats.publish_failures += 1;
+                metrics.record_publish_failure();
+                error!("Failed to publish bundle: {}", error);
+            }
+            if !settings.state_file.is_empty() && runtime_stats.last_saved.elapsed() >= STATE_SAVE_INTERVAL {
+                state::save(&settings.state_file, &runtime_stats.counters()).await;
+                runtime_stats.last_saved = Instant::now();
+            }
+
+            match result {
+                Err(error) if error.is_disconnected() => return Ok(Session::Disconnected { error, initialised: true }),
                 Err(error) => {
-                    error!("Failed to clear error: {}", error)
+                    consecutive_errors += 1;
+                    let error_delay = error_backoff(&settings, consecutive_errors);
+                    info!("{} errors in a row - backing off for {}s", consecutive_errors, error_delay);
+                    let (command, message) = describe_error(&error);
+                    match repeated_errors.check(&error.to_string(), Duration::from_secs(settings.error_summary_interval)) {
+                        None => error!("Error repeated: {} - not publishing again yet, sleeping for {}", error, error_delay),
+                        Some(occurrence) => {
+                            let message = if occurrence > 1 { format!("still failing ({} occurrences): {}", occurrence, message) } else { message };
+                            match publish_error(&mqtt_client, &settings.mqtt, command, message, error.crc_mismatch()).await {
+                                Ok(()) => error!("Published error: {} - sleeping for {}", error, error_delay),
+                                Err(pub_error) => {
+                                    runtime_stats.publish_failures += 1;
+                                    error!("Failed to publish error: {} ({}) - sleeping for {}", error, pub_error, error_delay)
+                                }
+                            }
+                        }
+                    }
+                    if settings.run_once {
+                        return Err(error);
+                    }
+                    // hopefully this can help it sort itself out on errors
+                    // before going straight back into the next update
+                    shutdown.sleep(Duration::from_secs(error_delay)).await;
                 }
-            },
+                Ok(()) => {
+                    if let Some(ref path) = settings.heartbeat_file {
+                        healthcheck::touch(path).await;
+                    }
+                    repeated_errors.reset();
+                    consecutive_errors = 0;
+                    if let Ok(mut last_success) = last_success.lock() {
+                        *last_success = Instant::now();
+                    }
+                    if settings.run_once {
+                        info!("Completed the single update - stopping");
+                        break;
+                    }
+                    match clear_error(&mqtt_client, &settings.mqtt).await {
+                        Ok(()) => (),
+                        Err(error) => {
+                            runtime_stats.publish_failures += 1;
+                            error!("Failed to clear error: {}", error)
+                        }
+                    }
+                    // Between updates so the regular commands keep their cadence
+                    if !settings.skip_init && settings.init_refresh_secs > 0 && last_init.elapsed() >= Duration::from_secs(settings.init_refresh_secs) {
+                        debug!("Refreshing init values");
+                        last_init = Instant::now();
+                        match init(&queue, &mqtt_client, &settings).await {
+                            Ok(_) => (),
+                            Err(error) if error.is_disconnected() => return Ok(Session::Disconnected { error, initialised: true }),
+                            Err(error) => error!("Failed to refresh init values: {}", error),
+                        }
+                    }
+                }
+            }
         }
+
+        Ok::<Session, MpqttError>(Session::Stopped)
+    };
+    // The watchdogs run alongside so a wedged update still gets flagged, and the serial one reopens the device
+    // by dropping the update mid way. The stale marker never finishes on its own.
+    let session = tokio::select! {
+        session = polling => session,
+        _ = watch_staleness(&mqtt_client, watched, &last_success) => unreachable!(),
+        error = watch_serial(watched, last_success.clone()) => Ok(Session::Disconnected { error, initialised: true }),
+    };
+    // Whatever ended polling, keep the totals counted since the last periodic save
+    if !settings.state_file.is_empty() {
+        state::save(&settings.state_file, &runtime_stats.counters()).await;
     }
+    session
 }
 
-async fn init(inverter: &mut Inverter<File>, mqtt_client: &MQTTClient, settings: &Settings) -> Result<(), Box<dyn std::error::Error>> {
-    // Get initial values
-
-    // QID      - Serial number
-    match inverter.execute::<QID>(()).await {
-        Ok(serial_number) => {
-            publish_update(&mqtt_client, &settings.mqtt, "qid", serde_json::to_string(&serial_number)?).await?;
+// Waits until serial_watchdog_secs pass without a successful update and returns the error to reconnect with,
+// or never finishes when it's 0
+async fn watch_serial(settings: &Settings, last_success: Arc<Mutex<Instant>>) -> MpqttError {
+    if settings.serial_watchdog_secs == 0 {
+        return futures::future::pending().await;
+    }
+    let window = Duration::from_secs(settings.serial_watchdog_secs);
+    loop {
+        let since = last_success.lock().map(|last_success| last_success.elapsed()).unwrap_or_default();
+        if since > window {
+            error!("WATCHDOG: no successful update for {}s (serial_watchdog_secs is {}) - closing and reopening {}", since.as_secs(), settings.serial_watchdog_secs, settings.inverter().path);
+            return MpqttError::Serial(CommandError {
+                command: String::from("watchdog"),
+                error: QueueError::Io(format!("no successful update for {}s", since.as_secs())),
+            });
         }
-        Err(serial_number_error) => {
-            error!("Error fetching serial number: {}", serial_number_error);
+        delay_for(Duration::from_secs(1)).await;
+    }
+}
+
+// Publishes {topic}/stale as ON once stale_threshold_secs pass without a successful update, and OFF again after one.
+// Runs until dropped, or never does anything when the threshold is 0.
+async fn watch_staleness(mqtt_client: &Publisher, settings: &Settings, last_success: &Mutex<Instant>) {
+    if settings.stale_threshold_secs == 0 {
+        return futures::future::pending().await;
+    }
+    let threshold = Duration::from_secs(settings.stale_threshold_secs);
+    let mut published = None;
+    loop {
+        let stale = last_success.lock().map(|last_success| last_success.elapsed() > threshold).unwrap_or(false);
+        if published != Some(stale) {
+            if stale {
+                warn!("No successful update for over {}s - marking the data stale", settings.stale_threshold_secs);
+            }
+            let payload = if stale { "ON" } else { "OFF" };
+            let mqtt = &settings.mqtt;
+            match publish_with_retry(mqtt_client, mqtt, mqtt.state_topic("stale"), Vec::from(payload), QoS::AtLeastOnce, true).await {
+                Ok(()) => published = Some(stale),
+                Err(error) => error!("Failed to publish stale marker: {}", error),
+            }
         }
-    };
-    // QPI      - Protocol ID
-    let protocol_id = inverter.execute::<QPI>(()).await?;
-    publish_update(&mqtt_client, &settings.mqtt, "qpi", serde_json::to_string(&protocol_id)?).await?;
+        delay_for(Duration::from_secs(1)).await;
+    }
+}
 
+// The values are only published for information, apart from the model which can pick the mode and the serial number
+// which can identify the device for discovery. A failed command is logged and skipped, only a lost serial line stops init.
+async fn init(queue: &CommandQueue, mqtt_client: &Publisher, settings: &Settings) -> Result<(Option<QMn>, Option<String>), MpqttError> {
+    // Get initial values
+    // QID      - Serial number
+    // QPI      - Protocol ID
     // QVFW     - Software version 1
-    let software_version_1 = inverter.execute::<QVFW>(()).await?;
-    publish_update(&mqtt_client, &settings.mqtt, "qvfw", serde_json::to_string(&software_version_1)?).await?;
+    // QMN      - Model name
+    let mut model = None;
+    let mut serial = None;
+    // Only the update loop publishes timings
+    let mut timings = HashMap::new();
+    for query in [Query::QID, Query::QPI, Query::QVFW, Query::QMN].iter().cloned() {
+        let command = query.name();
+        if !settings.command_enabled(&command) {
+            continue;
+        }
+        match execute_with_retry(queue, query, mqtt_client, settings, &mut timings).await {
+            Ok(None) => (),
+            Ok(Some(reading)) => {
+                if command == "qmn" {
+                    model = serde_json::from_str(&reading.json).ok();
+                }
+                if command == "qid" {
+                    serial = serial_number(&reading.json);
+                }
+                publish_reading(&mqtt_client, &settings.mqtt, &command, reading).await?;
+            }
+            Err(error) if error.is_disconnected() => return Err(error),
+            Err(error) => {
+                warn!("Skipping init command {}: {}", command, error);
+            }
+        }
+    }
 
     debug!("Completed init commands");
 
-    Ok(())
+    Ok((model, serial))
+}
+
+// The serial number from a QID response, kept to the characters Home Assistant accepts in identifiers
+fn serial_number(json: &str) -> Option<String> {
+    let serial = match serde_json::from_str::<serde_json::Value>(json).ok()? {
+        serde_json::Value::String(serial) => serial,
+        serde_json::Value::Number(serial) => serial.to_string(),
+        serde_json::Value::Object(fields) => match fields.get("serial_number").or_else(|| fields.values().next())? {
+            serde_json::Value::String(serial) => serial.clone(),
+            serde_json::Value::Number(serial) => serial.to_string(),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    let serial: String = serial.trim().chars().filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-').collect();
+    Some(serial).filter(|serial| !serial.is_empty())
 }
 
-async fn update(inverter: &mut Inverter<File>, mqtt_client: &MQTTClient, settings: &Settings) -> Result<(), Box<dyn std::error::Error>> {
+#[allow(clippy::too_many_arguments)]
+async fn update(
+    queue: &CommandQueue,
+    mqtt_client: &Publisher,
+    settings: &Settings,
+    shutdown: &mut Shutdown,
+    set_handler: &mut SetHandler,
+    on_demand: &mut OnDemand,
+    scheduler: &mut Scheduler,
+    smoother: &mut Smoother,
+    influx: &mut Influx,
+    derived: &mut Derived,
+    metrics: &Metrics,
+) -> Result<(), MpqttError> {
     // Start update
     debug!("Starting new update");
     let outer_start = Instant::now();
+    // Milliseconds spent on each command this cycle, retries included
+    let mut timings = HashMap::new();
     // QPGSn    - Device general status parameters inquiry
-    for _ in 0..settings.inner_iterations {
+    let cycle = scheduler.start_cycle();
+    for iteration in 1..=settings.inner_iterations {
+        // Pending writes are queued between polls
+        set_handler.process(queue, &mqtt_client, &settings).await?;
+
         let inner_start = Instant::now();
-        if settings.mode == String::from("phocos") {
-            let start_index = if settings.debug { 0 } else { 1 };
-            for index in start_index..=settings.inverter_count {
-                let qpgs = match index {
-                    0 => inverter.execute::<QPGS0>(()).await?,
-                    1 => inverter.execute::<QPGS1>(()).await?,
-                    2 => inverter.execute::<QPGS2>(()).await?,
-                    3 => inverter.execute::<QPGS3>(()).await?,
-                    4 => inverter.execute::<QPGS4>(()).await?,
-                    5 => inverter.execute::<QPGS5>(()).await?,
-                    6 => inverter.execute::<QPGS6>(()).await?,
-                    7 => inverter.execute::<QPGS7>(()).await?,
-                    8 => inverter.execute::<QPGS8>(()).await?,
-                    9 => inverter.execute::<QPGS9>(()).await?,
-                    _ => unimplemented!(),
-                };
-                if (settings.debug && index == 0) || index != 0 {
-                    publish_update(&mqtt_client, &settings.mqtt, &format!("qpgs{}", index), serde_json::to_string(&qpgs)?).await?;
+        if settings.mode == Mode::Phocos && scheduler.due("qpgs") {
+            // Every unit's status keyed by index, for qpgs_all
+            let mut all = serde_json::Map::new();
+            for index in settings.qpgs_indexes() {
+                let query = Query::QPGS(index);
+                if let Some(reading) = poll_command(queue, query.clone(), mqtt_client, settings, scheduler, &mut timings).await? {
+                    let command = query.name();
+                    metrics.record_values(&command, &reading.json);
+                    influx.record(&command, &reading.json);
+                    publish_derived(&mqtt_client, &settings.mqtt, derived, &command, &reading.json).await?;
+                    if let Some(fault) = faults::decode(settings.mode, &reading.json) {
+                        let fault = Reading {
+                            json: serde_json::to_string(&fault)?,
+                            received: reading.received,
+                        };
+                        publish_reading(&mqtt_client, &settings.mqtt, &format!("{}/fault_text", command), fault).await?;
+                    }
+                    if settings.aggregate_qpgs {
+                        all.insert(index.to_string(), serde_json::from_str(&reading.json)?);
+                    }
+                    publish_reading(&mqtt_client, &settings.mqtt, &command, reading).await?;
                 }
             }
+            if settings.aggregate_qpgs && !all.is_empty() {
+                publish_reading(&mqtt_client, &settings.mqtt, "qpgs_all", Reading::now(serde_json::Value::Object(all).to_string())).await?;
+            }
         }
 
         // QPIGS    - Device general status parameters inquiry
-        if settings.mode != String::from("phocos") {
-            let qpigs = inverter.execute::<QPIGS>(()).await?;
-            publish_update(&mqtt_client, &settings.mqtt, "qpigs", serde_json::to_string(&qpigs)?).await?;
+        if settings.mode != Mode::Phocos && scheduler.due("qpigs") {
+            if let Some(reading) = poll_command(queue, Query::QPIGS, mqtt_client, settings, scheduler, &mut timings).await? {
+                metrics.record_values("qpigs", &reading.json);
+                influx.record("qpigs", &reading.json);
+                publish_derived(&mqtt_client, &settings.mqtt, derived, "qpigs", &reading.json).await?;
+                for (flag, set) in warnings::decode_status(&reading.json) {
+                    publish_update(&mqtt_client, &settings.mqtt, &format!("qpigs/{}", flag.field), String::from(if set { "ON" } else { "OFF" })).await?;
+                }
+                if let Some(soc) = battery_soc(&reading.json) {
+                    publish_update(&mqtt_client, &settings.mqtt, "qpigs/battery_soc", soc.to_string()).await?;
+                }
+                for (name, value) in ac_readings(&reading.json) {
+                    publish_update(&mqtt_client, &settings.mqtt, &format!("qpigs/{}", name), value).await?;
+                }
+                if let Some(power) = battery_power(&reading.json) {
+                    publish_update(&mqtt_client, &settings.mqtt, "battery_power", power.to_string()).await?;
+                }
+                if let Some(smoothed) = smoother.push(&reading.json) {
+                    publish_reading(&mqtt_client, &settings.mqtt, "qpigs_smoothed", Reading { json: smoothed, received: reading.received }).await?;
+                }
+                publish_reading(&mqtt_client, &settings.mqtt, "qpigs", reading).await?;
+            }
+        }
+
+        // QPIGS2   - Second PV input status inquiry
+        if settings.has_second_pv && scheduler.due("qpigs2") {
+            if let Some(reading) = poll_command(queue, Query::QPIGS2, mqtt_client, settings, scheduler, &mut timings).await? {
+                metrics.record_values("qpigs2", &reading.json);
+                influx.record("qpigs2", &reading.json);
+                publish_derived(&mqtt_client, &settings.mqtt, derived, "qpigs2", &reading.json).await?;
+                publish_reading(&mqtt_client, &settings.mqtt, "qpigs2", reading).await?;
+            }
         }
 
         // inner loop reporting
+        metrics.record_duration("inner", inner_start.elapsed());
         let inner_time = inner_start.elapsed().as_millis();
-        info!("Partial update took {}ms - sleeping for {}s", inner_time, settings.inner_delay);
+        let inner_delay = scheduler.jitter(settings.inner_delay);
+        info!("Partial update took {}ms - sleeping for {:.1}s", inner_time, inner_delay.as_secs_f64());
         // inner_loop_duration can essentially be our heartbeat
-        let inner_stats = Stats { update_duration: inner_time };
-        publish_update(&mqtt_client, &settings.mqtt, "inner_stats", serde_json::to_string(&inner_stats)?).await?;
-        sleep(Duration::from_secs(settings.inner_delay));
+        let inner_stats = Stats {
+            scope: "inner",
+            update_duration: inner_time,
+            iteration,
+            overrun: check_overrun("Partial", inner_time, settings.inner_delay, "inner_delay"),
+        };
+        publish_stats(&mqtt_client, &settings.mqtt, &inner_stats).await?;
+        if shutdown.sleep(inner_delay).await {
+            return Ok(());
+        }
     }
 
     // QMOD     -  Device Mode Inquiry
-    let qmod = inverter.execute::<QMOD>(()).await?;
-    publish_update(&mqtt_client, &settings.mqtt, "qmod", serde_json::to_string(&qmod)?).await?;
+    if scheduler.due("qmod") {
+        if let Some(qmod) = poll_command(queue, Query::QMOD(settings.mode), mqtt_client, settings, scheduler, &mut timings).await? {
+            publish_reading(&mqtt_client, &settings.mqtt, "qmod", qmod).await?;
+        }
+    }
 
     // QPIWS    - Device Warning Status Inquiry
-    let qpiws = inverter.execute::<QPIWS>(()).await?;
-    publish_update(&mqtt_client, &settings.mqtt, "qpiws", serde_json::to_string(&qpiws)?).await?;
+    if scheduler.due("qpiws") {
+        if let Some(qpiws) = poll_command(queue, Query::QPIWS, mqtt_client, settings, scheduler, &mut timings).await? {
+            for (warning, active) in warnings::decode(&qpiws.json) {
+                publish_update(&mqtt_client, &settings.mqtt, &format!("qpiws/{}", warning.field), String::from(if active { "ON" } else { "OFF" })).await?;
+            }
+            publish_reading(&mqtt_client, &settings.mqtt, "qpiws", qpiws).await?;
+        }
+    }
 
     // QPIRI    - Device Rating Information Inquiry
-    if settings.mode != String::from("phocos") {
-        let qpiri = inverter.execute::<QPIRI>(()).await?;
-        publish_update(&mqtt_client, &settings.mqtt, "qpiri", serde_json::to_string(&qpiri)?).await?;
-    } else {
-        let qpiri = inverter.execute::<QPIRIReduced>(()).await?;
-        publish_update(&mqtt_client, &settings.mqtt, "qpiri", serde_json::to_string(&qpiri)?).await?;
+    if scheduler.due("qpiri") {
+        let query = if settings.mode != Mode::Phocos { Query::QPIRI } else { Query::QPIRIReduced };
+        if let Some(qpiri) = poll_command(queue, query, mqtt_client, settings, scheduler, &mut timings).await? {
+            publish_fields(&mqtt_client, &settings.mqtt, "qpiri", &qpiri.json).await?;
+            publish_derived(&mqtt_client, &settings.mqtt, derived, "qpiri", &qpiri.json).await?;
+            publish_reading(&mqtt_client, &settings.mqtt, "qpiri", qpiri).await?;
+        }
+    }
+
+    // QFLAG    - Device Flag Status Inquiry
+    if settings.mode != Mode::Phocos && scheduler.due("qflag") {
+        if let Some(qflag) = poll_command(queue, Query::QFLAG, mqtt_client, settings, scheduler, &mut timings).await? {
+            publish_reading(&mqtt_client, &settings.mqtt, "qflag", qflag).await?;
+        }
+    }
+
+    // QT       - Device time inquiry, not every model has a clock
+    if scheduler.due("qt") {
+        if let Some(qt) = poll_command(queue, Query::QT, mqtt_client, settings, scheduler, &mut timings).await? {
+            publish_derived(&mqtt_client, &settings.mqtt, derived, "qt", &qt.json).await?;
+            publish_reading(&mqtt_client, &settings.mqtt, "qt", qt).await?;
+        }
     }
 
+    influx.flush().await;
+
     // Report update completed
+    metrics.record_duration("outer", outer_start.elapsed());
     let outer_time = outer_start.elapsed().as_millis();
-    info!("Full update took {}ms - sleeping for {}s", outer_time, settings.outer_delay);
-    let outer_stats = Stats { update_duration: outer_time };
-    publish_update(&mqtt_client, &settings.mqtt, "outer_stats", serde_json::to_string(&outer_stats)?).await?;
-    sleep(Duration::from_secs(settings.outer_delay));
+    let outer_delay = scheduler.jitter(settings.outer_delay);
+    info!("Full update took {}ms - sleeping for {:.1}s", outer_time, outer_delay.as_secs_f64());
+    let outer_stats = Stats {
+        scope: "outer",
+        update_duration: outer_time,
+        iteration: cycle,
+        overrun: check_overrun("Full", outer_time, settings.outer_delay, "outer_delay"),
+    };
+    publish_stats(&mqtt_client, &settings.mqtt, &outer_stats).await?;
+    publish_update(&mqtt_client, &settings.mqtt, "serial_stats", serde_json::to_string(&queue.serial_stats())?).await?;
+    if settings.timings {
+        publish_update(&mqtt_client, &settings.mqtt, "timings", serde_json::to_string(&timings)?).await?;
+    }
+    if !settings.run_once {
+        tokio::select! {
+            _ = shutdown.sleep(outer_delay) => (),
+            _ = on_demand.requested() => info!("Poll requested - updating now"),
+        }
+    }
     Ok(())
 }
 
-async fn publish_update(mqtt_client: &MQTTClient, mqtt: &MqttSettings, command: &str, value: String) -> Result<(), Box<dyn std::error::Error>> {
-    let mut msg = PublishOpts::new(format!("{}/{}", mqtt.topic, command).to_string(), Vec::from(value));
-    msg.set_qos(QoS::AtLeastOnce);
-    msg.set_retain(false);
-    for _ in 0..5 {
-        match mqtt_client.publish(&msg).await {
-            Ok(()) => break,
-            Err(pub_error) => error!("Error publishing update for {}: {}", command, pub_error),
-        };
+// execute_with_retry for the update loop. A command the inverter NAKs isn't supported by its firmware, so it's
+// disabled for the rest of the run rather than failing every cycle.
+async fn poll_command(queue: &CommandQueue, query: Query, mqtt_client: &Publisher, settings: &Settings, scheduler: &mut Scheduler, timings: &mut HashMap<String, u128>) -> Result<Option<Reading>, MpqttError> {
+    let command = query.name();
+    if scheduler.is_disabled(&command) {
+        return Ok(None);
+    }
+    match execute_with_retry(queue, query, mqtt_client, settings, timings).await {
+        Err(error) if error.is_nak() => {
+            warn!("{} isn't supported by this inverter (NAK) - not asking again until restarted, add it to disabled_commands to skip it from the start", command.to_ascii_uppercase());
+            scheduler.disable(&command);
+            Ok(None)
+        }
+        Ok(Some(mut reading)) => {
+            let (json, rejected) = sanity::check(&settings.sanity, &command, reading.json);
+            reading.json = json;
+            if !rejected.is_empty() {
+                let rejected = serde_json::json!({ "command": command, "values": rejected });
+                debug!("Dropping out of range values {}", rejected);
+                if settings.sanity.publish_rejected {
+                    publish_update(mqtt_client, &settings.mqtt, "rejected", rejected.to_string()).await?;
+                }
+            }
+            Ok(Some(reading))
+        }
+        result => result,
+    }
+}
+
+// Runs a command through the queue, retrying it on its own when the response is garbled or missing so one bad
+// frame doesn't restart the whole update. A command that keeps timing out is reported and skipped (None),
+// other errors are passed on once retries run out.
+async fn execute_with_retry(queue: &CommandQueue, query: Query, mqtt_client: &Publisher, settings: &Settings, timings: &mut HashMap<String, u128>) -> Result<Option<Reading>, MpqttError> {
+    let command = query.name();
+    let attempts = settings.command_retries + 1;
+    let mut attempt = 1;
+    loop {
+        let started = Instant::now();
+        let (result, raw) = queue.execute_with_raw(query.clone()).await;
+        *timings.entry(command.clone()).or_insert(0) += started.elapsed().as_millis();
+        if settings.publish_raw && !raw.is_empty() {
+            let hex: String = raw.iter().map(|byte| format!("{:02X}", byte)).collect();
+            publish_update(mqtt_client, &settings.mqtt, &format!("raw/{}", command), hex).await?;
+        }
+        match result {
+            Ok(json) => return Ok(Some(Reading::now(json))),
+            Err(err) if is_recoverable_error(&err) && attempt < attempts => {
+                warn!("{} failed: {} - retrying (attempt {}/{})", command, err, attempt, attempts);
+                attempt += 1;
+            }
+            Err(QueueError::Timeout(duration)) => {
+                warn!("{} got no response within {}ms - skipping", command, duration.as_millis());
+                publish_error(mqtt_client, &settings.mqtt, Some(&command), format!("timed out after {}ms", duration.as_millis()), None).await?;
+                return Ok(None);
+            }
+            Err(error) => return Err(CommandError { command, error }.into()),
+        }
+    }
+}
+
+// The QPIGS battery capacity as a percentage, clamped to 0-100 so a bad reading doesn't reach the dashboard
+fn battery_soc(json: &str) -> Option<u8> {
+    let capacity = match serde_json::from_str::<serde_json::Value>(json).ok().and_then(|qpigs| qpigs.get("battery_capacity").and_then(serde_json::Value::as_f64)) {
+        Some(capacity) => capacity,
+        None => {
+            warn!("QPIGS has no battery_capacity - not publishing qpigs/battery_soc");
+            return None;
+        }
+    };
+    if !(0.0..=100.0).contains(&capacity) {
+        warn!("QPIGS battery_capacity {} is outside 0-100% - clamping", capacity);
+    }
+    Some(capacity.max(0.0).min(100.0).round() as u8)
+}
+
+// The QPIGS input and output voltage and frequency, keyed by the names they're published under so the two sides
+// can't be mixed up
+fn ac_readings(json: &str) -> Vec<(&'static str, String)> {
+    let qpigs = match serde_json::from_str::<serde_json::Value>(json) {
+        Ok(qpigs) => qpigs,
+        Err(_) => return Vec::new(),
+    };
+    QPIGS_AC_FIELDS.iter().filter_map(|(field, name)| qpigs.get(field).map(|value| (*name, value.to_string()))).collect()
+}
+
+// The QPIGS battery power in watts, positive while charging and negative while discharging. Firmware either splits
+// the current across battery_charge_current and battery_discharge_current or reports its magnitude in one or both
+// of them, so when the two fields don't say which way it's flowing the charging status bit decides the sign.
+fn battery_power(json: &str) -> Option<i64> {
+    let qpigs = serde_json::from_str::<serde_json::Value>(json).ok()?;
+    let field = |name: &str| qpigs.get(name).and_then(serde_json::Value::as_f64);
+    let (voltage, charge, discharge) = match (field("battery_voltage"), field("battery_charge_current"), field("battery_discharge_current")) {
+        (Some(voltage), Some(charge), Some(discharge)) => (voltage, charge, discharge),
+        _ => {
+            warn!("QPIGS is missing the battery voltage or currents - not publishing battery_power");
+            return None;
+        }
+    };
+    let charging = qpigs.pointer("/device_status/charging").and_then(serde_json::Value::as_bool);
+    let current = match (charge > 0.0, discharge > 0.0, charging) {
+        (false, false, _) => 0.0,
+        // Only one direction reported and the status bit agrees, or there's no status bit to go on
+        (true, false, Some(true)) | (false, true, Some(false)) | (_, _, None) => charge - discharge,
+        // Magnitude only
+        (_, _, Some(true)) => charge.max(discharge),
+        (_, _, Some(false)) => -charge.max(discharge),
+    };
+    Some((voltage * current).round() as i64)
+}
+
+// Splits the command label back off an error so it can be published on its own
+fn describe_error(error: &MpqttError) -> (Option<&str>, String) {
+    match error.command() {
+        Some(command_error) => (Some(&command_error.command), command_error.error.to_string()),
+        None => (None, error.to_string()),
+    }
+}
+
+async fn publish_update(mqtt_client: &Publisher, mqtt: &MqttSettings, command: &str, value: String) -> Result<(), MpqttError> {
+    if mqtt.publish_mode == PublishMode::Bundle {
+        mqtt_client.bundle(command, &value);
+        return Ok(());
+    }
+    let (qos, retain) = mqtt.publish.resolve(command);
+    let outgoing = Outgoing {
+        topic: mqtt.state_topic(command),
+        payload: Vec::from(value),
+        qos,
+        retain,
+    };
+    // While an update is pipelined this is sent by pipelined alongside the next command
+    match mqtt_client.enqueue(outgoing) {
+        None => Ok(()),
+        Some(outgoing) => publish_with_retry(mqtt_client, mqtt, outgoing.topic, outgoing.payload, outgoing.qos, outgoing.retain).await,
+    }
+}
+
+// Runs an update with its publishes queued and sent alongside it, so they wait on their PUBACKs while the next
+// command is already on the serial line. They go out one at a time in the order they were made, so every topic
+// keeps its order. The first failed publish fails the update and the rest are dropped, as they would be unpipelined.
+// The outbox is still drained after a failure, as closing it would have publish_update send the rest itself.
+async fn pipelined(mqtt_client: &Publisher, mqtt: &MqttSettings, update: impl std::future::Future<Output = Result<(), MpqttError>>) -> Result<(), MpqttError> {
+    let mut outbox = mqtt_client.start_pipeline();
+    let updating = async {
+        let result = update.await;
+        mqtt_client.end_pipeline();
+        result
+    };
+    let publishing = async move {
+        let (mut published, mut waited, mut failed) = (0, Duration::default(), None);
+        while let Some(outgoing) = outbox.recv().await {
+            if failed.is_some() {
+                debug!("Dropping the pipelined publish to {} after an earlier one failed", outgoing.topic);
+                continue;
+            }
+            let started = Instant::now();
+            match publish_with_retry(mqtt_client, mqtt, outgoing.topic, outgoing.payload, outgoing.qos, outgoing.retain).await {
+                Ok(()) => published += 1,
+                Err(err) => failed = Some(err),
+            }
+            waited += started.elapsed();
+        }
+        match failed {
+            Some(err) => Err(err),
+            None => Ok((published, waited)),
+        }
+    };
+    let (updated, publishing) = join(updating, publishing).await;
+    mqtt_client.end_pipeline();
+    let (published, waited) = publishing?;
+    info!("Pipelined {} publishes - {}ms of waiting on the broker was taken off the serial path", published, waited.as_millis());
+    updated
+}
+
+// Publishes everything bundled since the last flush to {topic}/bundle, or gzipped to {topic}/bundle/gzip
+async fn flush_bundle(mqtt_client: &Publisher, mqtt: &MqttSettings) -> Result<(), MpqttError> {
+    let bundle = match mqtt_client.take_bundle() {
+        Some(bundle) => bundle,
+        None => return Ok(()),
+    };
+    let (qos, retain) = mqtt.publish.resolve("bundle");
+    #[cfg(feature = "gzip")]
+    {
+        if mqtt.bundle_gzip {
+            let payload = publisher::gzip(bundle.as_bytes())?;
+            return publish_with_retry(mqtt_client, mqtt, mqtt.state_topic("bundle/gzip"), payload, qos, retain).await;
+        }
+    }
+    publish_with_retry(mqtt_client, mqtt, mqtt.state_topic("bundle"), Vec::from(bundle), qos, retain).await
+}
+
+async fn publish_stats(mqtt_client: &Publisher, mqtt: &MqttSettings, stats: &Stats) -> Result<(), MpqttError> {
+    let json = serde_json::to_string(stats)?;
+    if mqtt.legacy_stats_topics {
+        publish_reading(mqtt_client, mqtt, &format!("{}_stats", stats.scope), Reading::now(json.clone())).await?;
+    }
+    publish_reading(mqtt_client, mqtt, &format!("stats/{}", stats.scope), Reading::now(json)).await
+}
+
+// Publishes the derived sensors that depend on this command's reading
+async fn publish_derived(mqtt_client: &Publisher, mqtt: &MqttSettings, derived: &mut Derived, command: &str, json: &str) -> Result<(), MpqttError> {
+    for (name, value) in derived.record(command, json) {
+        publish_update(mqtt_client, mqtt, &name, value.to_string()).await?;
     }
     Ok(())
 }
 
-async fn publish_error(mqtt_client: &MQTTClient, mqtt: &MqttSettings, error: String) -> Result<(), Box<dyn std::error::Error>> {
-    let mut msg = PublishOpts::new(format!("{}/error", mqtt.topic).to_string(), Vec::from(error.clone()));
-    msg.set_qos(QoS::AtLeastOnce);
-    msg.set_retain(false);
-    for _ in 0..5 {
-        match mqtt_client.publish(&msg).await {
-            Ok(()) => break,
-            Err(pub_error) => error!("Error publishing error: {} - {}", pub_error, error),
-        };
+// Keeps only the listed keys of a JSON object, anything that isn't an object is left as it is
+fn select_fields(json: &str, fields: &[String]) -> Result<String, MpqttError> {
+    let value = match serde_json::from_str(json)? {
+        serde_json::Value::Object(object) => serde_json::Value::Object(object.into_iter().filter(|(key, _)| fields.contains(key)).collect()),
+        value => value,
+    };
+    Ok(value.to_string())
+}
+
+// Publishes each top level value of a response to {command}/{field} as plain text
+async fn publish_fields(mqtt_client: &Publisher, mqtt: &MqttSettings, command: &str, json: &str) -> Result<(), MpqttError> {
+    if let serde_json::Value::Object(fields) = serde_json::from_str(json)? {
+        for (field, value) in fields {
+            let value = match value {
+                serde_json::Value::String(text) => text,
+                serde_json::Value::Object(_) | serde_json::Value::Array(_) => continue,
+                value => value.to_string(),
+            };
+            publish_update(mqtt_client, mqtt, &format!("{}/{}", command, field), value).await?;
+        }
     }
     Ok(())
 }
 
-async fn clear_error(mqtt_client: &MQTTClient, mqtt: &MqttSettings) -> Result<(), Box<dyn std::error::Error>> {
-    let mut msg = PublishOpts::new(format!("{}/error", mqtt.topic).to_string(), "".to_string().as_bytes().to_vec());
-    msg.set_qos(QoS::AtLeastOnce);
-    msg.set_retain(false);
-    for _ in 0..5 {
-        match mqtt_client.publish(&msg).await {
-            Ok(()) => break,
-            Err(pub_error) => error!("Error clearing error: {}", pub_error),
-        };
+// Cuts the reading down to the command's fields allowlist, then wraps it in a {"ts", "data"} envelope when
+// timestamp_payloads is set
+async fn publish_reading(mqtt_client: &Publisher, mqtt: &MqttSettings, command: &str, reading: Reading) -> Result<(), MpqttError> {
+    let json = match mqtt.publish.fields(command) {
+        Some(fields) => select_fields(&reading.json, fields)?,
+        None => reading.json,
+    };
+    let payload = if mqtt.timestamp_payloads { publisher::timestamped(&json, reading.received) } else { json };
+    publish_update(mqtt_client, mqtt, command, payload).await
+}
+
+async fn publish_error(mqtt_client: &Publisher, mqtt: &MqttSettings, command: Option<&str>, message: String, crc: Option<(u16, u16)>) -> Result<(), MpqttError> {
+    let (qos, retain) = mqtt.publish.resolve("error");
+    let payload = match mqtt.error_format {
+        ErrorFormat::Text => match command {
+            Some(command) => format!("{}: {}", command, message),
+            None => message,
+        },
+        ErrorFormat::Json => serde_json::to_string(&ErrorPayload {
+            command,
+            message,
+            crc: crc.map(|(expected, actual)| CrcPayload {
+                expected: format!("{:04X}", expected),
+                actual: format!("{:04X}", actual),
+            }),
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or_default(),
+        })?,
+    };
+    publish_with_retry(mqtt_client, mqtt, mqtt.state_topic("error"), Vec::from(payload), qos, retain).await?;
+    mqtt_client.set_error_active(true);
+    Ok(())
+}
+
+// Only publishes when an error is still showing, so a healthy run doesn't send a clear every update
+async fn clear_error(mqtt_client: &Publisher, mqtt: &MqttSettings) -> Result<(), MpqttError> {
+    if !mqtt_client.error_active() {
+        return Ok(());
     }
+    let payload = match mqtt.clear_error {
+        ClearError::None => return Ok(()),
+        ClearError::OnSuccess => Vec::new(),
+        ClearError::Structured => serde_json::to_vec(&ClearedPayload { cleared: true })?,
+    };
+    let (qos, retain) = mqtt.publish.resolve("error");
+    publish_with_retry(mqtt_client, mqtt, mqtt.state_topic("error"), payload, qos, retain).await?;
+    mqtt_client.set_error_active(false);
     Ok(())
 }
 
-fn raw_open<P: AsRef<Path>>(path: P) -> std::io::Result<File> {
-    let fd = unsafe { open(path.as_ref().as_os_str().as_bytes().as_ptr() as *const u8, O_RDWR) };
-    if fd < 0 {
-        return Err(std::io::Error::last_os_error());
+fn event_payload(event: Event) -> Result<Vec<u8>, MpqttError> {
+    let payload = EventPayload {
+        event,
+        timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or_default(),
+    };
+    Ok(serde_json::to_vec(&payload)?)
+}
+
+async fn publish_event(mqtt_client: &Publisher, mqtt: &MqttSettings, event: Event) -> Result<(), MpqttError> {
+    let (qos, retain) = mqtt.publish.resolve("events");
+    publish_with_retry(mqtt_client, mqtt, mqtt.state_topic("events"), event_payload(event)?, qos, retain).await
+}
+
+// Retained so whatever subscribes later still sees which build and config produced the data
+async fn publish_info(mqtt_client: &Publisher, settings: &Settings) -> Result<(), MpqttError> {
+    let info = Info {
+        version: env!("CARGO_PKG_VERSION"),
+        mode: settings.mode,
+        inverter_count: settings.inverter_count,
+        has_second_pv: settings.has_second_pv,
+        default_interval: settings.default_interval,
+        intervals: &settings.intervals,
+    };
+    let payload = serde_json::to_vec(&info)?;
+    publish_with_retry(mqtt_client, &settings.mqtt, settings.mqtt.state_topic("info"), payload, QoS::AtLeastOnce, true).await
+}
+
+async fn publish_availability(mqtt_client: &Publisher, mqtt: &MqttSettings, online: bool) -> Result<(), MpqttError> {
+    let payload = if online { mqtt.availability.payload_online.clone() } else { mqtt.availability.payload_offline.clone() };
+    publish_with_retry(mqtt_client, mqtt, mqtt.availability_topic(), Vec::from(payload), QoS::AtLeastOnce, true).await
+}
+
+// Publishes a message, doubling the delay between attempts. Once all attempts are used up it waits for the
+// client to reconnect for up to reconnect_give_up_secs before returning the last error.
+async fn publish_with_retry(mqtt_client: &Publisher, mqtt: &MqttSettings, topic: String, payload: Vec<u8>, qos: QoS, retain: bool) -> Result<(), MpqttError> {
+    let attempts = mqtt.publish_retries;
+    let pub_error = match publisher::retry(mqtt_client, &topic, &payload, qos, retain, attempts, Duration::from_millis(mqtt.publish_backoff_ms)).await {
+        Ok(()) => return Ok(()),
+        Err(pub_error) => pub_error,
+    };
+    error!("Error publishing to {} after {} attempts: {}", topic, attempts, pub_error);
+    let give_up = Duration::from_secs(mqtt.reconnect_give_up_secs);
+    if give_up.as_secs() == 0 {
+        return Err(pub_error.into());
+    }
+    mqtt_client.wait_for_reconnect(&topic, &payload, qos, retain, Duration::from_secs(mqtt.connect_retry_delay_secs), give_up).await?;
+    // Straight to the client as publish_with_retry can't call itself, it has only just reconnected anyway
+    let (qos, retain) = mqtt.publish.resolve("events");
+    if let Err(err) = mqtt_client.publish(&mqtt.state_topic("events"), &event_payload(Event::MqttReconnected)?, qos, retain).await {
+        warn!("Failed to publish the reconnected event: {}", err);
+    }
+    Ok(())
+}
+
+// The broker may not be up yet when mpqtt starts, e.g. when both start at boot, so the first connect is retried with
+// a doubling delay from connect_retry_delay_secs until connect_give_up_secs have passed
+async fn connect_with_retry(client: &mut MQTTClient, mqtt: &MqttSettings) -> Result<(), MpqttError> {
+    let started = Instant::now();
+    let give_up = Duration::from_secs(mqtt.connect_give_up_secs);
+    let max_delay = MAX_CONNECT_RETRY_DELAY.max(Duration::from_secs(mqtt.connect_retry_delay_secs));
+    let mut delay = Duration::from_secs(mqtt.connect_retry_delay_secs);
+    let mut attempt = 1;
+    loop {
+        match client.connect().await {
+            Ok(()) => return Ok(()),
+            Err(err) if started.elapsed() + delay > give_up => {
+                error!("Could not connect to the MQTT broker at {}:{} after {} attempts: {}", mqtt.host, mqtt.port(), attempt, err);
+                return Err(err.into());
+            }
+            Err(err) => {
+                warn!("Could not connect to the MQTT broker at {}:{} (attempt {}): {} - retrying in {}s", mqtt.host, mqtt.port(), attempt, err, delay.as_secs());
+                delay_for(delay).await;
+                delay = (delay * 2).min(max_delay);
+                attempt += 1;
+            }
+        }
+    }
+}
+
+fn build_mqtt_client(mqtt: &MqttSettings, client_id: String) -> Result<MQTTClient, MpqttError> {
+    let mut builder = MQTTClient::builder();
+    builder
+        .set_host(mqtt.host.clone())
+        .set_port(mqtt.port())
+        .set_username(Option::from(mqtt.username.clone()))
+        .set_password(Option::from(mqtt.password.as_bytes().to_vec()))
+        .set_client_id(Option::from(client_id))
+        .set_connect_retry_delay(Duration::from_secs(mqtt.connect_retry_delay_secs))
+        .set_keep_alive(KeepAlive::from_secs(mqtt.keep_alive_secs))
+        .set_operation_timeout(Duration::from_secs(mqtt.operation_timeout_secs))
+        .set_automatic_connect(true);
+    if mqtt.tls {
+        builder.set_tls_client_config(tls::client_config(mqtt).map_err(|err| MpqttError::Config(err.to_string()))?);
+    }
+    Ok(builder.build()?)
+}
+
+// Warns when an update took longer than the delay configured after it, returning whether it did
+fn check_overrun(scope: &str, took_ms: u128, delay_secs: u64, setting: &str) -> bool {
+    let delay_ms = u128::from(delay_secs) * 1000;
+    if took_ms <= delay_ms {
+        return false;
+    }
+    warn!("{} update took {}ms, {}ms longer than {} ({}s) - the poll rate is limited by the serial line rather than the delay", scope, took_ms, took_ms - delay_ms, setting, delay_secs);
+    true
+}
+
+// Doubles error_delay for each error in a row, up to max_error_delay
+fn error_backoff(settings: &Settings, consecutive_errors: u32) -> u64 {
+    let factor = 1u64.checked_shl(consecutive_errors.saturating_sub(1)).unwrap_or(u64::MAX);
+    settings.error_delay.saturating_mul(factor).min(settings.max_error_delay)
+}
+
+// Opens the inverter, or the fixtures or capture standing in for it
+async fn open_inverter(mqtt_client: &Publisher, settings: &Settings, shutdown: &mut Shutdown) -> Result<SerialStream, MpqttError> {
+    if let Some(ref capture) = settings.replay {
+        return Ok(SerialStream::Replayed(Replay::load(capture)?));
+    }
+    if settings.simulate {
+        info!("Simulating the inverter with responses from {}", settings.simulation_fixtures);
+        return Ok(SerialStream::Simulated(Simulator::load(&settings.simulation_fixtures)?));
     }
+    Ok(open_with_retry(mqtt_client, settings, shutdown).await?)
+}
 
-    let std_file = unsafe { std::fs::File::from_raw_fd(fd) };
-    Ok(File::from_std(std_file))
+// Keeps trying to open the inverter device, e.g. while a USB adapter is re-enumerating
+async fn open_with_retry(mqtt_client: &Publisher, settings: &Settings, shutdown: &mut Shutdown) -> std::io::Result<SerialStream> {
+    let attempts = settings.inverter().open_retry_attempts;
+    let mut attempt = 1;
+    loop {
+        match serial::open_device(settings.inverter()) {
+            Ok(stream) => return Ok(stream),
+            Err(err) if attempt >= attempts => return Err(err),
+            Err(err) => {
+                warn!("Could not open {} (attempt {}/{}): {} - retrying in {}s", settings.inverter().path, attempt, attempts, err, settings.inverter().open_retry_delay);
+                if let Err(pub_error) = publish_error(mqtt_client, &settings.mqtt, None, format!("Could not open {}: {}", settings.inverter().path, err), None).await {
+                    error!("Failed to publish error: {}", pub_error);
+                }
+                if shutdown.sleep(Duration::from_secs(settings.inverter().open_retry_delay)).await {
+                    return Err(err);
+                }
+                attempt += 1;
+            }
+        }
+    }
 }
 
+// Published to {topic}/stats/<scope>
 #[derive(Serialize, Debug)]
 struct Stats {
+    scope: &'static str,
     update_duration: u128,
+    // Pass of the inner loop within its update, or update since the inverter was opened
+    iteration: u64,
+    // The update took longer than the delay after it, so serial latency sets the poll rate rather than the delay
+    overrun: bool,
+}
+
+// Published to {topic}/error when error_format is json
+#[derive(Serialize, Debug)]
+struct ErrorPayload<'a> {
+    command: Option<&'a str>,
+    message: String,
+    // Only set for responses that failed their crc check
+    #[serde(skip_serializing_if = "Option::is_none")]
+    crc: Option<CrcPayload>,
+    timestamp: u64,
+}
+
+// Lifecycle points published to {topic}/events
+#[derive(Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum Event {
+    Started,
+    MqttConnected,
+    MqttReconnected,
+    SerialOpened,
+    SerialLost,
+    Shutdown,
+}
+
+#[derive(Serialize, Debug)]
+struct EventPayload {
+    event: Event,
+    timestamp: u64,
+}
+
+// Published to {topic}/error in place of an empty payload when clear_error is structured
+#[derive(Serialize, Debug)]
+struct ClearedPayload {
+    cleared: bool,
+}
+
+// Hex, as the crc is written in protocol docs
+#[derive(Serialize, Debug)]
+struct CrcPayload {
+    expected: String,
+    actual: String,
+}
+
+// A command response and when it came off the serial line, so MQTT retries don't skew the timestamp
+struct Reading {
+    json: String,
+    received: SystemTime,
+}
+
+impl Reading {
+    fn now(json: String) -> Self {
+        Reading { json, received: SystemTime::now() }
+    }
+}
+
+// The last error seen by the update loop, so a fault that keeps recurring isn't published on every attempt
+#[derive(Default)]
+struct RepeatedErrors {
+    last: Option<String>,
+    count: u64,
+    published: Option<Instant>,
+}
+
+impl RepeatedErrors {
+    // Records an occurrence and returns its count if it should be published - always for a new error,
+    // otherwise once per summary interval
+    fn check(&mut self, error: &str, summary_interval: Duration) -> Option<u64> {
+        if self.last.as_deref() == Some(error) {
+            self.count += 1;
+        } else {
+            self.last = Some(error.to_string());
+            self.count = 1;
+            self.published = None;
+        }
+        match self.published {
+            Some(published) if published.elapsed() < summary_interval => None,
+            _ => {
+                self.published = Some(Instant::now());
+                Some(self.count)
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = RepeatedErrors::default();
+    }
+}
+
+// Static config fingerprint published once to {topic}/info
+#[derive(Serialize, Debug)]
+struct Info<'a> {
+    version: &'static str,
+    mode: Mode,
+    inverter_count: u8,
+    has_second_pv: bool,
+    default_interval: u64,
+    intervals: &'a HashMap<String, u64>,
+}
+
+// Heartbeat accumulated across update cycles and published to {topic}/status
+#[derive(Serialize, Debug)]
+struct RuntimeStats {
+    uptime: u64,
+    update_cycles: u64,
+    serial_errors: u64,
+    publish_failures: u64,
+    // Unix timestamp in seconds
+    last_successful_poll: Option<u64>,
+    #[serde(skip)]
+    started: Instant,
+    #[serde(skip)]
+    last_saved: Instant,
+}
+
+// The longest wait between attempts at the first connect to the broker
+const MAX_CONNECT_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+// How often the counters are written to state_file while polling, as well as whenever polling stops
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+impl RuntimeStats {
+    fn new() -> Self {
+        RuntimeStats {
+            uptime: 0,
+            update_cycles: 0,
+            serial_errors: 0,
+            publish_failures: 0,
+            last_successful_poll: None,
+            started: Instant::now(),
+            last_saved: Instant::now(),
+        }
+    }
+
+    fn restore(&mut self, counters: state::Counters) {
+        self.update_cycles = counters.update_cycles;
+        self.serial_errors = counters.serial_errors;
+        self.publish_failures = counters.publish_failures;
+    }
+
+    fn counters(&self) -> state::Counters {
+        state::Counters {
+            update_cycles: self.update_cycles,
+            serial_errors: self.serial_errors,
+            publish_failures: self.publish_failures,
+        }
+    }
+
+    fn record_success(&mut self) {
+        self.update_cycles += 1;
+        self.last_successful_poll = SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|since| since.as_secs());
+    }
+
+    fn record_error(&mut self, error: &MpqttError, metrics: &Metrics) {
+        self.update_cycles += 1;
+        if let MpqttError::MqttPublish(_) = error {
+            self.publish_failures += 1;
+            metrics.record_publish_failure();
+        } else {
+            self.serial_errors += 1;
+            metrics.record_serial_error();
+        }
+    }
 }
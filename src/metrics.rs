@@ -0,0 +1,169 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[cfg(feature = "metrics")]
+use crate::settings::MetricsSettings;
+#[cfg(feature = "metrics")]
+use log::{error, info};
+#[cfg(feature = "metrics")]
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+#[cfg(feature = "metrics")]
+use tokio::net::TcpListener;
+
+// Upper bounds in seconds for the update duration histogram
+const DURATION_BUCKETS: [f64; 9] = [0.5, 1.0, 2.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0];
+
+#[derive(Default)]
+#[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+struct Histogram {
+    buckets: [u64; 9],
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Default)]
+#[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+struct State {
+    // (inverter, command, field) -> latest value
+    values: BTreeMap<(String, String, String), f64>,
+    // Counters are keyed by inverter
+    serial_errors: BTreeMap<String, u64>,
+    publish_failures: BTreeMap<String, u64>,
+    // (inverter, scope) -> histogram
+    durations: BTreeMap<(String, String), Histogram>,
+}
+
+/// Shared handle the poll loop records into and the `/metrics` endpoint reads from
+#[derive(Clone, Default)]
+pub struct Metrics {
+    state: Arc<Mutex<State>>,
+    // Label added to everything recorded through this handle
+    inverter: String,
+}
+
+impl Metrics {
+    /// A handle onto the same registry that labels everything it records with the given inverter id
+    pub fn for_inverter(&self, inverter: &str) -> Self {
+        Metrics {
+            state: self.state.clone(),
+            inverter: inverter.to_string(),
+        }
+    }
+
+    /// Records every numeric (and boolean) field of a published JSON payload as a gauge
+    pub fn record_values(&self, command: &str, json: &str) {
+        if let Ok(value) = serde_json::from_str::<serde_json::Value>(json) {
+            let mut state = self.state.lock().unwrap();
+            flatten(&mut state.values, &self.inverter, command, "", &value);
+        }
+    }
+
+    pub fn record_serial_error(&self) {
+        *self.state.lock().unwrap().serial_errors.entry(self.inverter.clone()).or_default() += 1;
+    }
+
+    pub fn record_publish_failure(&self) {
+        *self.state.lock().unwrap().publish_failures.entry(self.inverter.clone()).or_default() += 1;
+    }
+
+    pub fn record_duration(&self, scope: &str, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let mut state = self.state.lock().unwrap();
+        let histogram = state.durations.entry((self.inverter.clone(), scope.to_string())).or_default();
+        for (bucket, bound) in histogram.buckets.iter_mut().zip(DURATION_BUCKETS.iter()) {
+            if seconds <= *bound {
+                *bucket += 1;
+            }
+        }
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+
+    #[cfg(feature = "metrics")]
+    fn render(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let mut out = String::new();
+
+        out.push_str("# HELP mpqtt_value Latest value reported by the inverter\n# TYPE mpqtt_value gauge\n");
+        for ((inverter, command, field), value) in &state.values {
+            out.push_str(&format!("mpqtt_value{{inverter=\"{}\",command=\"{}\",field=\"{}\"}} {}\n", inverter, command, field, value));
+        }
+
+        out.push_str("# HELP mpqtt_serial_errors_total Failed update cycles caused by the inverter\n# TYPE mpqtt_serial_errors_total counter\n");
+        for (inverter, count) in &state.serial_errors {
+            out.push_str(&format!("mpqtt_serial_errors_total{{inverter=\"{}\"}} {}\n", inverter, count));
+        }
+        out.push_str("# HELP mpqtt_publish_failures_total MQTT publishes that failed after retrying\n# TYPE mpqtt_publish_failures_total counter\n");
+        for (inverter, count) in &state.publish_failures {
+            out.push_str(&format!("mpqtt_publish_failures_total{{inverter=\"{}\"}} {}\n", inverter, count));
+        }
+
+        out.push_str("# HELP mpqtt_update_duration_seconds Time spent polling the inverter\n# TYPE mpqtt_update_duration_seconds histogram\n");
+        for ((inverter, scope), histogram) in &state.durations {
+            for (bucket, bound) in histogram.buckets.iter().zip(DURATION_BUCKETS.iter()) {
+                out.push_str(&format!("mpqtt_update_duration_seconds_bucket{{inverter=\"{}\",scope=\"{}\",le=\"{}\"}} {}\n", inverter, scope, bound, bucket));
+            }
+            out.push_str(&format!("mpqtt_update_duration_seconds_bucket{{inverter=\"{}\",scope=\"{}\",le=\"+Inf\"}} {}\n", inverter, scope, histogram.count));
+            out.push_str(&format!("mpqtt_update_duration_seconds_sum{{inverter=\"{}\",scope=\"{}\"}} {}\n", inverter, scope, histogram.sum));
+            out.push_str(&format!("mpqtt_update_duration_seconds_count{{inverter=\"{}\",scope=\"{}\"}} {}\n", inverter, scope, histogram.count));
+        }
+        out
+    }
+}
+
+fn flatten(values: &mut BTreeMap<(String, String, String), f64>, inverter: &str, command: &str, prefix: &str, value: &serde_json::Value) {
+    match value {
+        serde_json::Value::Number(number) => {
+            if let Some(number) = number.as_f64() {
+                values.insert((inverter.to_string(), command.to_string(), prefix.to_string()), number);
+            }
+        }
+        serde_json::Value::Bool(flag) => {
+            values.insert((inverter.to_string(), command.to_string(), prefix.to_string()), if *flag { 1.0 } else { 0.0 });
+        }
+        serde_json::Value::Object(fields) => {
+            for (key, field) in fields {
+                let name = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+                flatten(values, inverter, command, &name, field);
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Serves `/metrics` in the Prometheus text format until the process exits
+#[cfg(feature = "metrics")]
+pub async fn spawn_server(settings: &MetricsSettings, metrics: Metrics) -> std::io::Result<()> {
+    let address = format!("{}:{}", settings.bind, settings.port);
+    let mut listener = TcpListener::bind(&address).await?;
+    info!("Serving metrics on http://{}/metrics", address);
+
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(err) => {
+                    error!("Error accepting metrics connection: {}", err);
+                    continue;
+                }
+            };
+            let metrics = metrics.clone();
+            tokio::spawn(async move {
+                let mut request = [0u8; 1024];
+                let read = match socket.read(&mut request).await {
+                    Ok(read) => read,
+                    Err(_) => return,
+                };
+                let response = if request[..read].starts_with(b"GET /metrics") {
+                    let body = metrics.render();
+                    format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+                } else {
+                    String::from("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                };
+                let _ = socket.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,33 @@
+// Descriptions for the single letter QMOD device mode, per protocol mode so another protocol can list its own codes
+
+use crate::settings::Mode;
+
+pub struct DeviceMode {
+    pub code: char,
+    pub description: &'static str,
+}
+
+const STANDARD_MODES: &[DeviceMode] = &[
+    DeviceMode { code: 'P', description: "Power On Mode" },
+    DeviceMode { code: 'S', description: "Standby Mode" },
+    DeviceMode { code: 'L', description: "Line Mode" },
+    DeviceMode { code: 'B', description: "Battery Mode" },
+    DeviceMode { code: 'F', description: "Fault Mode" },
+    DeviceMode { code: 'H', description: "Power Saving Mode" },
+];
+
+fn table(mode: Mode) -> &'static [DeviceMode] {
+    match mode {
+        Mode::Standard | Mode::Phocos => STANDARD_MODES,
+    }
+}
+
+/// The description of a QMOD code, or "Unknown" for codes the protocol's table doesn't list
+pub fn describe(mode: Mode, code: &str) -> &'static str {
+    let table = table(mode);
+    let mut chars = code.chars();
+    match (chars.next(), chars.next()) {
+        (Some(code), None) => table.iter().find(|known| known.code == code.to_ascii_uppercase()).map_or("Unknown", |known| known.description),
+        _ => "Unknown",
+    }
+}
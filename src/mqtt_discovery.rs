@@ -1,184 +1,845 @@
-use crate::settings::MqttSettings;
-use mqtt_async_client::client::{Client, Publish as PublishOpts, QoS};
+use crate::error::MpqttError;
+use crate::publisher::Publisher;
+use crate::settings::{DerivedSensorSettings, Mode, MqttSettings};
+use crate::warnings::{QPIGS_STATUS_FLAGS, QPIWS_WARNINGS};
 use serde_derive::Serialize;
 
 use log::{debug, info};
 
-pub async fn run_mqtt_discovery(client: &Client, cfg: &MqttSettings, inverter_count: u8, mode: &str) -> Result<(), Box<dyn std::error::Error>> {
+#[allow(clippy::too_many_arguments)]
+pub async fn run_mqtt_discovery(client: &Publisher, cfg: &MqttSettings, qpgs_indexes: &[u8], mode: Mode, has_second_pv: bool, disabled_commands: &[String], derived: &[DerivedSensorSettings], stale_marker: bool) -> Result<(), MpqttError> {
+    if !cfg.discovery.enabled {
+        info!("MQTT Discovery is disabled");
+        return Ok(());
+    }
     info!("Running MQTT Discovery");
+    // Disabled commands are never published so their sensors would only ever be unavailable
+    let enabled = |command: &str| !disabled_commands.iter().any(|disabled| disabled.eq_ignore_ascii_case(command));
 
     // Register error sensor
     register_error_sensor(client, cfg).await?;
 
+    // Register the stale data marker
+    if stale_marker {
+        publish_binary_sensor(client, cfg, "stale", None, "Stale data", "timer-alert-outline", Some("problem")).await?;
+    }
+
     // Register stats sensors
-    register_sensor(client, cfg, "inner_stats", "update_duration", "Last Inner Update Duration", Some("ms".to_string()), "clock").await?;
-    register_sensor(client, cfg, "outer_stats", "update_duration", "Last Outer Update Duration", Some("ms".to_string()), "clock").await?;
+    for scope in STATS_SCOPES {
+        register_sensor(client, cfg, &format!("stats/{}", scope.to_ascii_lowercase()), "update_duration", &format!("Last {} Update Duration", scope), Some("ms".to_string()), "clock").await?;
+        if cfg.legacy_stats_topics {
+            register_sensor(client, cfg, &format!("{}_stats", scope.to_ascii_lowercase()), "update_duration", &format!("Last {} Update Duration (deprecated)", scope), Some("ms".to_string()), "clock").await?;
+        }
+    }
+
+    // Register serial line counters
+    for (field, name) in SERIAL_STATS {
+        publish_sensor_for(client, cfg, &get_device_hassio(cfg), "serial_stats", Some(field), name, None, "counter", None, Some("total_increasing")).await?;
+    }
+
+    // Register the runtime counters from {topic}/status
+    for sensor in STATUS_SENSORS {
+        publish_sensor_for(
+            client,
+            cfg,
+            &get_device_hassio(cfg),
+            "status",
+            Some(sensor.field),
+            sensor.name,
+            sensor.unit.map(String::from),
+            sensor.icon,
+            sensor.device_class.map(String::from),
+            Some("total_increasing"),
+        )
+        .await?;
+    }
 
     // Register QID Response
-    register_sensor(client, cfg, "qid", "serial_number", "Serial number", None, "slot-machine").await?;
+    if enabled("qid") {
+        register_sensor(client, cfg, "qid", "serial_number", "Serial number", None, "slot-machine").await?;
+    }
 
     // Register QPI Response
-    register_sensor(client, cfg, "qpi", "protocol_id", "Protocol ID", None, "slot-machine").await?;
+    if enabled("qpi") {
+        register_sensor(client, cfg, "qpi", "protocol_id", "Protocol ID", None, "slot-machine").await?;
+    }
 
     // Register software version 1
-    register_sensor(client, cfg, "qvfw", "major", "CPU Firmware Version Major", None, "update").await?;
-    register_sensor(client, cfg, "qvfw", "minor", "CPU Firmware Version Minor", None, "update").await?;
+    if enabled("qvfw") {
+        register_sensor(client, cfg, "qvfw", "major", "CPU Firmware Version Major", None, "update").await?;
+        register_sensor(client, cfg, "qvfw", "minor", "CPU Firmware Version Minor", None, "update").await?;
+    }
+
+    // Register QMN Response
+    if enabled("qmn") {
+        register_sensor(client, cfg, "qmn", "model", "Model", None, "information-outline").await?;
+    }
 
     // Register software version 2
     register_sensor(client, cfg, "qvfw2", "major", "CPU Firmware Version 2 Major", None, "update").await?;
     register_sensor(client, cfg, "qvfw2", "minor", "CPU Firmware Version 2 Minor", None, "update").await?;
 
     // Register software version 3
-    if mode != "phocos" {
+    if mode != Mode::Phocos {
         register_sensor(client, cfg, "qvfw3", "major", "CPU Firmware Version 2 Major", None, "update").await?;
         register_sensor(client, cfg, "qvfw3", "minor", "CPU Firmware Version 2 Minor", None, "update").await?;
     }
 
     // Register QMOD
-    register_sensor(client, cfg, "qmod", "mode", "Device mode", None, "information-outline").await?;
-
-    // Register QPIRI Sensors
-    register_sensor(client, cfg, "qpiri", "grid_rating_voltage", "Grid Rating Voltage", Some("V".to_string()), "power-plug").await?;
-    register_sensor(client, cfg, "qpiri", "grid_rating_current", "Grid Rating Current", Some("A".to_string()), "current-dc").await?;
-    if mode != "phocos" {
-        register_sensor(client, cfg, "qpiri", "ac_output_rating_voltage", "AC Output Rating Voltage", Some("V".to_string()), "power-plug").await?;
-        register_sensor(client, cfg, "qpiri", "ac_out_rating_frequency", "AC Output Rating Frequency", Some("Hz".to_string()), "current-ac").await?;
-        register_sensor(client, cfg, "qpiri", "ac_out_rating_current", "AC Output Rating Current", Some("A".to_string()), "current-dc").await?;
-        register_sensor(client, cfg, "qpiri", "ac_out_rating_apparent_power", "AC Output Rating Apparent Power", Some("W".to_string()), "power-plug").await?;
-        register_sensor(client, cfg, "qpiri", "ac_out_rating_active_power", "AC Output Rating Active Voltage", Some("W".to_string()), "power-plug").await?;
-        register_sensor(client, cfg, "qpiri", "battery_rating_voltage", "Battery Rating Voltage", Some("V".to_string()), "current-dc").await?;
-        register_sensor(client, cfg, "qpiri", "battery_recharge_voltage", "Battery Recharge Voltage", Some("V".to_string()), "current-dc").await?;
-        register_sensor(client, cfg, "qpiri", "battery_under_voltage", "Battery Under Voltage", Some("V".to_string()), "current-dc").await?;
-        register_sensor(client, cfg, "qpiri", "battery_bulk_voltage", "Battery Bulk Voltage", Some("V".to_string()), "current-dc").await?;
-        register_sensor(client, cfg, "qpiri", "battery_float_voltage", "Battery Float Voltage", Some("V".to_string()), "current-dc").await?;
-        register_sensor(client, cfg, "qpiri", "battery_redischarge_voltage", "Battery Redischarge Voltage", Some("V".to_string()), "battery-negative").await?;
-        register_sensor(client, cfg, "qpiri", "battery_type", "Battery Type", None, "battery").await?;
-        register_sensor(client, cfg, "qpiri", "max_ac_charging_current", "Max AC Charging Current", Some("A".to_string()), "current-ac").await?;
-        register_sensor(client, cfg, "qpiri", "max_charging_current", "Max Charging Current", Some("A".to_string()), "current-ac").await?;
-        register_sensor(client, cfg, "qpiri", "input_voltage_range", "Input Voltage range", None, "power-plug").await?;
-        register_sensor(client, cfg, "qpiri", "output_source_priority", "Output Source Priority", None, "power-plug").await?;
-        register_sensor(client, cfg, "qpiri", "charge_source_priority", "Charge Source Priority", None, "power-plug").await?;
-        register_sensor(client, cfg, "qpiri", "machine_type", "Machine Type", None, "power-plug").await?;
-        register_sensor(client, cfg, "qpiri", "topology", "Topology", None, "power-plug").await?;
-        register_sensor(client, cfg, "qpiri", "output_mode", "Output mode", None, "power-plug").await?;
+    if enabled("qmod") {
+        register_sensor(client, cfg, "qmod", "mode", "Device mode", None, "information-outline").await?;
+        register_sensor(client, cfg, "qmod", "description", "Device mode description", None, "information-outline").await?;
+    }
+
+    // Register QPIRI Sensors, each field is also published on its own topic
+    if enabled("qpiri") {
+        let sensors = if mode == Mode::Phocos { QPIRI_REDUCED_SENSORS } else { QPIRI_SENSORS };
+        for sensor in sensors {
+            publish_sensor(client, cfg, &format!("qpiri/{}", sensor.field), None, sensor.name, sensor.unit.map(String::from), sensor.icon, sensor.device_class.map(String::from)).await?;
+        }
     }
 
     // Register QPIGS Sensors
-    if mode != "phocos" {
-        register_sensor(client, cfg, "qpigs", "grid_voltage", "Grid Voltage", Some("V".to_string()), "power-plug").await?;
-        register_sensor(client, cfg, "qpigs", "grid_frequency", "Grid Frequency", Some("Hz".to_string()), "current-ac").await?;
-        register_sensor(client, cfg, "qpigs", "ac_out_voltage", "Out Voltage", Some("V".to_string()), "power-plug").await?;
-        register_sensor(client, cfg, "qpigs", "ac_out_frequency", "Out Frequency", Some("Hz".to_string()), "current-ac").await?;
-        register_sensor(client, cfg, "qpigs", "ac_out_apparent_power", "Out apparent power", Some("W".to_string()), "power-plug").await?;
-        register_sensor(client, cfg, "qpigs", "ac_out_active_power", "Out active power", Some("W".to_string()), "power-plug").await?;
-        register_sensor(client, cfg, "qpigs", "out_load_percent", "Out load percent", Some("%".to_string()), "brightness-percent").await?;
-        register_sensor(client, cfg, "qpigs", "bus_voltage", "Bus Voltage", Some("V".to_string()), "details").await?;
-        register_sensor(client, cfg, "qpigs", "battery_voltage", "Battery Voltage", Some("V".to_string()), "battery-outline").await?;
-        register_sensor(client, cfg, "qpigs", "battery_charge_current", "Battery charge current", Some("A".to_string()), "current-dc").await?;
-        register_sensor(client, cfg, "qpigs", "battery_capacity", "Battery capacity", Some("%".to_string()), "battery-outline").await?;
-        register_sensor(client, cfg, "qpigs", "inverter_heat_sink_temp", "Heat sink temperature", Some("°C".to_string()), "details").await?;
-        register_sensor(client, cfg, "qpigs", "pv_input_current", "PV Input Current", Some("A".to_string()), "solar-power").await?;
-        register_sensor(client, cfg, "qpigs", "pv_input_voltage", "PV Input Voltage", Some("V".to_string()), "solar-power").await?;
-        register_sensor(client, cfg, "qpigs", "battery_scc_voltage", "Battery SCC Voltage", Some("V".to_string()), "current-dc").await?;
-        register_sensor(client, cfg, "qpigs", "battery_discharge_current", "Battery discharge current", Some("A".to_string()), "battery-negative").await?;
-        register_sensor(client, cfg, "qpigs", "device_status.charge_status", "Device charge status", None, "power-plug").await?;
-        register_sensor(client, cfg, "qpigs", "device_status.active_load", "Active load", None, "power").await?;
+    if mode != Mode::Phocos && enabled("qpigs") {
+        for sensor in QPIGS_SENSORS {
+            publish_sensor(client, cfg, "qpigs", Some(sensor.field), sensor.name, sensor.unit.map(String::from), sensor.icon, sensor.device_class.map(String::from)).await?;
+        }
     }
 
-    assert_ne!(inverter_count, 0);
-    for index in 0..=inverter_count {
-        // Register QPGS Sensors
-        register_sensor(client, cfg, &format!("qpgs{}", index), "other_units_connected", &format!("Other Units Connected - Inverter {}", index), None, "power-plug").await?;
-        register_sensor(client, cfg, &format!("qpgs{}", index), "serial_number", &format!("Serial Number - Inverter {}", index), None, "details").await?;
-        register_sensor(client, cfg, &format!("qpgs{}", index), "operation_mode", &format!("Operation Mode - Inverter {}", index), None, "slot-machine").await?;
-        register_sensor(client, cfg, &format!("qpgs{}", index), "fault_code", &format!("Fault Code - Inverter {}", index), None, "alert").await?;
-        register_sensor(client, cfg, &format!("qpgs{}", index), "ac_input_voltage", &format!("AC Input Voltage - Inverter {}", index), Some("Vac".to_string()), "power-plug").await?;
-        register_sensor(client, cfg, &format!("qpgs{}", index), "ac_input_frequency", &format!("AC Input Frequency - Inverter {}", index), Some("Hz".to_string()), "current-ac").await?;
-        register_sensor(client, cfg, &format!("qpgs{}", index), "ac_output_voltage", &format!("AC Output Voltage - Inverter {}", index), Some("Vac".to_string()), "power-plug").await?;
-        register_sensor(client, cfg, &format!("qpgs{}", index), "ac_output_frequency", &format!("AC Output Frequency - Inverter {}", index), Some("Hz".to_string()), "current-ac").await?;
-        register_sensor(client, cfg, &format!("qpgs{}", index), "ac_output_apparent_power", &format!("AC Output Apparent Power - Inverter {}", index), Some("VA".to_string()), "power-plug").await?;
-        register_sensor(client, cfg, &format!("qpgs{}", index), "ac_output_active_power", &format!("AC Output Active Power - Inverter {}", index), Some("W".to_string()), "power-plug").await?;
-        register_sensor(
-            client,
-            cfg,
-            &format!("qpgs{}", index),
-            "percentage_of_nominal_output_power",
-            &format!("Percentage Of Nominal Output Power - Inverter {}", index),
-            Some("% of single inverter".to_string()),
-            "power-plug",
-        )
-        .await?;
-        register_sensor(client, cfg, &format!("qpgs{}", index), "battery_voltage", &format!("Battery Votlage - Inverter {}", index), Some("Vdc".to_string()), "battery").await?;
-        register_sensor(client, cfg, &format!("qpgs{}", index), "battery_charging_current", &format!("Battery Charging Current - Inverter {}", index), Some("Adc".to_string()), "battery-positive").await?;
-        register_sensor(client, cfg, &format!("qpgs{}", index), "battery_approx_state_of_charge", &format!("Battery State of Charge - Inverter {}", index), Some("%".to_string()), "battery-outline").await?;
-        register_sensor(client, cfg, &format!("qpgs{}", index), "pv_input_voltage", &format!("PV Input Voltage - Inverter {}", index), Some("Vdc".to_string()), "solar-power").await?;
-        register_sensor(client, cfg, &format!("qpgs{}", index), "total_charging_current", &format!("Total Charging Current - Inverter {}", index), Some("Adc".to_string()), "battery-positive").await?;
-        register_sensor(client, cfg, &format!("qpgs{}", index), "total_ac_output_apparent_power", &format!("Total AC Output Apparent Power - Inverter {}", index), Some("VA".to_string()), "power-plug").await?;
-        register_sensor(client, cfg, &format!("qpgs{}", index), "total_ac_output_active_power", &format!("Total AC Output Active Power - Inverter {}", index), Some("W".to_string()), "power-plug").await?;
-        register_sensor(
-            client,
-            cfg,
-            &format!("qpgs{}", index),
-            "total_percentage_of_nominal_output_power",
-            &format!("Total Percentage Of Output Power - Inverter {}", index),
-            Some("% of inverters".to_string()),
-            "power-plug",
-        )
-        .await?;
-        register_sensor(client, cfg, &format!("qpgs{}", index), "inverter_status.mppt_active", &format!("MPPT Active - Inverter {}", index), None, "order-bool-ascending-variant").await?;
-        register_sensor(client, cfg, &format!("qpgs{}", index), "inverter_status.ac_charging", &format!("AC Charging - Inverter {}", index), None, "order-bool-ascending-variant").await?;
-        register_sensor(client, cfg, &format!("qpgs{}", index), "inverter_status.solar_charging", &format!("Solar Charging - Inverter {}", index), None, "order-bool-ascending-variant").await?;
-        register_sensor(client, cfg, &format!("qpgs{}", index), "inverter_status.battery_status", &format!("Battery Status - Inverter {}", index), None, "battery-heart-variant").await?;
-        register_sensor(client, cfg, &format!("qpgs{}", index), "inverter_status.ac_input", &format!("AC Input - Inverter {}", index), None, "order-bool-ascending-variant").await?;
-        register_sensor(client, cfg, &format!("qpgs{}", index), "inverter_status.ac_output", &format!("AC Output - Inverter {}", index), None, "order-bool-ascending-variant").await?;
-        register_sensor(client, cfg, &format!("qpgs{}", index), "inverter_status.reserved_bit", &format!("Reserved - Inverter {}", index), None, "order-bool-ascending-variant").await?;
-        register_sensor(client, cfg, &format!("qpgs{}", index), "ac_output_mode", &format!("AC Output Mode - Inverter {}", index), None, "slot-machine").await?;
-        register_sensor(client, cfg, &format!("qpgs{}", index), "battery_charging_source_priority", &format!("Battery Charging Source - Inverter {}", index), None, "ev-station").await?;
-        register_sensor(client, cfg, &format!("qpgs{}", index), "max_charging_current_set", &format!("Max Charging Current Set - Inverter {}", index), Some("Adc".to_string()), "current-dc").await?;
-        register_sensor(client, cfg, &format!("qpgs{}", index), "max_charging_current_possible", &format!("Max Charging Current Possible - Inverter {}", index), Some("Adc".to_string()), "current-dc").await?;
-        register_sensor(client, cfg, &format!("qpgs{}", index), "max_ac_charging_current_set", &format!("Max AC Charging Current Set - Inverter {}", index), Some("Adc".to_string()), "current-dc").await?;
-        register_sensor(client, cfg, &format!("qpgs{}", index), "pv_input_current", &format!("PV Input Current - Inverter {}", index), Some("Adc".to_string()), "current-dc").await?;
-        register_sensor(client, cfg, &format!("qpgs{}", index), "battery_discharge_current", &format!("Battery Discharge Current - Inverter {}", index), Some("Adc".to_string()), "current-dc").await?;
-
-        // manually calculated - not reported from qpgs directly
-        register_sensor(client, cfg, &format!("qpgs{}", index), "pv_input_power", &format!("PV Input Power - Inverter {}", index), Some("W".to_string()), "solar-panel").await?;
-        register_sensor(client, cfg, &format!("qpgs{}", index), "battery_charging_power", &format!("Battery Charging Power - Inverter {}", index), Some("W".to_string()), "battery-positive").await?;
-        register_sensor(client, cfg, &format!("qpgs{}", index), "battery_discharging_power", &format!("Battery Discharging Power - Inverter {}", index), Some("W".to_string()), "battery-negative").await?;
+    // Register the AC input and output readings, published on their own topics from QPIGS
+    if mode != Mode::Phocos && enabled("qpigs") {
+        for sensor in QPIGS_AC_SENSORS {
+            publish_sensor(client, cfg, &format!("qpigs/{}", sensor.field), None, sensor.name, sensor.unit.map(String::from), sensor.icon, sensor.device_class.map(String::from)).await?;
+        }
+    }
+
+    // Register the battery state of charge and signed battery power, published on their own topics from QPIGS
+    if mode != Mode::Phocos && enabled("qpigs") {
+        publish_sensor(client, cfg, "qpigs/battery_soc", None, "Battery state of charge", Some("%".to_string()), "battery", Some("battery".to_string())).await?;
+        publish_sensor(client, cfg, "battery_power", None, "Battery power", Some("W".to_string()), "battery-charging", Some("power".to_string())).await?;
+    }
+
+    // Register the QPIGS device status bits, each published on its own topic
+    if mode != Mode::Phocos && enabled("qpigs") {
+        for flag in QPIGS_STATUS_FLAGS {
+            publish_binary_sensor(client, cfg, &format!("qpigs/{}", flag.field), None, flag.name, flag.icon, None).await?;
+        }
+    }
+
+    // Register QPIGS2 Sensors
+    if has_second_pv && enabled("qpigs2") {
+        for sensor in QPIGS2_SENSORS {
+            publish_sensor(client, cfg, "qpigs2", Some(sensor.field), sensor.name, sensor.unit.map(String::from), sensor.icon, sensor.device_class.map(String::from)).await?;
+        }
+    }
+
+    // Register QFLAG Binary Sensors
+    if mode != Mode::Phocos && enabled("qflag") {
+        register_binary_sensor(client, cfg, "qflag", "buzzer", "Buzzer", "volume-high").await?;
+        register_binary_sensor(client, cfg, "qflag", "overload_bypass", "Overload bypass", "transit-detour").await?;
+        register_binary_sensor(client, cfg, "qflag", "power_saving", "Power saving", "leaf").await?;
+        register_binary_sensor(client, cfg, "qflag", "lcd_escape_to_default", "LCD escape to default page", "monitor").await?;
+        register_binary_sensor(client, cfg, "qflag", "overload_restart", "Overload restart", "restart").await?;
+        register_binary_sensor(client, cfg, "qflag", "over_temperature_restart", "Over temperature restart", "restart").await?;
+        register_binary_sensor(client, cfg, "qflag", "lcd_backlight", "LCD backlight", "brightness-6").await?;
+        register_binary_sensor(client, cfg, "qflag", "primary_source_interrupt_alarm", "Primary source interrupt alarm", "alarm-light").await?;
+        register_binary_sensor(client, cfg, "qflag", "fault_code_record", "Fault code record", "history").await?;
+    }
+
+    // Register QT Sensors
+    if enabled("qt") {
+        register_sensor(client, cfg, "qt", "time", "Device time", None, "clock-outline").await?;
+        register_sensor(client, cfg, "qt", "clock_drift_seconds", "Clock drift", Some("s".to_string()), "clock-alert-outline").await?;
     }
 
-    // Register QPIWS response
-
-    register_sensor(client, cfg, "qpiws", "inverter_fault", "Inverter fault", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "bus_over", "Bus over", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "bus_under", "Bus under", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "bus_soft_fail", "Bus soft fail", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "line_fail", "Line fail", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "opv_short", "OPV Short", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "inverter_voltage_too_low", "Inverter voltage too low", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "inverter_voltage_too_high", "Inverter voltage too high", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "over_temperature", "Over temperature", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "fan_locked", "Fan locked", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "battery_voltage_high", "Battery voltage high", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "battery_low_alarm", "Battery low alarm", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "battery_under_shutdown", "Battery under shutdown", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "over_load", "Over load", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "eeprom_fault", "EEPROM Fault", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "inverter_over_current", "Inverter over current", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "inverter_soft_fail", "Inverter soft fail", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "self_test_fail", "Self test fail", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "op_dc_voltage_over", "OP DC Voltage over", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "bat_open", "Bat open", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "current_sensor_fail", "Current sensor fail", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "battery_short", "Battery short", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "power_limit", "Power limit", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "pv_voltage_high", "PV Voltage high", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "mppt_overload_fault", "MPPT Overload fault", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "mppt_overload_warning", "MPPT Overload warning", None, "alert").await?;
-    register_sensor(client, cfg, "qpiws", "battery_too_low_to_charge", "Battery too low to charge", None, "alert").await?;
+    // Register QPGS Sensors, each parallel unit as its own device
+    for index in qpgs_indexes.iter().cloned().filter(|_| enabled("qpgs")) {
+        let command = format!("qpgs{}", index);
+        let device = get_unit_device_hassio(cfg, index);
+        for sensor in QPGS_SENSORS {
+            let name = format!("{} - Inverter {}", sensor.name, index);
+            publish_sensor_for(client, cfg, &device, &command, Some(sensor.field), &name, sensor.unit.map(String::from), sensor.icon, sensor.device_class.map(String::from), None).await?;
+        }
+        publish_sensor_for(client, cfg, &device, &format!("{}/fault_text", command), Some("text"), &format!("Fault - Inverter {}", index), None, "alert-octagon", None, None).await?;
+    }
+
+    // Register derived sensors, each published on its own topic
+    for sensor in derived {
+        publish_sensor(client, cfg, &sensor.name, None, &sensor.name.replace("_", " "), sensor.unit.clone(), "calculator", sensor.device_class.clone()).await?;
+    }
+
+    // Register QPIWS warnings, each published on its own topic
+    for warning in QPIWS_WARNINGS.iter().filter(|_| enabled("qpiws")) {
+        publish_binary_sensor(client, cfg, &format!("qpiws/{}", warning.field), None, warning.name, "alert", Some("problem")).await?;
+    }
 
     Ok(())
 }
 
+// Fields of {topic}/serial_stats
+const SERIAL_STATS: [(&str, &str); 4] = [("bytes_written", "Serial Bytes Written"), ("bytes_read", "Serial Bytes Read"), ("frames_ok", "Serial Frames OK"), ("frames_crc_fail", "Serial Frames Failing CRC")];
+
+// Fields of {topic}/status, all of them only ever count up (until a restart when there's no state_file)
+const STATUS_SENSORS: &[SensorSpec] = &[
+    SensorSpec {
+        field: "uptime",
+        name: "Uptime",
+        unit: Some("s"),
+        device_class: Some("duration"),
+        icon: "timer-outline",
+    },
+    SensorSpec {
+        field: "update_cycles",
+        name: "Update Cycles",
+        unit: None,
+        device_class: None,
+        icon: "counter",
+    },
+    SensorSpec {
+        field: "serial_errors",
+        name: "Serial Errors",
+        unit: None,
+        device_class: None,
+        icon: "alert-circle-outline",
+    },
+    SensorSpec {
+        field: "publish_failures",
+        name: "Publish Failures",
+        unit: None,
+        device_class: None,
+        icon: "alert-circle-outline",
+    },
+];
+
+// The update loops timed on {topic}/stats/<scope>
+const STATS_SCOPES: [&str; 2] = ["Inner", "Outer"];
+
+/// Home Assistant metadata for one field of a command's JSON payload
+struct SensorSpec {
+    field: &'static str,
+    name: &'static str,
+    unit: Option<&'static str>,
+    device_class: Option<&'static str>,
+    icon: &'static str,
+}
+
+const QPIRI_SENSORS: &[SensorSpec] = &[
+    SensorSpec {
+        field: "grid_rating_voltage",
+        name: "Grid Rating Voltage",
+        unit: Some("V"),
+        device_class: Some("voltage"),
+        icon: "power-plug",
+    },
+    SensorSpec {
+        field: "grid_rating_current",
+        name: "Grid Rating Current",
+        unit: Some("A"),
+        device_class: Some("current"),
+        icon: "current-dc",
+    },
+    SensorSpec {
+        field: "ac_output_rating_voltage",
+        name: "AC Output Rating Voltage",
+        unit: Some("V"),
+        device_class: Some("voltage"),
+        icon: "power-plug",
+    },
+    SensorSpec {
+        field: "ac_out_rating_frequency",
+        name: "AC Output Rating Frequency",
+        unit: Some("Hz"),
+        device_class: Some("frequency"),
+        icon: "current-ac",
+    },
+    SensorSpec {
+        field: "ac_out_rating_current",
+        name: "AC Output Rating Current",
+        unit: Some("A"),
+        device_class: Some("current"),
+        icon: "current-dc",
+    },
+    SensorSpec {
+        field: "ac_out_rating_apparent_power",
+        name: "AC Output Rating Apparent Power",
+        unit: Some("VA"),
+        device_class: Some("apparent_power"),
+        icon: "power-plug",
+    },
+    SensorSpec {
+        field: "ac_out_rating_active_power",
+        name: "AC Output Rating Active Power",
+        unit: Some("W"),
+        device_class: Some("power"),
+        icon: "power-plug",
+    },
+    SensorSpec {
+        field: "battery_rating_voltage",
+        name: "Battery Rating Voltage",
+        unit: Some("V"),
+        device_class: Some("voltage"),
+        icon: "current-dc",
+    },
+    SensorSpec {
+        field: "battery_recharge_voltage",
+        name: "Battery Recharge Voltage",
+        unit: Some("V"),
+        device_class: Some("voltage"),
+        icon: "current-dc",
+    },
+    SensorSpec {
+        field: "battery_under_voltage",
+        name: "Battery Under Voltage",
+        unit: Some("V"),
+        device_class: Some("voltage"),
+        icon: "current-dc",
+    },
+    SensorSpec {
+        field: "battery_bulk_voltage",
+        name: "Battery Bulk Voltage",
+        unit: Some("V"),
+        device_class: Some("voltage"),
+        icon: "current-dc",
+    },
+    SensorSpec {
+        field: "battery_float_voltage",
+        name: "Battery Float Voltage",
+        unit: Some("V"),
+        device_class: Some("voltage"),
+        icon: "current-dc",
+    },
+    SensorSpec {
+        field: "battery_redischarge_voltage",
+        name: "Battery Redischarge Voltage",
+        unit: Some("V"),
+        device_class: Some("voltage"),
+        icon: "battery-negative",
+    },
+    SensorSpec {
+        field: "battery_type",
+        name: "Battery Type",
+        unit: None,
+        device_class: None,
+        icon: "battery",
+    },
+    SensorSpec {
+        field: "max_ac_charging_current",
+        name: "Max AC Charging Current",
+        unit: Some("A"),
+        device_class: Some("current"),
+        icon: "current-ac",
+    },
+    SensorSpec {
+        field: "max_charging_current",
+        name: "Max Charging Current",
+        unit: Some("A"),
+        device_class: Some("current"),
+        icon: "current-ac",
+    },
+    SensorSpec {
+        field: "input_voltage_range",
+        name: "Input Voltage range",
+        unit: None,
+        device_class: None,
+        icon: "power-plug",
+    },
+    SensorSpec {
+        field: "output_source_priority",
+        name: "Output Source Priority",
+        unit: None,
+        device_class: None,
+        icon: "power-plug",
+    },
+    SensorSpec {
+        field: "charge_source_priority",
+        name: "Charge Source Priority",
+        unit: None,
+        device_class: None,
+        icon: "power-plug",
+    },
+    SensorSpec {
+        field: "machine_type",
+        name: "Machine Type",
+        unit: None,
+        device_class: None,
+        icon: "power-plug",
+    },
+    SensorSpec {
+        field: "topology",
+        name: "Topology",
+        unit: None,
+        device_class: None,
+        icon: "power-plug",
+    },
+    SensorSpec {
+        field: "output_mode",
+        name: "Output mode",
+        unit: None,
+        device_class: None,
+        icon: "power-plug",
+    },
+];
+
+// phocos units answer with the reduced QPIRI
+const QPIRI_REDUCED_SENSORS: &[SensorSpec] = &[
+    SensorSpec {
+        field: "grid_rating_voltage",
+        name: "Grid Rating Voltage",
+        unit: Some("V"),
+        device_class: Some("voltage"),
+        icon: "power-plug",
+    },
+    SensorSpec {
+        field: "grid_rating_current",
+        name: "Grid Rating Current",
+        unit: Some("A"),
+        device_class: Some("current"),
+        icon: "current-dc",
+    },
+];
+
+// One parallel unit's QPGS response, registered under its own device per index
+const QPGS_SENSORS: &[SensorSpec] = &[
+    SensorSpec {
+        field: "other_units_connected",
+        name: "Other Units Connected",
+        unit: None,
+        device_class: None,
+        icon: "power-plug",
+    },
+    SensorSpec {
+        field: "serial_number",
+        name: "Serial Number",
+        unit: None,
+        device_class: None,
+        icon: "details",
+    },
+    SensorSpec {
+        field: "operation_mode",
+        name: "Operation Mode",
+        unit: None,
+        device_class: None,
+        icon: "slot-machine",
+    },
+    SensorSpec {
+        field: "fault_code",
+        name: "Fault Code",
+        unit: None,
+        device_class: None,
+        icon: "alert",
+    },
+    SensorSpec {
+        field: "ac_input_voltage",
+        name: "AC Input Voltage",
+        unit: Some("Vac"),
+        device_class: Some("voltage"),
+        icon: "power-plug",
+    },
+    SensorSpec {
+        field: "ac_input_frequency",
+        name: "AC Input Frequency",
+        unit: Some("Hz"),
+        device_class: Some("frequency"),
+        icon: "current-ac",
+    },
+    SensorSpec {
+        field: "ac_output_voltage",
+        name: "AC Output Voltage",
+        unit: Some("Vac"),
+        device_class: Some("voltage"),
+        icon: "power-plug",
+    },
+    SensorSpec {
+        field: "ac_output_frequency",
+        name: "AC Output Frequency",
+        unit: Some("Hz"),
+        device_class: Some("frequency"),
+        icon: "current-ac",
+    },
+    SensorSpec {
+        field: "ac_output_apparent_power",
+        name: "AC Output Apparent Power",
+        unit: Some("VA"),
+        device_class: Some("apparent_power"),
+        icon: "power-plug",
+    },
+    SensorSpec {
+        field: "ac_output_active_power",
+        name: "AC Output Active Power",
+        unit: Some("W"),
+        device_class: Some("power"),
+        icon: "power-plug",
+    },
+    SensorSpec {
+        field: "percentage_of_nominal_output_power",
+        name: "Percentage Of Nominal Output Power",
+        unit: Some("% of single inverter"),
+        device_class: None,
+        icon: "power-plug",
+    },
+    SensorSpec {
+        field: "battery_voltage",
+        name: "Battery Voltage",
+        unit: Some("Vdc"),
+        device_class: Some("voltage"),
+        icon: "battery",
+    },
+    SensorSpec {
+        field: "battery_charging_current",
+        name: "Battery Charging Current",
+        unit: Some("Adc"),
+        device_class: Some("current"),
+        icon: "battery-positive",
+    },
+    SensorSpec {
+        field: "battery_approx_state_of_charge",
+        name: "Battery State of Charge",
+        unit: Some("%"),
+        device_class: Some("battery"),
+        icon: "battery-outline",
+    },
+    SensorSpec {
+        field: "pv_input_voltage",
+        name: "PV Input Voltage",
+        unit: Some("Vdc"),
+        device_class: Some("voltage"),
+        icon: "solar-power",
+    },
+    SensorSpec {
+        field: "total_charging_current",
+        name: "Total Charging Current",
+        unit: Some("Adc"),
+        device_class: Some("current"),
+        icon: "battery-positive",
+    },
+    SensorSpec {
+        field: "total_ac_output_apparent_power",
+        name: "Total AC Output Apparent Power",
+        unit: Some("VA"),
+        device_class: Some("apparent_power"),
+        icon: "power-plug",
+    },
+    SensorSpec {
+        field: "total_ac_output_active_power",
+        name: "Total AC Output Active Power",
+        unit: Some("W"),
+        device_class: Some("power"),
+        icon: "power-plug",
+    },
+    SensorSpec {
+        field: "total_percentage_of_nominal_output_power",
+        name: "Total Percentage Of Output Power",
+        unit: Some("% of inverters"),
+        device_class: None,
+        icon: "power-plug",
+    },
+    SensorSpec {
+        field: "inverter_status.mppt_active",
+        name: "MPPT Active",
+        unit: None,
+        device_class: None,
+        icon: "order-bool-ascending-variant",
+    },
+    SensorSpec {
+        field: "inverter_status.ac_charging",
+        name: "AC Charging",
+        unit: None,
+        device_class: None,
+        icon: "order-bool-ascending-variant",
+    },
+    SensorSpec {
+        field: "inverter_status.solar_charging",
+        name: "Solar Charging",
+        unit: None,
+        device_class: None,
+        icon: "order-bool-ascending-variant",
+    },
+    SensorSpec {
+        field: "inverter_status.battery_status",
+        name: "Battery Status",
+        unit: None,
+        device_class: None,
+        icon: "battery-heart-variant",
+    },
+    SensorSpec {
+        field: "inverter_status.ac_input",
+        name: "AC Input",
+        unit: None,
+        device_class: None,
+        icon: "order-bool-ascending-variant",
+    },
+    SensorSpec {
+        field: "inverter_status.ac_output",
+        name: "AC Output",
+        unit: None,
+        device_class: None,
+        icon: "order-bool-ascending-variant",
+    },
+    SensorSpec {
+        field: "inverter_status.reserved_bit",
+        name: "Reserved",
+        unit: None,
+        device_class: None,
+        icon: "order-bool-ascending-variant",
+    },
+    SensorSpec {
+        field: "ac_output_mode",
+        name: "AC Output Mode",
+        unit: None,
+        device_class: None,
+        icon: "slot-machine",
+    },
+    SensorSpec {
+        field: "battery_charging_source_priority",
+        name: "Battery Charging Source",
+        unit: None,
+        device_class: None,
+        icon: "ev-station",
+    },
+    SensorSpec {
+        field: "max_charging_current_set",
+        name: "Max Charging Current Set",
+        unit: Some("Adc"),
+        device_class: Some("current"),
+        icon: "current-dc",
+    },
+    SensorSpec {
+        field: "max_charging_current_possible",
+        name: "Max Charging Current Possible",
+        unit: Some("Adc"),
+        device_class: Some("current"),
+        icon: "current-dc",
+    },
+    SensorSpec {
+        field: "max_ac_charging_current_set",
+        name: "Max AC Charging Current Set",
+        unit: Some("Adc"),
+        device_class: Some("current"),
+        icon: "current-dc",
+    },
+    SensorSpec {
+        field: "pv_input_current",
+        name: "PV Input Current",
+        unit: Some("Adc"),
+        device_class: Some("current"),
+        icon: "current-dc",
+    },
+    SensorSpec {
+        field: "battery_discharge_current",
+        name: "Battery Discharge Current",
+        unit: Some("Adc"),
+        device_class: Some("current"),
+        icon: "current-dc",
+    },
+    // manually calculated - not reported from qpgs directly
+    SensorSpec {
+        field: "pv_input_power",
+        name: "PV Input Power",
+        unit: Some("W"),
+        device_class: Some("power"),
+        icon: "solar-panel",
+    },
+    SensorSpec {
+        field: "battery_charging_power",
+        name: "Battery Charging Power",
+        unit: Some("W"),
+        device_class: Some("power"),
+        icon: "battery-positive",
+    },
+    SensorSpec {
+        field: "battery_discharging_power",
+        name: "Battery Discharging Power",
+        unit: Some("W"),
+        device_class: Some("power"),
+        icon: "battery-negative",
+    },
+];
+
+// The fields are the topic names from commands::QPIGS_AC_FIELDS
+const QPIGS_AC_SENSORS: &[SensorSpec] = &[
+    SensorSpec {
+        field: "ac_in_voltage",
+        name: "AC input voltage",
+        unit: Some("V"),
+        device_class: Some("voltage"),
+        icon: "transmission-tower-import",
+    },
+    SensorSpec {
+        field: "ac_in_freq",
+        name: "AC input frequency",
+        unit: Some("Hz"),
+        device_class: Some("frequency"),
+        icon: "current-ac",
+    },
+    SensorSpec {
+        field: "ac_out_voltage",
+        name: "AC output voltage",
+        unit: Some("V"),
+        device_class: Some("voltage"),
+        icon: "power-socket",
+    },
+    SensorSpec {
+        field: "ac_out_freq",
+        name: "AC output frequency",
+        unit: Some("Hz"),
+        device_class: Some("frequency"),
+        icon: "current-ac",
+    },
+];
+
+const QPIGS_SENSORS: &[SensorSpec] = &[
+    SensorSpec {
+        field: "grid_voltage",
+        name: "Grid Voltage",
+        unit: Some("V"),
+        device_class: Some("voltage"),
+        icon: "power-plug",
+    },
+    SensorSpec {
+        field: "grid_frequency",
+        name: "Grid Frequency",
+        unit: Some("Hz"),
+        device_class: Some("frequency"),
+        icon: "current-ac",
+    },
+    SensorSpec {
+        field: "ac_out_voltage",
+        name: "Out Voltage",
+        unit: Some("V"),
+        device_class: Some("voltage"),
+        icon: "power-plug",
+    },
+    SensorSpec {
+        field: "ac_out_frequency",
+        name: "Out Frequency",
+        unit: Some("Hz"),
+        device_class: Some("frequency"),
+        icon: "current-ac",
+    },
+    SensorSpec {
+        field: "ac_out_apparent_power",
+        name: "Out apparent power",
+        unit: Some("VA"),
+        device_class: Some("apparent_power"),
+        icon: "power-plug",
+    },
+    SensorSpec {
+        field: "ac_out_active_power",
+        name: "Out active power",
+        unit: Some("W"),
+        device_class: Some("power"),
+        icon: "power-plug",
+    },
+    SensorSpec {
+        field: "out_load_percent",
+        name: "Out load percent",
+        unit: Some("%"),
+        device_class: None,
+        icon: "brightness-percent",
+    },
+    SensorSpec {
+        field: "bus_voltage",
+        name: "Bus Voltage",
+        unit: Some("V"),
+        device_class: Some("voltage"),
+        icon: "details",
+    },
+    SensorSpec {
+        field: "battery_voltage",
+        name: "Battery Voltage",
+        unit: Some("V"),
+        device_class: Some("voltage"),
+        icon: "battery-outline",
+    },
+    SensorSpec {
+        field: "battery_charge_current",
+        name: "Battery charge current",
+        unit: Some("A"),
+        device_class: Some("current"),
+        icon: "current-dc",
+    },
+    SensorSpec {
+        field: "battery_capacity",
+        name: "Battery capacity",
+        unit: Some("%"),
+        device_class: Some("battery"),
+        icon: "battery-outline",
+    },
+    SensorSpec {
+        field: "inverter_heat_sink_temp",
+        name: "Heat sink temperature",
+        unit: Some("°C"),
+        device_class: Some("temperature"),
+        icon: "details",
+    },
+    SensorSpec {
+        field: "pv_input_current",
+        name: "PV Input Current",
+        unit: Some("A"),
+        device_class: Some("current"),
+        icon: "solar-power",
+    },
+    SensorSpec {
+        field: "pv_input_voltage",
+        name: "PV Input Voltage",
+        unit: Some("V"),
+        device_class: Some("voltage"),
+        icon: "solar-power",
+    },
+    SensorSpec {
+        field: "battery_scc_voltage",
+        name: "Battery SCC Voltage",
+        unit: Some("V"),
+        device_class: Some("voltage"),
+        icon: "current-dc",
+    },
+    SensorSpec {
+        field: "battery_discharge_current",
+        name: "Battery discharge current",
+        unit: Some("A"),
+        device_class: Some("current"),
+        icon: "battery-negative",
+    },
+    SensorSpec {
+        field: "device_status.charge_status",
+        name: "Device charge status",
+        unit: None,
+        device_class: None,
+        icon: "power-plug",
+    },
+    SensorSpec {
+        field: "device_status.active_load",
+        name: "Active load",
+        unit: None,
+        device_class: None,
+        icon: "power",
+    },
+];
+
+const QPIGS2_SENSORS: &[SensorSpec] = &[
+    SensorSpec {
+        field: "pv2_input_current",
+        name: "PV2 Input Current",
+        unit: Some("A"),
+        device_class: Some("current"),
+        icon: "solar-power",
+    },
+    SensorSpec {
+        field: "pv2_input_voltage",
+        name: "PV2 Input Voltage",
+        unit: Some("V"),
+        device_class: Some("voltage"),
+        icon: "solar-power",
+    },
+    SensorSpec {
+        field: "pv2_charging_power",
+        name: "PV2 Charging Power",
+        unit: Some("W"),
+        device_class: Some("power"),
+        icon: "solar-panel",
+    },
+];
+
 #[derive(Serialize, Debug)]
 struct SensorDiscoveryParams {
     unique_id: String,
@@ -188,6 +849,9 @@ struct SensorDiscoveryParams {
     #[serde(skip_serializing_if = "Option::is_none")]
     value_template: Option<String>,
     state_topic: String,
+    availability_topic: String,
+    payload_available: String,
+    payload_not_available: String,
     icon: String,
     device: SensorDiscoveryDevice,
     force_update: bool,
@@ -197,13 +861,16 @@ struct SensorDiscoveryParams {
     device_class: Option<String>,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Clone)]
 struct SensorDiscoveryDevice {
     name: String,
     identifiers: [String; 1],
     model: String,
     manufacturer: String,
     sw_version: String,
+    // Links a parallel unit's device to the MPQTT device it's reached through
+    #[serde(skip_serializing_if = "Option::is_none")]
+    via_device: Option<String>,
 }
 
 fn get_device_hassio(cfg: &MqttSettings) -> SensorDiscoveryDevice {
@@ -213,17 +880,31 @@ fn get_device_hassio(cfg: &MqttSettings) -> SensorDiscoveryDevice {
         model: env!("CARGO_PKG_NAME").to_ascii_uppercase().parse().unwrap(),
         manufacturer: env!("CARGO_PKG_NAME").to_ascii_uppercase().parse().unwrap(),
         sw_version: env!("CARGO_PKG_VERSION").parse().unwrap(),
+        via_device: None,
     }
 }
 
-async fn register_error_sensor(client: &Client, cfg: &MqttSettings) -> Result<(), Box<dyn std::error::Error>> {
+// A parallel unit's device, named from discovery.unit_name_template
+fn get_unit_device_hassio(cfg: &MqttSettings, index: u8) -> SensorDiscoveryDevice {
+    SensorDiscoveryDevice {
+        name: cfg.discovery.unit_name(index),
+        identifiers: [format!("{}_unit{}", cfg.discovery.device_id, index)],
+        via_device: Some(cfg.discovery.device_id.clone()),
+        ..get_device_hassio(cfg)
+    }
+}
+
+async fn register_error_sensor(client: &Publisher, cfg: &MqttSettings) -> Result<(), MpqttError> {
     debug!("Registering error sensor");
     let params = SensorDiscoveryParams {
         unique_id: format!("{}_last_error", cfg.discovery.node_name).parse().unwrap(),
         name: format!("{} - Last error", cfg.discovery.device_name).to_string(),
         unit_of_measurement: None,
         value_template: None,
-        state_topic: format!("{}/{}", cfg.topic, "error").to_string(),
+        state_topic: cfg.state_topic("error"),
+        availability_topic: cfg.availability_topic(),
+        payload_available: cfg.availability.payload_online.clone(),
+        payload_not_available: cfg.availability.payload_offline.clone(),
         icon: "mdi:hammer-wrench".parse().unwrap(),
         device: get_device_hassio(&cfg),
         force_update: false,
@@ -231,22 +912,13 @@ async fn register_error_sensor(client: &Client, cfg: &MqttSettings) -> Result<()
         device_class: None,
     };
     let params_string = serde_json::to_string(&params)?;
-    let mut msg = PublishOpts::new(format!("{}/sensor/{}/{}/config", cfg.discovery.prefix, cfg.discovery.node_name, "error").to_string(), params_string.as_bytes().to_vec());
-    msg.set_qos(QoS::AtLeastOnce);
-    msg.set_retain(true);
-    client.publish(&msg).await?;
+    client
+        .publish_discovery(format!("{}/sensor/{}/{}/config", cfg.discovery.prefix, cfg.discovery.node_name, "error").to_string(), params_string.into_bytes(), cfg.discovery.retain)
+        .await?;
     Ok(())
 }
 
-async fn register_sensor(client: &Client, cfg: &MqttSettings, command: &str, id: &str, name: &str, unit: Option<String>, icon: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let unique_id = format!("{}_{}_{}", cfg.discovery.node_name, command, id).to_string().replace(".", "_");
-    let topic = format!("{}/{}", cfg.topic, command).to_string();
-
-    debug!("Registering sensor {}", unique_id);
-    let state_class = match unit {
-        Some(_) => Some(String::from("measurement")),
-        None => None,
-    };
+async fn register_sensor(client: &Publisher, cfg: &MqttSettings, command: &str, id: &str, name: &str, unit: Option<String>, icon: &str) -> Result<(), MpqttError> {
     // device_class and state_class enable long term statistics in home assistant
     let device_class = match unit {
         Some(ref unit) => match unit.as_str() {
@@ -262,23 +934,101 @@ async fn register_sensor(client: &Client, cfg: &MqttSettings, command: &str, id:
         },
         None => None,
     };
+    publish_sensor(client, cfg, command, Some(id), name, unit, icon, device_class).await
+}
+
+// Publishes the discovery config for one field of a command's JSON, or for a topic carrying a bare value when there's no field.
+// Anything with a unit is a measurement so it gets long term statistics.
+#[allow(clippy::too_many_arguments)]
+async fn publish_sensor(client: &Publisher, cfg: &MqttSettings, command: &str, field: Option<&str>, name: &str, unit: Option<String>, icon: &str, device_class: Option<String>) -> Result<(), MpqttError> {
+    publish_sensor_for(client, cfg, &get_device_hassio(cfg), command, field, name, unit, icon, device_class, None).await
+}
+
+// publish_sensor for an entity that belongs to a device other than MPQTT's own, or one that needs a state_class other than
+// the measurement worked out from its unit, e.g. total_increasing for counters
+#[allow(clippy::too_many_arguments)]
+async fn publish_sensor_for(client: &Publisher, cfg: &MqttSettings, device: &SensorDiscoveryDevice, command: &str, field: Option<&str>, name: &str, unit: Option<String>, icon: &str, device_class: Option<String>, state_class: Option<&str>) -> Result<(), MpqttError> {
+    // Nested state topics like stats/inner can't keep their slash in an id
+    let object_id = match field {
+        Some(id) => format!("{}_{}", command, id),
+        None => command.to_string(),
+    }
+    .replace(".", "_")
+    .replace("/", "_");
+    let unique_id = format!("{}_{}", cfg.discovery.node_name, object_id);
+    let topic = cfg.state_topic(command);
+
+    debug!("Registering sensor {}", unique_id);
+    let state_class = match (state_class, &unit) {
+        (Some(state_class), _) => Some(state_class.to_string()),
+        (None, Some(_)) => Some(String::from("measurement")),
+        (None, None) => None,
+    };
 
     let params = SensorDiscoveryParams {
         unique_id,
         name: format!("{} - {}", cfg.discovery.device_name, name).to_string(),
         unit_of_measurement: unit,
-        value_template: Some(format!("{{{{ value_json.{} }}}}", id).to_string()),
+        value_template: field.map(|id| format!("{{{{ {} }}}}", value_field(cfg, id))),
         state_topic: topic,
+        availability_topic: cfg.availability_topic(),
+        payload_available: cfg.availability.payload_online.clone(),
+        payload_not_available: cfg.availability.payload_offline.clone(),
         icon: format!("mdi:{}", icon).to_string(),
-        device: get_device_hassio(&cfg),
+        device: device.clone(),
         force_update: false,
         state_class,
         device_class,
     };
     let params_string = serde_json::to_string(&params)?;
-    let mut msg = PublishOpts::new(format!("{}/sensor/{}/{}_{}/config", cfg.discovery.prefix, cfg.discovery.node_name, command, id.replace(".", "_")).to_string(), params_string.as_bytes().to_vec());
-    msg.set_qos(QoS::AtLeastOnce);
-    msg.set_retain(true);
-    client.publish(&msg).await?;
+    client.publish_discovery(format!("{}/sensor/{}/{}/config", cfg.discovery.prefix, cfg.discovery.node_name, object_id), params_string.into_bytes(), cfg.discovery.retain).await?;
+    Ok(())
+}
+
+// Where a field sits in the published JSON, under data when the payloads are timestamped
+fn value_field(cfg: &MqttSettings, id: &str) -> String {
+    if cfg.timestamp_payloads {
+        format!("value_json.data.{}", id)
+    } else {
+        format!("value_json.{}", id)
+    }
+}
+
+async fn register_binary_sensor(client: &Publisher, cfg: &MqttSettings, command: &str, id: &str, name: &str, icon: &str) -> Result<(), MpqttError> {
+    // The published JSON holds real booleans, home assistant expects ON/OFF
+    let value_template = format!("{{{{ 'ON' if {} else 'OFF' }}}}", value_field(cfg, id));
+    publish_binary_sensor(client, cfg, &format!("{}/{}", command, id), Some((command, value_template)), name, icon, None).await
+}
+
+// state is the topic suffix the sensor's config is keyed by. With a template the state is read from the named
+// command's JSON, otherwise the topic itself carries ON/OFF.
+async fn publish_binary_sensor(client: &Publisher, cfg: &MqttSettings, state: &str, template: Option<(&str, String)>, name: &str, icon: &str, device_class: Option<&str>) -> Result<(), MpqttError> {
+    let object_id = state.replace("/", "_");
+    let unique_id = format!("{}_{}", cfg.discovery.node_name, object_id);
+    let (state_topic, value_template) = match template {
+        Some((command, value_template)) => (cfg.state_topic(command), Some(value_template)),
+        None => (cfg.state_topic(state), None),
+    };
+
+    debug!("Registering binary sensor {}", unique_id);
+    let params = SensorDiscoveryParams {
+        unique_id,
+        name: format!("{} - {}", cfg.discovery.device_name, name),
+        unit_of_measurement: None,
+        value_template,
+        state_topic,
+        availability_topic: cfg.availability_topic(),
+        payload_available: cfg.availability.payload_online.clone(),
+        payload_not_available: cfg.availability.payload_offline.clone(),
+        icon: format!("mdi:{}", icon),
+        device: get_device_hassio(&cfg),
+        force_update: false,
+        state_class: None,
+        device_class: device_class.map(String::from),
+    };
+    let params_string = serde_json::to_string(&params)?;
+    client
+        .publish_discovery(format!("{}/binary_sensor/{}/{}/config", cfg.discovery.prefix, cfg.discovery.node_name, object_id), params_string.into_bytes(), cfg.discovery.retain)
+        .await?;
     Ok(())
 }
@@ -0,0 +1,82 @@
+use crate::error::MpqttError;
+use crate::publish_update;
+use crate::publisher::Publisher;
+use crate::settings::MqttSettings;
+
+use log::{debug, error, info};
+use mqtt_async_client::client::{Client, QoS, Subscribe, SubscribeTopic};
+use serde_derive::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+
+// Requests closer together than this are dropped, on top of a burst only ever queueing one more update
+const DEBOUNCE: Duration = Duration::from_secs(5);
+
+/// Updates requested on `{topic}/poll`, which cut the wait after the current update short. Without a listener
+/// nothing is ever requested.
+pub struct OnDemand {
+    notify: Arc<Notify>,
+    // A request woke the loop and its update hasn't been acknowledged yet
+    pending: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct PollResult {
+    success: bool,
+    // Unix seconds the requested update finished
+    timestamp: u64,
+}
+
+impl Default for OnDemand {
+    fn default() -> Self {
+        OnDemand { notify: Arc::new(Notify::new()), pending: false }
+    }
+}
+
+impl OnDemand {
+    /// Subscribes to {topic}/poll on its own client, any message there requests an update
+    pub async fn listen(mut client: Client, mqtt: &MqttSettings) -> Result<Self, MpqttError> {
+        client.connect().await?;
+        let topic = mqtt.state_topic("poll");
+        let subscription = Subscribe::new(vec![SubscribeTopic { qos: QoS::AtLeastOnce, topic_path: topic.clone() }]);
+        client.subscribe(subscription).await?.any_failures()?;
+        info!("Listening for poll requests on {}", topic);
+
+        let notify = Arc::new(Notify::new());
+        let requests = notify.clone();
+        tokio::spawn(async move {
+            let mut last: Option<Instant> = None;
+            loop {
+                match client.read_subscriptions().await {
+                    Ok(_) if last.map_or(false, |last| last.elapsed() < DEBOUNCE) => debug!("Ignoring a poll request within {}s of the last", DEBOUNCE.as_secs()),
+                    Ok(_) => {
+                        last = Some(Instant::now());
+                        requests.notify();
+                    }
+                    Err(err) => error!("Error reading poll requests: {}", err),
+                }
+            }
+        });
+        Ok(OnDemand { notify, pending: false })
+    }
+
+    /// Waits for a request, marking it as pending until `acknowledge`
+    pub async fn requested(&mut self) {
+        self.notify.notified().await;
+        self.pending = true;
+    }
+
+    /// Publishes {topic}/poll/result once the update a request woke has run
+    pub async fn acknowledge(&mut self, mqtt_client: &Publisher, mqtt: &MqttSettings, success: bool) -> Result<(), MpqttError> {
+        if !self.pending {
+            return Ok(());
+        }
+        self.pending = false;
+        let result = PollResult {
+            success,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or_default(),
+        };
+        publish_update(mqtt_client, mqtt, "poll/result", serde_json::to_string(&result)?).await
+    }
+}
@@ -0,0 +1,184 @@
+use crc_any::CRCu16;
+use std::fmt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// Raw PI30 framing for the commands masterpower_api doesn't cover.
+// Requests are `<command><crc>\r`, responses are `(<data><crc>\r`.
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    Io(std::io::Error),
+    Crc { expected: u16, actual: u16 },
+    Nak,
+    Malformed(String),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Io(err) => write!(f, "IO error: {}", err),
+            ProtocolError::Crc { expected, actual } => write!(f, "invalid response crc (expected {:04X} got {:04X})", expected, actual),
+            ProtocolError::Nak => write!(f, "inverter responded with NAK"),
+            ProtocolError::Malformed(frame) => write!(f, "malformed response: {}", frame),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<std::io::Error> for ProtocolError {
+    fn from(err: std::io::Error) -> Self {
+        ProtocolError::Io(err)
+    }
+}
+
+/// CRC16/XMODEM with the protocol's adjustment for bytes that would clash with frame delimiters
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = CRCu16::crc16xmodem();
+    crc.digest(data);
+    let [mut high, mut low] = crc.get_crc().to_be_bytes();
+    if high == 0x28 || high == 0x0d || high == 0x0a {
+        high += 1;
+    }
+    if low == 0x28 || low == 0x0d || low == 0x0a {
+        low += 1;
+    }
+    u16::from_be_bytes([high, low])
+}
+
+pub fn encode_request(command: &str) -> Vec<u8> {
+    let mut frame = command.as_bytes().to_vec();
+    frame.extend_from_slice(&crc16(command.as_bytes()).to_be_bytes());
+    frame.push(b'\r');
+    frame
+}
+
+/// Validates a full response frame (including the trailing `\r`) and returns its data section
+pub fn decode_response(frame: &[u8]) -> Result<String, ProtocolError> {
+    if frame.len() < 4 || frame[0] != b'(' || frame[frame.len() - 1] != b'\r' {
+        return Err(ProtocolError::Malformed(String::from_utf8_lossy(frame).to_string()));
+    }
+    let crc_start = frame.len() - 3;
+    let expected = crc16(&frame[..crc_start]);
+    let actual = u16::from_be_bytes([frame[crc_start], frame[crc_start + 1]]);
+    if expected != actual {
+        return Err(ProtocolError::Crc { expected, actual });
+    }
+    let data = String::from_utf8_lossy(&frame[1..crc_start]).to_string();
+    if data.starts_with("NAK") {
+        return Err(ProtocolError::Nak);
+    }
+    Ok(data)
+}
+
+/// The crc a response frame should have and the one it carries, for frames whose crc doesn't match
+pub fn crc_mismatch(frame: &[u8]) -> Option<(u16, u16)> {
+    let end = frame.iter().position(|byte| *byte == b'\r')?;
+    if end < 3 || frame[0] != b'(' {
+        return None;
+    }
+    let crc_start = end - 2;
+    let expected = crc16(&frame[..crc_start]);
+    let actual = u16::from_be_bytes([frame[crc_start], frame[crc_start + 1]]);
+    if expected == actual {
+        None
+    } else {
+        Some((expected, actual))
+    }
+}
+
+/// Sends a command and waits for its response frame
+pub async fn query<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, command: &str) -> Result<String, ProtocolError> {
+    // hidraw devices expect the request in 8 byte reports, serial devices don't mind
+    for chunk in encode_request(command).chunks(8) {
+        stream.write_all(chunk).await?;
+    }
+    stream.flush().await?;
+
+    let mut frame = Vec::new();
+    let mut buf = [0u8; 64];
+    loop {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            return Err(ProtocolError::Io(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "serial stream closed")));
+        }
+        frame.extend_from_slice(&buf[..read]);
+        // hidraw reports are padded with zeros after the terminator
+        if let Some(end) = frame.iter().position(|byte| *byte == b'\r') {
+            frame.truncate(end + 1);
+            // Resync on the frame start, anything before it is left over from an earlier garbled response
+            if let Some(start) = frame.iter().position(|byte| *byte == b'(').filter(|start| *start > 0) {
+                frame.drain(..start);
+            }
+            break;
+        }
+    }
+    decode_response(&frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A response frame for data with its crc worked out
+    fn frame(data: &str) -> Vec<u8> {
+        let mut frame = format!("({}", data).into_bytes();
+        frame.extend_from_slice(&crc16(&frame).to_be_bytes());
+        frame.push(b'\r');
+        frame
+    }
+
+    #[test]
+    fn crc_is_xmodem() {
+        // The standard CRC16/XMODEM check value
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+        assert_eq!(encode_request("QPIGS"), b"QPIGS\xB7\xA9\r");
+    }
+
+    #[test]
+    fn crc_bytes_that_look_like_delimiters_are_bumped() {
+        // XMODEM gives 0x0A88 for QBOOT, whose high byte would read as a line feed
+        assert_eq!(crc16(b"QBOOT"), 0x0B88);
+        // and 0x2835 for "(9", whose high byte would read as a frame start
+        assert_eq!(crc16(b"(9"), 0x2935);
+    }
+
+    #[test]
+    fn decodes_a_valid_response() {
+        assert_eq!(decode_response(&frame("230.0 49.9")).unwrap(), "230.0 49.9");
+        assert_eq!(crc_mismatch(&frame("230.0 49.9")), None);
+    }
+
+    #[test]
+    fn rejects_a_bad_crc() {
+        let mut corrupt = frame("230.0 49.9");
+        // Line noise flipping a digit after the crc was worked out
+        corrupt[1] = b'3';
+        match decode_response(&corrupt) {
+            Err(ProtocolError::Crc { expected, actual }) => {
+                assert_eq!(expected, crc16(b"(330.0 49.9"));
+                assert_eq!(actual, crc16(b"(230.0 49.9"));
+                assert_eq!(crc_mismatch(&corrupt), Some((expected, actual)));
+            }
+            result => panic!("expected a crc error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn rejects_short_or_unframed_responses() {
+        for frame in [&b""[..], b"\r", b"(\r", b"(A\r", b"230.0 49.9\xB7\xA9\r", b"(230.0 49.9"].iter() {
+            match decode_response(frame) {
+                Err(ProtocolError::Malformed(_)) => (),
+                result => panic!("{:?} should be malformed, got {:?}", String::from_utf8_lossy(frame), result),
+            }
+        }
+    }
+
+    #[test]
+    fn reports_a_nak() {
+        match decode_response(&frame("NAK")) {
+            Err(ProtocolError::Nak) => (),
+            result => panic!("expected a NAK, got {:?}", result),
+        }
+    }
+}
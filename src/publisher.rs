@@ -0,0 +1,295 @@
+use futures::future::LocalBoxFuture;
+use log::{debug, error, info, warn};
+use mqtt_async_client::client::{Client, Publish as PublishOpts, QoS};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio::time::{delay_for, Duration};
+
+#[derive(Default)]
+struct State {
+    // topic -> (payload, qos, retain) of the last publish
+    last: HashMap<String, (Vec<u8>, QoS, bool)>,
+    failing: bool,
+    // Waiting on the client to reconnect after publishes kept failing
+    reconnecting: bool,
+    // Home Assistant config topics published, so they can be cleared on exit
+    discovery: Vec<String>,
+    // Whether {topic}/error holds an error that hasn't been cleared
+    error_active: bool,
+    // command -> payload collected for {topic}/bundle since the last flush
+    bundle: Map<String, Value>,
+    // Where publishes are queued while an update is pipelined
+    outbox: Option<mpsc::UnboundedSender<Outgoing>>,
+}
+
+/// A publish queued to go out alongside the update that made it
+pub struct Outgoing {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: QoS,
+    pub retain: bool,
+}
+
+/// The publishing MQTT client. With `republish_on_reconnect` it remembers the last payload per topic and sends
+/// them all again on the first successful publish after a failure, since the client reconnects without telling us.
+pub struct Publisher {
+    client: Client,
+    republish_on_reconnect: bool,
+    state: Mutex<State>,
+}
+
+impl Publisher {
+    pub fn new(client: Client, republish_on_reconnect: bool) -> Self {
+        Publisher {
+            client,
+            republish_on_reconnect,
+            // A retained error from the last run may still be on the broker
+            state: Mutex::new(State { error_active: true, ..State::default() }),
+        }
+    }
+
+    pub fn error_active(&self) -> bool {
+        self.state.lock().unwrap().error_active
+    }
+
+    pub fn set_error_active(&self, active: bool) {
+        self.state.lock().unwrap().error_active = active;
+    }
+
+    /// Holds a payload for the next bundle instead of publishing it, JSON payloads are nested as they are
+    pub fn bundle(&self, command: &str, payload: &str) {
+        let value = serde_json::from_str(payload).unwrap_or_else(|_| Value::String(payload.to_string()));
+        self.state.lock().unwrap().bundle.insert(command.to_string(), value);
+    }
+
+    /// Everything bundled since the last call, None when nothing was
+    pub fn take_bundle(&self) -> Option<String> {
+        let bundle = std::mem::take(&mut self.state.lock().unwrap().bundle);
+        if bundle.is_empty() {
+            None
+        } else {
+            Some(Value::Object(bundle).to_string())
+        }
+    }
+
+    /// Queues publishes from here on until `end_pipeline`, for the caller to send from the returned receiver
+    pub fn start_pipeline(&self) -> mpsc::UnboundedReceiver<Outgoing> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.state.lock().unwrap().outbox = Some(sender);
+        receiver
+    }
+
+    /// Stops queueing, the receiver ends once it has everything queued so far
+    pub fn end_pipeline(&self) {
+        self.state.lock().unwrap().outbox = None;
+    }
+
+    /// Queues a publish if an update is pipelined, otherwise (or if the receiver has gone) hands it back to be sent
+    pub fn enqueue(&self, outgoing: Outgoing) -> Option<Outgoing> {
+        match self.state.lock().unwrap().outbox {
+            Some(ref outbox) => outbox.send(outgoing).err().map(|unsent| unsent.0),
+            None => Some(outgoing),
+        }
+    }
+
+    pub async fn disconnect(&mut self) -> Result<(), mqtt_async_client::Error> {
+        self.client.disconnect().await
+    }
+
+    pub async fn publish(&self, topic: &str, payload: &[u8], qos: QoS, retain: bool) -> Result<(), mqtt_async_client::Error> {
+        if self.republish_on_reconnect {
+            self.state.lock().unwrap().last.insert(topic.to_string(), (payload.to_vec(), qos, retain));
+        }
+
+        let result = self.client.publish(&message(topic, payload, qos, retain)).await;
+        let recovered = {
+            let mut state = self.state.lock().unwrap();
+            let recovered = result.is_ok() && state.failing;
+            state.failing = result.is_err();
+            if result.is_ok() && state.reconnecting {
+                state.reconnecting = false;
+                info!("Reconnected to the MQTT broker - publishing again");
+            }
+            recovered
+        };
+        if recovered && self.republish_on_reconnect {
+            self.republish(topic).await;
+        }
+        result
+    }
+
+    /// Keeps retrying a publish every `retry_delay` while the client reconnects (it does so automatically on the next
+    /// operation), returning the last error if it hasn't gone through within `give_up`
+    pub async fn wait_for_reconnect(&self, topic: &str, payload: &[u8], qos: QoS, retain: bool, retry_delay: Duration, give_up: Duration) -> Result<(), mqtt_async_client::Error> {
+        {
+            let mut state = self.state.lock().unwrap();
+            if !state.reconnecting {
+                state.reconnecting = true;
+                warn!("Publishing to the MQTT broker keeps failing - reconnecting for up to {}s", give_up.as_secs());
+            }
+        }
+        let started = Instant::now();
+        loop {
+            delay_for(retry_delay).await;
+            match self.publish(topic, payload, qos, retain).await {
+                Ok(()) => return Ok(()),
+                Err(err) if started.elapsed() >= give_up => {
+                    error!("Could not reconnect to the MQTT broker within {}s: {}", give_up.as_secs(), err);
+                    return Err(err);
+                }
+                Err(err) => debug!("Still reconnecting to the MQTT broker: {}", err),
+            }
+        }
+    }
+
+    /// Publishes a Home Assistant discovery config. These aren't cached for republishing, Home Assistant keeps
+    /// its own copy once it has seen them.
+    pub async fn publish_discovery(&self, topic: String, payload: Vec<u8>, retain: bool) -> Result<(), mqtt_async_client::Error> {
+        self.client.publish(&message(&topic, &payload, QoS::AtLeastOnce, retain)).await?;
+        let mut state = self.state.lock().unwrap();
+        if !state.discovery.contains(&topic) {
+            state.discovery.push(topic);
+        }
+        Ok(())
+    }
+
+    /// Publishes an empty retained payload to every discovery topic, which removes the entities from Home Assistant
+    pub async fn clear_discovery(&self) {
+        let topics: Vec<String> = self.state.lock().unwrap().discovery.drain(..).collect();
+        info!("Clearing {} discovery topics", topics.len());
+        for topic in topics {
+            if let Err(err) = self.client.publish(&message(&topic, &[], QoS::AtLeastOnce, true)).await {
+                warn!("Error clearing discovery topic {}: {}", topic, err);
+            }
+        }
+    }
+
+    // Sends every cached topic again, other than the one that just went through
+    async fn republish(&self, skip: &str) {
+        let cached: Vec<(String, _)> = self.state.lock().unwrap().last.iter().filter(|(topic, _)| topic.as_str() != skip).map(|(topic, last)| (topic.clone(), last.clone())).collect();
+        info!("Publishing recovered - republishing {} cached topics", cached.len());
+        for (topic, (payload, qos, retain)) in cached {
+            if let Err(err) = self.client.publish(&message(&topic, &payload, qos, retain)).await {
+                warn!("Error republishing {}: {}", topic, err);
+                self.state.lock().unwrap().failing = true;
+                return;
+            }
+        }
+    }
+}
+
+/// Something `retry` can publish through - the Publisher, or a fake in tests
+pub trait PublishClient {
+    fn publish<'a>(&'a self, topic: &'a str, payload: &'a [u8], qos: QoS, retain: bool) -> LocalBoxFuture<'a, Result<(), mqtt_async_client::Error>>;
+}
+
+impl PublishClient for Publisher {
+    fn publish<'a>(&'a self, topic: &'a str, payload: &'a [u8], qos: QoS, retain: bool) -> LocalBoxFuture<'a, Result<(), mqtt_async_client::Error>> {
+        Box::pin(Publisher::publish(self, topic, payload, qos, retain))
+    }
+}
+
+/// Publishes a message up to `attempts` times, waiting `backoff` after the first failure and doubling it after each
+/// one after that. Returns the last error once the attempts are used up.
+pub async fn retry<C: PublishClient>(client: &C, topic: &str, payload: &[u8], qos: QoS, retain: bool, attempts: u32, backoff: Duration) -> Result<(), mqtt_async_client::Error> {
+    let mut delay = backoff;
+    let mut attempt = 1;
+    loop {
+        match client.publish(topic, payload, qos, retain).await {
+            Ok(()) => return Ok(()),
+            Err(err) if attempt >= attempts => return Err(err),
+            Err(err) => {
+                warn!("Error publishing to {} (attempt {}/{}): {} - retrying in {}ms", topic, attempt, attempts, err, delay.as_millis());
+                delay_for(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+fn message(topic: &str, payload: &[u8], qos: QoS, retain: bool) -> PublishOpts {
+    let mut msg = PublishOpts::new(topic.to_string(), payload.to_vec());
+    msg.set_qos(qos);
+    msg.set_retain(retain);
+    msg
+}
+
+#[cfg(feature = "gzip")]
+pub fn gzip(payload: &[u8]) -> std::io::Result<Vec<u8>> {
+    use std::io::Write;
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(payload)?;
+    encoder.finish()
+}
+
+/// `{"ts": <unix ms>, "data": <json>}` - value templates read the fields as `value_json.data.<field>`
+pub fn timestamped(json: &str, received: SystemTime) -> String {
+    let ts = received.duration_since(UNIX_EPOCH).map(|since| since.as_millis()).unwrap_or_default();
+    format!("{{\"ts\":{},\"data\":{}}}", ts, json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    // Fails the first `failures` publishes, recording when each one was attempted
+    struct FlakyClient {
+        failures: usize,
+        attempts: Mutex<Vec<Instant>>,
+    }
+
+    impl FlakyClient {
+        fn new(failures: usize) -> Self {
+            FlakyClient { failures, attempts: Mutex::new(Vec::new()) }
+        }
+
+        // The gaps between consecutive attempts
+        fn delays(&self) -> Vec<Duration> {
+            self.attempts.lock().unwrap().windows(2).map(|pair| pair[1] - pair[0]).collect()
+        }
+    }
+
+    impl PublishClient for FlakyClient {
+        fn publish<'a>(&'a self, _topic: &'a str, _payload: &'a [u8], _qos: QoS, _retain: bool) -> LocalBoxFuture<'a, Result<(), mqtt_async_client::Error>> {
+            let mut attempts = self.attempts.lock().unwrap();
+            attempts.push(Instant::now());
+            let result = if attempts.len() <= self.failures { Err(mqtt_async_client::Error::String(format!("failure {}", attempts.len()))) } else { Ok(()) };
+            async move { result }.boxed_local()
+        }
+    }
+
+    const BACKOFF: Duration = Duration::from_millis(20);
+
+    #[tokio::test]
+    async fn succeeds_without_waiting() {
+        let client = FlakyClient::new(0);
+        retry(&client, "test/qpigs", b"{}", QoS::AtLeastOnce, false, 3, BACKOFF).await.unwrap();
+        assert_eq!(client.attempts.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn doubles_the_delay_between_attempts() {
+        let client = FlakyClient::new(3);
+        retry(&client, "test/qpigs", b"{}", QoS::AtLeastOnce, false, 4, BACKOFF).await.unwrap();
+        let delays = client.delays();
+        assert_eq!(delays.len(), 3);
+        // Only lower bounds, a busy machine can always wake the retry up late
+        for (delay, expected) in delays.iter().zip(&[BACKOFF, BACKOFF * 2, BACKOFF * 4]) {
+            assert!(*delay >= *expected, "waited {:?} where at least {:?} was expected in {:?}", delay, expected, delays);
+        }
+    }
+
+    #[tokio::test]
+    async fn returns_the_last_error_after_the_attempts_run_out() {
+        let client = FlakyClient::new(usize::MAX);
+        let err = retry(&client, "test/qpigs", b"{}", QoS::AtLeastOnce, false, 3, BACKOFF).await.unwrap_err();
+        assert_eq!(err.to_string(), "failure 3");
+        assert_eq!(client.attempts.lock().unwrap().len(), 3);
+        assert_eq!(client.delays().len(), 2);
+    }
+}
@@ -0,0 +1,413 @@
+use crate::commands::{QFlag, QMn, QMod, QPigs, QPigs2, QT};
+use crate::protocol::{self, ProtocolError};
+use crate::serial::{Counted, SerialCounters, SerialStats, SerialStream, Shared, Tapped};
+use crate::settings::Mode;
+
+use masterpower_api::commands::qid::QID;
+use masterpower_api::commands::qpgs::{QPGS0, QPGS1, QPGS2, QPGS3, QPGS4, QPGS5, QPGS6, QPGS7, QPGS8, QPGS9};
+use masterpower_api::commands::qpi::QPI;
+use masterpower_api::commands::qpiri::QPIRIReduced;
+use masterpower_api::commands::qpiri::QPIRI;
+use masterpower_api::commands::qpiws::QPIWS;
+use masterpower_api::commands::qvfw::QVFW;
+use masterpower_api::inverter::Inverter;
+
+use log::{debug, warn};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{delay_for, timeout, Duration};
+
+// After a garbled or missing response, how long the rest of it gets to arrive before the input is discarded, so it
+// can't be read as the start of the next response
+const RESYNC_DELAY: Duration = Duration::from_millis(100);
+
+/// Highest QPGS index - the protocol only addresses parallel units 0 to 9
+pub const MAX_QPGS_INDEX: u8 = 9;
+
+/// A command the owner task can run on the serial line
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    QID,
+    QPI,
+    QVFW,
+    QMN,
+    // Carries the mode setting so the reply can be described with the right protocol's table
+    QMOD(Mode),
+    QPIWS,
+    QPIRI,
+    QPIRIReduced,
+    QPIGS,
+    QPIGS2,
+    QPGS(u8),
+    QFLAG,
+    QT,
+    // Sent as is, the response data comes back unparsed
+    Raw(String),
+}
+
+impl Query {
+    /// Looks up a parameterless query by its topic suffix, picking the QPIRI variant the mode supports
+    pub fn from_name(name: &str, mode: Mode) -> Option<Self> {
+        let query = match name {
+            "qid" => Query::QID,
+            "qpi" => Query::QPI,
+            "qvfw" => Query::QVFW,
+            "qmn" => Query::QMN,
+            "qmod" => Query::QMOD(mode),
+            "qpiws" => Query::QPIWS,
+            "qpiri" if mode == Mode::Phocos => Query::QPIRIReduced,
+            "qpiri" => Query::QPIRI,
+            "qpigs" => Query::QPIGS,
+            "qpigs2" => Query::QPIGS2,
+            "qflag" => Query::QFLAG,
+            "qt" => Query::QT,
+            _ => match name.strip_prefix("qpgs").map(str::parse::<u8>) {
+                Some(Ok(index)) if index <= MAX_QPGS_INDEX => Query::QPGS(index),
+                _ => return None,
+            },
+        };
+        Some(query)
+    }
+
+    /// The topic suffix the response is published under
+    pub fn name(&self) -> String {
+        match self {
+            Query::QID => String::from("qid"),
+            Query::QPI => String::from("qpi"),
+            Query::QVFW => String::from("qvfw"),
+            Query::QMN => String::from("qmn"),
+            Query::QMOD(_) => String::from("qmod"),
+            Query::QPIWS => String::from("qpiws"),
+            Query::QPIRI | Query::QPIRIReduced => String::from("qpiri"),
+            Query::QPIGS => String::from("qpigs"),
+            Query::QPIGS2 => String::from("qpigs2"),
+            Query::QPGS(index) => format!("qpgs{}", index),
+            Query::QFLAG => String::from("qflag"),
+            Query::QT => String::from("qt"),
+            Query::Raw(command) => command.to_ascii_lowercase(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum QueueError {
+    // The inverter didn't answer in time
+    Timeout(Duration),
+    // The inverter doesn't support the command
+    Nak,
+    // The response crc didn't match its data, worth asking again
+    Crc { expected: u16, actual: u16 },
+    // The response was garbled (unparseable, or a bad crc we couldn't recompute), worth asking again
+    Corrupt(String),
+    // The serial line itself failed, retrying on the same handle won't help
+    Io(String),
+    // The command failed, only the message survives the trip back from the owner task
+    Failed(String),
+    // The owner task has stopped
+    Closed,
+}
+
+impl fmt::Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueueError::Timeout(duration) => write!(f, "no response within {}ms", duration.as_millis()),
+            QueueError::Nak => write!(f, "inverter responded with NAK"),
+            QueueError::Crc { expected, actual } => write!(f, "invalid response crc (expected {:04X} got {:04X})", expected, actual),
+            QueueError::Corrupt(message) => write!(f, "{}", message),
+            QueueError::Io(message) => write!(f, "{}", message),
+            QueueError::Failed(message) => write!(f, "{}", message),
+            QueueError::Closed => write!(f, "inverter command queue has stopped"),
+        }
+    }
+}
+
+impl std::error::Error for QueueError {}
+
+struct Job {
+    query: Query,
+    // The result and the bytes the inverter sent back, which may be empty
+    reply: oneshot::Sender<(Result<String, QueueError>, Vec<u8>)>,
+}
+
+/// Handle for submitting commands to the owner task. Cheap to clone, one per requester.
+#[derive(Clone)]
+pub struct CommandQueue {
+    sender: mpsc::Sender<Job>,
+    counters: SerialCounters,
+}
+
+impl CommandQueue {
+    /// Queues a command and waits for its response, as JSON for the typed queries
+    pub async fn execute(&self, query: Query) -> Result<String, QueueError> {
+        self.execute_with_raw(query).await.0
+    }
+
+    /// `execute`, also returning the bytes the inverter sent before parsing - empty when nothing was read,
+    /// but kept when parsing failed
+    pub async fn execute_with_raw(&self, query: Query) -> (Result<String, QueueError>, Vec<u8>) {
+        let (reply, response) = oneshot::channel();
+        let mut sender = self.sender.clone();
+        if sender.send(Job { query, reply }).await.is_err() {
+            return (Err(QueueError::Closed), Vec::new());
+        }
+        response.await.unwrap_or_else(|_| (Err(QueueError::Closed), Vec::new()))
+    }
+
+    /// Bytes and frames through the serial line so far
+    pub fn serial_stats(&self) -> SerialStats {
+        self.counters.snapshot()
+    }
+}
+
+/// Sole owner of the serial line - runs queued jobs one at a time so transactions never interleave
+pub struct Owner {
+    inverter: Inverter<Shared<Counted<Tapped>>>,
+    // The inverter's last response frame, to recover crc values masterpower_api doesn't report
+    last_response: Arc<Mutex<Vec<u8>>>,
+    // The same stream again for the raw protocol commands masterpower_api doesn't cover
+    raw: Shared<Counted<Tapped>>,
+    receiver: mpsc::Receiver<Job>,
+    command_timeout: Duration,
+    counters: SerialCounters,
+}
+
+pub fn new(stream: SerialStream, command_timeout: Duration, counters: SerialCounters) -> (CommandQueue, Owner) {
+    let (sender, receiver) = mpsc::channel(16);
+    let stream = Tapped::new(stream);
+    let last_response = stream.last_response();
+    let raw = Shared::new(Counted::new(stream, counters.clone()));
+    let owner = Owner {
+        inverter: Inverter::from_stream(raw.clone()),
+        last_response,
+        raw,
+        receiver,
+        command_timeout,
+        counters: counters.clone(),
+    };
+    (CommandQueue { sender, counters }, owner)
+}
+
+// masterpower_api has a type per QPGS index, this picks the one for a runtime index
+macro_rules! qpgs {
+    ($inverter:expr, $index:expr, $($n:literal => $command:ident),*) => {
+        match $index {
+            $($n => $inverter.execute::<$command>(()).await.map_err(Into::into).and_then(to_json),)*
+            index => Err(format!("QPGS{} is not supported, parallel units are numbered 0 to 9", index).into()),
+        }
+    };
+}
+
+impl Owner {
+    /// Runs jobs until every `CommandQueue` handle has been dropped
+    pub async fn run(mut self) {
+        while let Some(job) = self.receiver.recv().await {
+            debug!("Running {:?}", job.query);
+            // A command that fails before writing anything mustn't report the previous command's response
+            if let Ok(mut last_response) = self.last_response.lock() {
+                last_response.clear();
+            }
+            let result = match timeout(self.command_timeout, self.execute(&job.query)).await {
+                Ok(result) => result,
+                Err(_) => Err(QueueError::Timeout(self.command_timeout)),
+            };
+            match result {
+                Ok(_) => self.counters.frame_ok(),
+                Err(QueueError::Crc { .. }) => self.counters.frame_crc_fail(),
+                Err(_) => (),
+            }
+            let resync = matches!(result, Err(QueueError::Crc { .. }) | Err(QueueError::Corrupt(_)) | Err(QueueError::Timeout(_)));
+            let raw = self.last_response.lock().map(|last_response| last_response.clone()).unwrap_or_default();
+            // The requester may have given up waiting, which is fine
+            let _ = job.reply.send((result, raw));
+            if resync {
+                self.resync().await;
+            }
+        }
+    }
+
+    /// Throws away whatever is still arriving from a garbled or late response so the next command starts on a frame
+    /// boundary
+    async fn resync(&mut self) {
+        delay_for(RESYNC_DELAY).await;
+        match self.raw.lock().get_mut().get_mut().discard_input() {
+            Ok(()) => debug!("Discarded pending input to resync on the next frame"),
+            // The next command will still see the leftovers, protocol::query skips up to the frame start at least
+            Err(err) => warn!("Could not discard pending input after a garbled response: {}", err),
+        }
+    }
+
+    async fn execute(&mut self, query: &Query) -> Result<String, QueueError> {
+        let result: Result<String, Box<dyn std::error::Error>> = match query {
+            Query::QID => self.inverter.execute::<QID>(()).await.map_err(Into::into).and_then(to_json),
+            Query::QPI => self.inverter.execute::<QPI>(()).await.map_err(Into::into).and_then(to_json),
+            Query::QVFW => self.inverter.execute::<QVFW>(()).await.map_err(Into::into).and_then(to_json),
+            Query::QPIWS => self.inverter.execute::<QPIWS>(()).await.map_err(Into::into).and_then(to_json),
+            Query::QPIRI => self.inverter.execute::<QPIRI>(()).await.map_err(Into::into).and_then(to_json),
+            Query::QPIRIReduced => self.inverter.execute::<QPIRIReduced>(()).await.map_err(Into::into).and_then(to_json),
+            Query::QPGS(index) => qpgs!(self.inverter, *index, 0 => QPGS0, 1 => QPGS1, 2 => QPGS2, 3 => QPGS3, 4 => QPGS4, 5 => QPGS5, 6 => QPGS6, 7 => QPGS7, 8 => QPGS8, 9 => QPGS9),
+            Query::QPIGS => QPigs::query(&mut self.raw).await.map_err(Into::into).and_then(to_json),
+            Query::QPIGS2 => QPigs2::query(&mut self.raw).await.map_err(Into::into).and_then(to_json),
+            Query::QMN => QMn::query(&mut self.raw).await.map_err(Into::into).and_then(to_json),
+            Query::QMOD(mode) => QMod::query(&mut self.raw, *mode).await.map_err(Into::into).and_then(to_json),
+            Query::QFLAG => QFlag::query(&mut self.raw).await.map_err(Into::into).and_then(to_json),
+            Query::QT => QT::query(&mut self.raw).await.map_err(Into::into).and_then(to_json),
+            Query::Raw(command) => protocol::query(&mut self.raw, command).await.map_err(Into::into),
+        };
+        result.map_err(|err| classify(err.as_ref(), &self.last_response))
+    }
+}
+
+// masterpower_api's error variants aren't public, so anything that isn't ours is matched on its debug output.
+// Its crc errors don't carry the values either, so they're recomputed from the frame the inverter sent.
+fn classify(err: &(dyn std::error::Error + 'static), last_response: &Mutex<Vec<u8>>) -> QueueError {
+    match err.downcast_ref::<ProtocolError>() {
+        Some(ProtocolError::Nak) => return QueueError::Nak,
+        Some(ProtocolError::Crc { expected, actual }) => return QueueError::Crc { expected: *expected, actual: *actual },
+        Some(ProtocolError::Malformed(_)) => return QueueError::Corrupt(err.to_string()),
+        Some(ProtocolError::Io(_)) => return QueueError::Io(err.to_string()),
+        None => (),
+    }
+    if err.downcast_ref::<std::io::Error>().is_some() {
+        return QueueError::Io(err.to_string());
+    }
+    // masterpower_api fails to parse a NAK rather than reporting it, so the frame itself is checked
+    if let Ok(frame) = last_response.lock() {
+        if let Some(end) = frame.iter().position(|byte| *byte == b'\r') {
+            if let Err(ProtocolError::Nak) = protocol::decode_response(&frame[..=end]) {
+                return QueueError::Nak;
+            }
+        }
+    }
+    let debug = format!("{:?}", err).to_ascii_lowercase();
+    if debug.contains("crc") {
+        match last_response.lock().ok().and_then(|frame| protocol::crc_mismatch(&frame)) {
+            Some((expected, actual)) => QueueError::Crc { expected, actual },
+            None => QueueError::Corrupt(err.to_string()),
+        }
+    } else if debug.contains("pars") {
+        QueueError::Corrupt(err.to_string())
+    } else if debug.contains("io(") || debug.contains("os {") {
+        QueueError::Io(err.to_string())
+    } else {
+        QueueError::Failed(err.to_string())
+    }
+}
+
+/// A queue error labelled with the command that caused it, e.g. `qpigs: invalid response crc`
+#[derive(Debug)]
+pub struct CommandError {
+    pub command: String,
+    pub error: QueueError,
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.command, self.error)
+    }
+}
+
+impl std::error::Error for CommandError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Whether asking again might succeed - a garbled or missing response can be retried straight away,
+/// a failed serial line needs reopening first
+pub fn is_recoverable_error(err: &QueueError) -> bool {
+    match err {
+        QueueError::Crc { .. } | QueueError::Corrupt(_) | QueueError::Timeout(_) => true,
+        QueueError::Nak | QueueError::Io(_) | QueueError::Failed(_) | QueueError::Closed => false,
+    }
+}
+
+fn to_json<T: serde::Serialize>(response: T) -> Result<String, Box<dyn std::error::Error>> {
+    Ok(serde_json::to_string(&response)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+    use std::fs::{File, OpenOptions};
+    use std::io::{Read, Write};
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::thread;
+
+    const COMMAND_TIMEOUT: Duration = Duration::from_millis(200);
+
+    // Opens a pty, returning the inverter's end and the raw device end the queue is given
+    fn pty() -> (File, File) {
+        let (inverter, path) = unsafe {
+            let master = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            assert!(master >= 0, "posix_openpt: {}", std::io::Error::last_os_error());
+            assert_eq!(libc::grantpt(master), 0, "grantpt: {}", std::io::Error::last_os_error());
+            assert_eq!(libc::unlockpt(master), 0, "unlockpt: {}", std::io::Error::last_os_error());
+            let mut name = [0 as libc::c_char; 128];
+            assert_eq!(libc::ptsname_r(master, name.as_mut_ptr(), name.len()), 0, "ptsname_r: {}", std::io::Error::last_os_error());
+            (File::from_raw_fd(master), CStr::from_ptr(name.as_ptr()).to_string_lossy().to_string())
+        };
+        let device = OpenOptions::new().read(true).write(true).custom_flags(libc::O_NOCTTY).open(&path).unwrap();
+        unsafe {
+            let mut tty: libc::termios = std::mem::zeroed();
+            assert_eq!(libc::tcgetattr(device.as_raw_fd(), &mut tty), 0);
+            libc::cfmakeraw(&mut tty);
+            assert_eq!(libc::tcsetattr(device.as_raw_fd(), libc::TCSANOW, &tty), 0);
+        }
+        (inverter, device)
+    }
+
+    // Answers each request with its own command as the data, holding the first answer back until told to send it
+    fn start_inverter(mut inverter: File, late: std::sync::mpsc::Receiver<()>) {
+        thread::spawn(move || {
+            let mut request = Vec::new();
+            let mut buf = [0u8; 64];
+            let mut first = true;
+            while let Ok(read) = inverter.read(&mut buf) {
+                if read == 0 {
+                    return;
+                }
+                for byte in &buf[..read] {
+                    request.push(*byte);
+                    if *byte != b'\r' {
+                        continue;
+                    }
+                    let end = request.len().saturating_sub(3);
+                    let mut frame = format!("({}", String::from_utf8_lossy(&request[..end])).into_bytes();
+                    request.clear();
+                    frame.extend_from_slice(&protocol::crc16(&frame).to_be_bytes());
+                    frame.push(b'\r');
+                    if first && late.recv().is_err() {
+                        return;
+                    }
+                    first = false;
+                    if inverter.write_all(&frame).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn a_late_response_isnt_read_as_the_next_one() {
+        let (inverter, device) = pty();
+        let (send_late, late) = std::sync::mpsc::channel();
+        start_inverter(inverter, late);
+        let (queue, owner) = new(SerialStream::Device(device), COMMAND_TIMEOUT, SerialCounters::default());
+        let owner = tokio::spawn(owner.run());
+
+        match queue.execute(Query::Raw(String::from("QMN"))).await {
+            Err(QueueError::Timeout(_)) => (),
+            result => panic!("QMN should have timed out waiting for its response, got {:?}", result),
+        }
+        // The QMN response turns up while the owner is resyncing, before QT goes out
+        send_late.send(()).unwrap();
+        assert_eq!(queue.execute(Query::Raw(String::from("QT"))).await.unwrap(), "QT");
+
+        drop(queue);
+        owner.await.unwrap();
+    }
+}
@@ -0,0 +1,158 @@
+use crate::settings::Settings;
+
+use log::{error, info, warn, LevelFilter};
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use tokio::signal::unix::{signal, SignalKind};
+
+// SIGHUP re-reads the config file. The poll timing, intervals, disabled commands and log level are picked up by the
+// running loops, anything else (broker, topics, devices...) needs a restart.
+
+/// Settings reloaded by SIGHUP, numbered so each inverter's loop can tell when there's a new copy
+#[derive(Clone, Default)]
+pub struct Reloads {
+    latest: Arc<Mutex<(u64, Option<Settings>)>>,
+}
+
+impl Reloads {
+    /// Spawns a task reloading the config file on every SIGHUP, with the same overrides it was first loaded with
+    pub fn listen(config_path: String, overrides: Vec<(String, String)>, current: Settings) -> std::io::Result<Self> {
+        let mut sighup = signal(SignalKind::hangup())?;
+        let reloads = Reloads::default();
+        let latest = reloads.latest.clone();
+        tokio::spawn(async move {
+            let mut current = current;
+            while sighup.recv().await.is_some() {
+                info!("Received SIGHUP - reloading {}", config_path);
+                let overrides: Vec<(&str, &str)> = overrides.iter().map(|(key, value)| (key.as_str(), value.as_str())).collect();
+                let reloaded = match Settings::with_overrides(&config_path, &overrides) {
+                    Ok(reloaded) => reloaded,
+                    Err(err) => {
+                        error!("Could not reload {}: {} - carrying on with the current settings", config_path, err);
+                        continue;
+                    }
+                };
+                report(&current, &reloaded);
+                set_log_level(&reloaded);
+                {
+                    let mut latest = latest.lock().unwrap();
+                    latest.0 += 1;
+                    latest.1 = Some(reloaded.clone());
+                }
+                current = reloaded;
+            }
+        });
+        Ok(reloads)
+    }
+
+    /// A handle for one loop, which only sees reloads from here on
+    pub fn watch(&self) -> ReloadWatch {
+        let latest = self.latest.lock().unwrap();
+        ReloadWatch {
+            reloads: self.clone(),
+            seen: latest.0,
+            current: latest.1.clone(),
+        }
+    }
+}
+
+pub struct ReloadWatch {
+    reloads: Reloads,
+    seen: u64,
+    // The last reload seen, so a loop restarted after the serial line drops starts from it too
+    current: Option<Settings>,
+}
+
+impl ReloadWatch {
+    /// The reloaded settings, if there have been any since the last call
+    pub fn changed(&mut self) -> Option<Settings> {
+        let latest = self.reloads.latest.lock().unwrap();
+        if latest.0 == self.seen {
+            return None;
+        }
+        self.seen = latest.0;
+        self.current = latest.1.clone();
+        self.current.clone()
+    }
+
+    /// The last reloaded settings this watch has seen, if any
+    pub fn current(&self) -> Option<&Settings> {
+        self.current.as_ref()
+    }
+}
+
+/// Copies the fields that can change while running onto a loop's settings, the rest stay as they started
+pub fn apply(settings: &mut Settings, reloaded: &Settings) {
+    settings.outer_delay = reloaded.outer_delay;
+    settings.inner_delay = reloaded.inner_delay;
+    settings.error_delay = reloaded.error_delay;
+    settings.max_error_delay = reloaded.max_error_delay;
+    settings.inner_iterations = reloaded.inner_iterations;
+    settings.intervals = reloaded.intervals.clone();
+    settings.default_interval = reloaded.default_interval;
+    settings.disabled_commands = reloaded.disabled_commands.clone();
+    settings.poll_jitter_percent = reloaded.poll_jitter_percent;
+}
+
+// Logs what the reload changes, warning about the changes that won't apply until a restart
+fn report(current: &Settings, reloaded: &Settings) {
+    fn differs(a: &dyn Debug, b: &dyn Debug) -> bool {
+        format!("{:?}", a) != format!("{:?}", b)
+    }
+    let reloadable: Vec<&str> = vec![
+        ("outer_delay", differs(&current.outer_delay, &reloaded.outer_delay)),
+        ("inner_delay", differs(&current.inner_delay, &reloaded.inner_delay)),
+        ("error_delay", differs(&current.error_delay, &reloaded.error_delay)),
+        ("max_error_delay", differs(&current.max_error_delay, &reloaded.max_error_delay)),
+        ("inner_iterations", differs(&current.inner_iterations, &reloaded.inner_iterations)),
+        ("intervals", differs(&current.intervals, &reloaded.intervals)),
+        ("default_interval", differs(&current.default_interval, &reloaded.default_interval)),
+        ("disabled_commands", differs(&current.disabled_commands, &reloaded.disabled_commands)),
+        ("poll_jitter_percent", differs(&current.poll_jitter_percent, &reloaded.poll_jitter_percent)),
+        ("log_level", differs(&current.log_level, &reloaded.log_level)),
+    ]
+    .into_iter()
+    .filter(|(_, changed)| *changed)
+    .map(|(field, _)| field)
+    .collect();
+    let restart: Vec<&str> = vec![
+        ("mqtt.host", differs(&current.mqtt.host, &reloaded.mqtt.host)),
+        ("mqtt.port", differs(&current.mqtt.port(), &reloaded.mqtt.port())),
+        ("mqtt.client_id", differs(&current.mqtt.client_id, &reloaded.mqtt.client_id)),
+        ("mqtt.username", differs(&current.mqtt.username, &reloaded.mqtt.username)),
+        ("mqtt.password", differs(&current.mqtt.password, &reloaded.mqtt.password)),
+        ("mqtt.tls", differs(&current.mqtt.tls, &reloaded.mqtt.tls)),
+        ("mqtt.topic", differs(&current.mqtt.topic, &reloaded.mqtt.topic)),
+        ("inverter", differs(&current.inverter, &reloaded.inverter)),
+        ("mode", differs(&current.mode, &reloaded.mode)),
+        ("log_filter", differs(&current.log_filter, &reloaded.log_filter)),
+        ("log_format", differs(&current.log_format, &reloaded.log_format)),
+    ]
+    .into_iter()
+    .filter(|(_, changed)| *changed)
+    .map(|(field, _)| field)
+    .collect();
+
+    if reloadable.is_empty() {
+        info!("Reloaded settings - nothing that can be applied while running has changed");
+    } else {
+        info!("Reloaded settings - applying {}", reloadable.join(", "));
+    }
+    if !restart.is_empty() {
+        warn!("{} changed but can't be applied while running - restart mpqtt to pick them up", restart.join(", "));
+    }
+}
+
+// The logger's filter is fixed once it's built, so a reloaded log_level can only turn logging down from the level
+// it started with, not up. An externally set RUST_LOG or a log_filter is left alone.
+fn set_log_level(reloaded: &Settings) {
+    if std::env::var("RUST_LOG").is_ok() || reloaded.log_filter.is_some() {
+        return;
+    }
+    let level = match reloaded.log_level {
+        Some(ref level) => level.parse().unwrap_or(LevelFilter::Info),
+        None if reloaded.debug => LevelFilter::Trace,
+        None => LevelFilter::Info,
+    };
+    log::set_max_level(level);
+}
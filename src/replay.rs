@@ -0,0 +1,166 @@
+use crate::protocol;
+
+use log::{debug, info, warn};
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Stands in for the inverter by answering requests with the frames of a captured serial log, byte for byte,
+/// so a CRC or parse failure seen in the field can be reproduced.
+///
+/// Captures are either delimited, with each frame ending in `\r` or a newline, or length delimited, with each
+/// frame preceded by its length as two big endian bytes (told apart by the leading zero byte). Responses start with
+/// `(`, anything else is taken as a request. A response that follows a request is kept for that command, the others
+/// are handed out in order to whichever command asks next.
+pub struct Replay {
+    capture: Arc<Mutex<Capture>>,
+    request: Vec<u8>,
+    response: VecDeque<u8>,
+    waker: Option<Waker>,
+}
+
+struct Capture {
+    // command -> responses captured after it was asked
+    responses: HashMap<String, VecDeque<Vec<u8>>>,
+    // responses with no request captured before them
+    unmatched: VecDeque<Vec<u8>>,
+    exhausted: bool,
+}
+
+impl Replay {
+    pub fn load(path: &str) -> std::io::Result<Self> {
+        let bytes = std::fs::read(path)?;
+        let frames = if bytes.first() == Some(&0) { length_delimited(&bytes)? } else { delimited(&bytes) };
+
+        let mut capture = Capture {
+            responses: HashMap::new(),
+            unmatched: VecDeque::new(),
+            exhausted: false,
+        };
+        let mut last_request = None;
+        for frame in frames {
+            if frame.first() == Some(&b'(') {
+                match last_request.take() {
+                    Some(command) => capture.responses.entry(command).or_insert_with(VecDeque::new).push_back(frame),
+                    None => capture.unmatched.push_back(frame),
+                }
+            } else {
+                last_request = Some(command_name(&frame));
+            }
+        }
+        let count = capture.unmatched.len() + capture.responses.values().map(VecDeque::len).sum::<usize>();
+        info!("Replaying {} response frames from {}", count, path);
+        Ok(Replay {
+            capture: Arc::new(Mutex::new(capture)),
+            request: Vec::new(),
+            response: VecDeque::new(),
+            waker: None,
+        })
+    }
+
+    fn respond(&mut self) {
+        // Drop any report padding before working out the command
+        self.request.retain(|byte| *byte != 0);
+        let command = command_name(&self.request);
+        self.request.clear();
+
+        let mut frame = {
+            let mut capture = self.capture.lock().unwrap();
+            let next = match capture.responses.get_mut(&command).and_then(VecDeque::pop_front) {
+                Some(frame) => Some(frame),
+                None => capture.unmatched.pop_front(),
+            };
+            match next {
+                Some(frame) => frame,
+                None => {
+                    if !capture.exhausted {
+                        capture.exhausted = true;
+                        warn!("Replay capture has run out of responses - answering NAK from here on");
+                    }
+                    nak()
+                }
+            }
+        };
+        debug!("Replaying {} -> {}", command, String::from_utf8_lossy(&frame));
+        if frame.last() != Some(&b'\r') {
+            frame.push(b'\r');
+        }
+        self.response.extend(frame);
+
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+// A request frame without its crc and \r, lowercased like the fixture keys
+fn command_name(frame: &[u8]) -> String {
+    let frame: Vec<u8> = frame.iter().cloned().filter(|byte| *byte != b'\r').collect();
+    let end = frame.len().saturating_sub(2);
+    String::from_utf8_lossy(&frame[..end]).to_ascii_lowercase()
+}
+
+fn nak() -> Vec<u8> {
+    let mut frame = b"(NAK".to_vec();
+    frame.extend_from_slice(&protocol::crc16(&frame).to_be_bytes());
+    frame
+}
+
+fn delimited(bytes: &[u8]) -> Vec<Vec<u8>> {
+    bytes.split(|byte| *byte == b'\r' || *byte == b'\n').filter(|frame| !frame.is_empty()).map(<[u8]>::to_vec).collect()
+}
+
+fn length_delimited(bytes: &[u8]) -> std::io::Result<Vec<Vec<u8>>> {
+    let mut frames = Vec::new();
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        if rest.len() < 2 {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "capture ends partway through a frame length"));
+        }
+        let length = usize::from(u16::from_be_bytes([rest[0], rest[1]]));
+        if rest.len() < 2 + length {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("capture ends partway through a {} byte frame", length)));
+        }
+        frames.push(rest[2..2 + length].to_vec());
+        rest = &rest[2 + length..];
+    }
+    Ok(frames)
+}
+
+impl AsyncRead for Replay {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if this.response.is_empty() {
+            this.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let read = buf.len().min(this.response.len());
+        for (slot, byte) in buf.iter_mut().zip(this.response.drain(..read)) {
+            *slot = byte;
+        }
+        Poll::Ready(Ok(read))
+    }
+}
+
+impl AsyncWrite for Replay {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        for byte in buf {
+            this.request.push(*byte);
+            if *byte == b'\r' {
+                this.respond();
+            }
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
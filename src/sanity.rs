@@ -0,0 +1,67 @@
+use crate::settings::{SanityRange, SanitySettings};
+
+use serde_json::{Map, Value};
+
+// A corrupt frame can still pass its CRC and parse, giving readings like a 6500V battery. Fields outside their range
+// are dropped from the response before anything is published or recorded from it, so they never reach Home
+// Assistant's long term statistics.
+
+// Generous limits for the common QPIGS fields, wide enough for 12-48V banks and any grid, that only an impossible
+// reading falls outside
+const QPIGS_RANGES: &[(&str, f64, f64)] = &[
+    ("grid_voltage", 0.0, 300.0),
+    ("grid_frequency", 0.0, 70.0),
+    ("ac_out_voltage", 0.0, 300.0),
+    ("ac_out_frequency", 0.0, 70.0),
+    ("ac_out_apparent_power", 0.0, 30000.0),
+    ("ac_out_active_power", 0.0, 30000.0),
+    ("out_load_percent", 0.0, 250.0),
+    ("bus_voltage", 0.0, 600.0),
+    ("battery_voltage", 0.0, 80.0),
+    ("battery_charge_current", 0.0, 500.0),
+    ("battery_capacity", 0.0, 100.0),
+    ("inverter_heat_sink_temp", -40.0, 150.0),
+    ("pv_input_current", 0.0, 200.0),
+    ("pv_input_voltage", 0.0, 600.0),
+    ("battery_scc_voltage", 0.0, 80.0),
+    ("battery_discharge_current", 0.0, 500.0),
+];
+
+/// The range a field has to be in, a configured one replacing the built in one
+fn range(settings: &SanitySettings, command: &str, field: &str) -> Option<SanityRange> {
+    if let Some(range) = settings.ranges.get(command).and_then(|fields| fields.get(field)) {
+        return Some(range.clone());
+    }
+    if !settings.builtin || command != "qpigs" {
+        return None;
+    }
+    QPIGS_RANGES.iter().find(|(name, _, _)| *name == field).map(|(_, min, max)| SanityRange { min: Some(*min), max: Some(*max) })
+}
+
+/// Drops the top level numbers outside their range from a JSON response, returning what's left and what was dropped.
+/// The response comes back untouched when nothing was.
+pub fn check(settings: &SanitySettings, command: &str, json: String) -> (String, Map<String, Value>) {
+    let mut rejected = Map::new();
+    let mut fields = match serde_json::from_str::<Value>(&json) {
+        Ok(Value::Object(fields)) => fields,
+        _ => return (json, rejected),
+    };
+    for (field, value) in fields.iter() {
+        let number = match value.as_f64() {
+            Some(number) => number,
+            None => continue,
+        };
+        if let Some(range) = range(settings, command, field) {
+            if range.min.map_or(false, |min| number < min) || range.max.map_or(false, |max| number > max) {
+                rejected.insert(field.clone(), value.clone());
+            }
+        }
+    }
+    if rejected.is_empty() {
+        return (json, rejected);
+    }
+    for field in rejected.keys() {
+        fields.remove(field);
+    }
+    (Value::Object(fields).to_string(), rejected)
+}
@@ -0,0 +1,204 @@
+use crate::settings::Settings;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, SystemTime};
+
+/// Decides which commands are due on each pass of the update loop.
+/// Commands are only checked when the loop reaches them, so an interval shorter than the loop delays has no effect.
+/// Intervals are kept on the wall clock from when each command was last due rather than when the loop got to it, so
+/// a long interval (e.g. 86400 for daily) doesn't creep later by a loop's worth every time and still fires on time
+/// after the host has been suspended.
+pub struct Scheduler {
+    intervals: HashMap<String, Duration>,
+    default_interval: Duration,
+    disabled: Vec<String>,
+    // Disabled while running because the inverter NAKed them, kept when the settings are reloaded
+    unsupported: Vec<String>,
+    // When each command was last due, its next poll is due an interval after this
+    scheduled: HashMap<String, SystemTime>,
+    jitter_percent: u8,
+    rng: StdRng,
+    cycles: u64,
+}
+
+impl Scheduler {
+    pub fn new(settings: &Settings) -> Self {
+        let seed = settings.poll_jitter_seed.unwrap_or_else(|| {
+            let mut hasher = DefaultHasher::new();
+            settings.mqtt.client_id.hash(&mut hasher);
+            hasher.finish()
+        });
+        Scheduler {
+            intervals: settings.intervals.iter().map(|(command, secs)| (command.clone(), Duration::from_secs(*secs))).collect(),
+            default_interval: Duration::from_secs(settings.default_interval),
+            disabled: settings.disabled_commands.iter().map(|command| command.to_ascii_lowercase()).collect(),
+            unsupported: Vec::new(),
+            scheduled: HashMap::new(),
+            jitter_percent: settings.poll_jitter_percent,
+            rng: StdRng::seed_from_u64(seed),
+            cycles: 0,
+        }
+    }
+
+    /// Returns true, and marks the command as polled, if its interval has elapsed since it was last issued.
+    /// Disabled commands are never due.
+    pub fn due(&mut self, command: &str) -> bool {
+        self.due_at(command, SystemTime::now())
+    }
+
+    // due with the time taken as now
+    fn due_at(&mut self, command: &str, now: SystemTime) -> bool {
+        if self.is_disabled(command) {
+            return false;
+        }
+        let interval = *self.intervals.get(command).unwrap_or(&self.default_interval);
+        let next = match self.scheduled.get(command) {
+            None => now,
+            Some(scheduled) => match now.duration_since(*scheduled) {
+                Ok(elapsed) if elapsed < interval => return false,
+                // Keep to the schedule, unless a whole interval was missed (a slow loop or a suspend) and it would
+                // be due again straight away
+                Ok(elapsed) if elapsed < interval * 2 => *scheduled + interval,
+                Ok(_) => now,
+                // The clock was set back past when it was last due, start counting again from now
+                Err(_) => {
+                    self.scheduled.insert(command.to_string(), now);
+                    return false;
+                }
+            },
+        };
+        self.scheduled.insert(command.to_string(), next);
+        true
+    }
+
+    pub fn is_disabled(&self, command: &str) -> bool {
+        self.disabled.iter().chain(self.unsupported.iter()).any(|disabled| disabled == command)
+    }
+
+    /// Stops polling a command for the rest of the run, e.g. once the inverter has NAKed it
+    pub fn disable(&mut self, command: &str) {
+        if !self.is_disabled(command) {
+            self.unsupported.push(command.to_string());
+        }
+    }
+
+    /// Picks up reloaded intervals, disabled commands and jitter. When each command was last due is kept so a
+    /// reload doesn't make everything due at once.
+    pub fn reconfigure(&mut self, settings: &Settings) {
+        self.intervals = settings.intervals.iter().map(|(command, secs)| (command.clone(), Duration::from_secs(*secs))).collect();
+        self.default_interval = Duration::from_secs(settings.default_interval);
+        self.disabled = settings.disabled_commands.iter().map(|command| command.to_ascii_lowercase()).collect();
+        self.jitter_percent = settings.poll_jitter_percent;
+    }
+
+    /// Counts a new pass of the update loop and returns its number, starting from 1
+    pub fn start_cycle(&mut self) -> u64 {
+        self.cycles += 1;
+        self.cycles
+    }
+
+    /// A loop delay moved randomly by up to poll_jitter_percent either way
+    pub fn jitter(&mut self, secs: u64) -> Duration {
+        let delay = Duration::from_secs(secs);
+        if self.jitter_percent == 0 {
+            return delay;
+        }
+        let spread = f64::from(self.jitter_percent) / 100.0;
+        delay.mul_f64(1.0 + self.rng.gen_range(-spread, spread))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    const DAY: u64 = 86400;
+
+    fn scheduler(command: &str, interval: u64) -> Scheduler {
+        Scheduler {
+            intervals: vec![(command.to_string(), Duration::from_secs(interval))].into_iter().collect(),
+            default_interval: Duration::from_secs(0),
+            disabled: Vec::new(),
+            unsupported: Vec::new(),
+            scheduled: HashMap::new(),
+            jitter_percent: 0,
+            rng: StdRng::seed_from_u64(0),
+            cycles: 0,
+        }
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_600_000_000 + secs)
+    }
+
+    #[test]
+    fn due_on_first_check_then_after_each_interval() {
+        let mut scheduler = scheduler("qpiri", 60);
+        assert!(scheduler.due_at("qpiri", at(0)));
+        assert!(!scheduler.due_at("qpiri", at(59)));
+        assert!(scheduler.due_at("qpiri", at(60)));
+        assert!(!scheduler.due_at("qpiri", at(61)));
+    }
+
+    #[test]
+    fn long_interval_keeps_to_the_wall_clock() {
+        // A daily command checked by a loop every 7s, which never lands exactly on the interval
+        let mut scheduler = scheduler("qpiri", DAY);
+        let mut polled = Vec::new();
+        for secs in (0..5 * DAY).step_by(7) {
+            if scheduler.due_at("qpiri", at(secs)) {
+                polled.push(secs);
+            }
+        }
+        assert_eq!(polled.len(), 5, "polled at {:?}", polled);
+        // Each poll is within a loop of its day starting, rather than creeping up to 7s later every day
+        for (day, secs) in polled.iter().enumerate() {
+            let due = day as u64 * DAY;
+            assert!(*secs >= due && *secs < due + 7, "day {} polled at {} in {:?}", day, secs, polled);
+        }
+    }
+
+    #[test]
+    fn late_poll_stays_on_schedule() {
+        let mut scheduler = scheduler("qpiri", 60);
+        assert!(scheduler.due_at("qpiri", at(0)));
+        // Half an interval late, the next one is still due at 120 rather than 150
+        assert!(scheduler.due_at("qpiri", at(90)));
+        assert!(!scheduler.due_at("qpiri", at(119)));
+        assert!(scheduler.due_at("qpiri", at(120)));
+    }
+
+    #[test]
+    fn missed_intervals_restart_the_schedule() {
+        // e.g. the host was suspended for a few intervals
+        let mut scheduler = scheduler("qpiri", 60);
+        assert!(scheduler.due_at("qpiri", at(0)));
+        assert!(scheduler.due_at("qpiri", at(250)));
+        // Counted from 250, not caught up with polls at 260, 300 ...
+        assert!(!scheduler.due_at("qpiri", at(260)));
+        assert!(!scheduler.due_at("qpiri", at(309)));
+        assert!(scheduler.due_at("qpiri", at(310)));
+    }
+
+    #[test]
+    fn clock_set_back_restarts_the_count() {
+        let mut scheduler = scheduler("qpiri", 60);
+        assert!(scheduler.due_at("qpiri", at(1000)));
+        assert!(!scheduler.due_at("qpiri", at(500)));
+        assert!(!scheduler.due_at("qpiri", at(559)));
+        assert!(scheduler.due_at("qpiri", at(560)));
+    }
+
+    #[test]
+    fn disabled_commands_are_never_due() {
+        let mut scheduler = scheduler("qpiri", 60);
+        scheduler.disable("qpiri");
+        assert!(!scheduler.due_at("qpiri", at(0)));
+        assert!(!scheduler.due_at("qpiri", at(DAY)));
+    }
+}
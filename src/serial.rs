@@ -0,0 +1,470 @@
+use crate::replay::Replay;
+use crate::settings::{InverterSettings, Parity};
+use crate::simulator::Simulator;
+use serde_derive::Serialize;
+
+#[cfg(not(feature = "serialport-backend"))]
+use libc::{open, O_NOCTTY, O_NONBLOCK, O_RDWR};
+#[cfg(not(feature = "serialport-backend"))]
+use log::debug;
+#[cfg(not(feature = "serialport-backend"))]
+use std::ffi::CString;
+use std::io::{Read, Write};
+#[cfg(not(feature = "serialport-backend"))]
+use std::os::unix::ffi::OsStrExt;
+#[cfg(not(feature = "serialport-backend"))]
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+#[cfg(not(feature = "serialport-backend"))]
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// The byte stream the inverter is driven over
+pub enum SerialStream {
+    #[cfg_attr(feature = "serialport-backend", allow(dead_code))]
+    Device(std::fs::File),
+    #[cfg(feature = "serialport-backend")]
+    Port(Port),
+    Simulated(Simulator),
+    Replayed(Replay),
+}
+
+// How long a blocking read waits before handing control back so command timeouts can still fire
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+impl SerialStream {
+    /// Throws away anything received but not read yet. Reading it off instead would block until the next response
+    /// started arriving. hidraw devices can't be flushed but hand over whole reports anyway, and the stand-ins never
+    /// send anything that wasn't asked for.
+    pub fn discard_input(&mut self) -> std::io::Result<()> {
+        match self {
+            #[cfg(not(feature = "serialport-backend"))]
+            SerialStream::Device(file) => {
+                let fd = file.as_raw_fd();
+                if unsafe { libc::isatty(fd) } == 1 && unsafe { libc::tcflush(fd, libc::TCIFLUSH) } != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            }
+            #[cfg(feature = "serialport-backend")]
+            SerialStream::Device(_) => Ok(()),
+            #[cfg(feature = "serialport-backend")]
+            SerialStream::Port(port) => port.port.clear(serialport::ClearBuffer::Input).map_err(Into::into),
+            SerialStream::Simulated(_) | SerialStream::Replayed(_) => Ok(()),
+        }
+    }
+}
+
+impl AsyncRead for SerialStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            #[cfg(not(feature = "serialport-backend"))]
+            SerialStream::Device(file) => poll_read_device(file, cx, buf),
+            #[cfg(feature = "serialport-backend")]
+            SerialStream::Device(file) => Poll::Ready(tokio::task::block_in_place(|| file.read(buf))),
+            #[cfg(feature = "serialport-backend")]
+            SerialStream::Port(port) => Pin::new(port).poll_read(cx, buf),
+            SerialStream::Simulated(simulator) => Pin::new(simulator).poll_read(cx, buf),
+            SerialStream::Replayed(replay) => Pin::new(replay).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for SerialStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            SerialStream::Device(file) => Poll::Ready(tokio::task::block_in_place(|| file.write(buf))),
+            #[cfg(feature = "serialport-backend")]
+            SerialStream::Port(port) => Pin::new(port).poll_write(cx, buf),
+            SerialStream::Simulated(simulator) => Pin::new(simulator).poll_write(cx, buf),
+            SerialStream::Replayed(replay) => Pin::new(replay).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            SerialStream::Device(file) => Poll::Ready(tokio::task::block_in_place(|| file.flush())),
+            #[cfg(feature = "serialport-backend")]
+            SerialStream::Port(port) => Pin::new(port).poll_flush(cx),
+            SerialStream::Simulated(simulator) => Pin::new(simulator).poll_flush(cx),
+            SerialStream::Replayed(replay) => Pin::new(replay).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            SerialStream::Device(_) => Poll::Ready(Ok(())),
+            #[cfg(feature = "serialport-backend")]
+            SerialStream::Port(port) => Pin::new(port).poll_shutdown(cx),
+            SerialStream::Simulated(simulator) => Pin::new(simulator).poll_shutdown(cx),
+            SerialStream::Replayed(replay) => Pin::new(replay).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Waits up to `READ_TIMEOUT` for the device to have something to read, without leaving a read behind on a reader
+/// thread. One that outlived a timed out command would take the next command's response.
+#[cfg(not(feature = "serialport-backend"))]
+fn poll_read_device(file: &mut std::fs::File, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+    let mut pending = libc::pollfd { fd: file.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+    let ready = tokio::task::block_in_place(|| unsafe { libc::poll(&mut pending, 1, READ_TIMEOUT.as_millis() as libc::c_int) });
+    if ready < 0 {
+        let err = std::io::Error::last_os_error();
+        if err.kind() != std::io::ErrorKind::Interrupted {
+            return Poll::Ready(Err(err));
+        }
+    }
+    if ready <= 0 {
+        // Nothing arrived yet - ask to be polled again rather than holding the worker thread
+        cx.waker().wake_by_ref();
+        return Poll::Pending;
+    }
+    Poll::Ready(tokio::task::block_in_place(|| file.read(buf)))
+}
+
+/// Keeps the bytes read since the last write, i.e. the last response frame, so errors from masterpower_api
+/// that don't carry the frame can still be looked into
+pub struct Tapped {
+    stream: SerialStream,
+    last_response: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Tapped {
+    pub fn new(stream: SerialStream) -> Self {
+        Tapped {
+            stream,
+            last_response: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut SerialStream {
+        &mut self.stream
+    }
+
+    /// A handle onto the last response that stays valid after the stream is handed to the `Inverter`
+    pub fn last_response(&self) -> Arc<Mutex<Vec<u8>>> {
+        self.last_response.clone()
+    }
+}
+
+impl AsyncRead for Tapped {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+        let tapped = self.get_mut();
+        let result = Pin::new(&mut tapped.stream).poll_read(cx, buf);
+        if let Poll::Ready(Ok(read)) = result {
+            if let Ok(mut last_response) = tapped.last_response.lock() {
+                last_response.extend_from_slice(&buf[..read]);
+            }
+        }
+        result
+    }
+}
+
+impl AsyncWrite for Tapped {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let tapped = self.get_mut();
+        // A new request, so whatever comes back next is its response
+        if let Ok(mut last_response) = tapped.last_response.lock() {
+            last_response.clear();
+        }
+        Pin::new(&mut tapped.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+/// One stream handed to both masterpower_api and the raw protocol commands, so there's a single reader on the line.
+/// The owner task runs one command at a time, so the lock is never contended.
+pub struct Shared<S> {
+    stream: Arc<Mutex<S>>,
+}
+
+impl<S> Shared<S> {
+    pub fn new(stream: S) -> Self {
+        Shared { stream: Arc::new(Mutex::new(stream)) }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, S> {
+        self.stream.lock().unwrap()
+    }
+}
+
+impl<S> Clone for Shared<S> {
+    fn clone(&self) -> Self {
+        Shared { stream: self.stream.clone() }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Shared<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut *self.lock()).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Shared<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut *self.lock()).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.lock()).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.lock()).poll_shutdown(cx)
+    }
+}
+
+/// Running totals for the serial line, published to {topic}/serial_stats to help track down baud or wiring problems
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct SerialStats {
+    bytes_written: u64,
+    bytes_read: u64,
+    frames_ok: u64,
+    frames_crc_fail: u64,
+}
+
+/// Shared handle onto the serial totals, kept across reconnects so they count from startup
+#[derive(Clone, Default)]
+pub struct SerialCounters {
+    stats: Arc<Mutex<SerialStats>>,
+}
+
+impl SerialCounters {
+    pub fn frame_ok(&self) {
+        self.stats.lock().unwrap().frames_ok += 1;
+    }
+
+    pub fn frame_crc_fail(&self) {
+        self.stats.lock().unwrap().frames_crc_fail += 1;
+    }
+
+    pub fn snapshot(&self) -> SerialStats {
+        self.stats.lock().unwrap().clone()
+    }
+}
+
+/// Counts the bytes going through a stream into `SerialCounters`
+pub struct Counted<S> {
+    stream: S,
+    counters: SerialCounters,
+}
+
+impl<S> Counted<S> {
+    pub fn new(stream: S, counters: SerialCounters) -> Self {
+        Counted { stream, counters }
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+        let counted = self.get_mut();
+        let result = Pin::new(&mut counted.stream).poll_read(cx, buf);
+        if let Poll::Ready(Ok(read)) = result {
+            counted.counters.stats.lock().unwrap().bytes_read += read as u64;
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let counted = self.get_mut();
+        let result = Pin::new(&mut counted.stream).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            counted.counters.stats.lock().unwrap().bytes_written += written as u64;
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+/// Opens and configures the inverter device with the backend the build was made with
+#[cfg(not(feature = "serialport-backend"))]
+pub fn open_device(settings: &InverterSettings) -> std::io::Result<SerialStream> {
+    Ok(SerialStream::Device(raw_open(settings)?))
+}
+
+/// Opens and configures the inverter device with the backend the build was made with
+#[cfg(feature = "serialport-backend")]
+pub fn open_device(settings: &InverterSettings) -> std::io::Result<SerialStream> {
+    Ok(SerialStream::Port(Port::open(settings)?))
+}
+
+#[cfg(not(feature = "serialport-backend"))]
+pub fn raw_open(settings: &InverterSettings) -> std::io::Result<std::fs::File> {
+    let path = CString::new(Path::new(&settings.path).as_os_str().as_bytes()).map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    let mut flags = O_RDWR;
+    if settings.noctty {
+        flags |= O_NOCTTY;
+    }
+    if settings.nonblock {
+        flags |= O_NONBLOCK;
+    }
+    let fd = unsafe { open(path.as_ptr(), flags) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    // Owning the fd straight away means it's closed again if configuring the line fails
+    let std_file = unsafe { std::fs::File::from_raw_fd(fd) };
+    if settings.nonblock {
+        // O_NONBLOCK is only wanted for the open itself, reads run on tokio's blocking pool and expect to wait
+        clear_nonblock(std_file.as_raw_fd())?;
+    }
+    if settings.exclusive && unsafe { libc::isatty(fd) } == 1 && unsafe { libc::ioctl(fd, libc::TIOCEXCL) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    configure_line(std_file.as_raw_fd(), settings)?;
+    Ok(std_file)
+}
+
+#[cfg(not(feature = "serialport-backend"))]
+fn clear_nonblock(fd: RawFd) -> std::io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !O_NONBLOCK) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Puts a tty into raw mode with the configured framing. hidraw devices aren't ttys and are left as they are.
+#[cfg(not(feature = "serialport-backend"))]
+fn configure_line(fd: RawFd, settings: &InverterSettings) -> std::io::Result<()> {
+    if unsafe { libc::isatty(fd) } != 1 {
+        debug!("{} is not a tty - skipping line configuration", settings.path);
+        return Ok(());
+    }
+
+    let speed = baud_rate_to_speed(settings.baud_rate)?;
+    let mut tty: libc::termios = unsafe { std::mem::zeroed() };
+    if unsafe { libc::tcgetattr(fd, &mut tty) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    // No canonical processing, echo or signal characters - just bytes
+    unsafe { libc::cfmakeraw(&mut tty) };
+    if unsafe { libc::cfsetispeed(&mut tty, speed) } != 0 || unsafe { libc::cfsetospeed(&mut tty, speed) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    tty.c_cflag &= !(libc::CSIZE | libc::PARENB | libc::PARODD | libc::CSTOPB | libc::CRTSCTS);
+    tty.c_cflag |= libc::CLOCAL | libc::CREAD;
+    tty.c_cflag |= match settings.data_bits {
+        5 => libc::CS5,
+        6 => libc::CS6,
+        7 => libc::CS7,
+        _ => libc::CS8,
+    };
+    match settings.parity {
+        Parity::None => (),
+        Parity::Even => tty.c_cflag |= libc::PARENB,
+        Parity::Odd => tty.c_cflag |= libc::PARENB | libc::PARODD,
+    }
+    if settings.stop_bits == 2 {
+        tty.c_cflag |= libc::CSTOPB;
+    }
+    // Block until at least one byte is available
+    tty.c_cc[libc::VMIN] = 1;
+    tty.c_cc[libc::VTIME] = 0;
+
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &tty) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "serialport-backend"))]
+fn baud_rate_to_speed(baud_rate: u32) -> std::io::Result<libc::speed_t> {
+    match baud_rate {
+        1200 => Ok(libc::B1200),
+        2400 => Ok(libc::B2400),
+        4800 => Ok(libc::B4800),
+        9600 => Ok(libc::B9600),
+        19200 => Ok(libc::B19200),
+        38400 => Ok(libc::B38400),
+        57600 => Ok(libc::B57600),
+        115200 => Ok(libc::B115200),
+        _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("unsupported baud rate {}", baud_rate))),
+    }
+}
+
+/// A device opened with the serialport crate, which works on macOS and Windows too.
+/// Reads and writes block, so they run through `block_in_place` rather than on a reader thread that could outlive
+/// a timed out command.
+#[cfg(feature = "serialport-backend")]
+pub struct Port {
+    port: Box<dyn serialport::SerialPort>,
+}
+
+#[cfg(feature = "serialport-backend")]
+impl Port {
+    fn open(settings: &InverterSettings) -> std::io::Result<Self> {
+        let data_bits = match settings.data_bits {
+            5 => serialport::DataBits::Five,
+            6 => serialport::DataBits::Six,
+            7 => serialport::DataBits::Seven,
+            _ => serialport::DataBits::Eight,
+        };
+        let parity = match settings.parity {
+            Parity::None => serialport::Parity::None,
+            Parity::Even => serialport::Parity::Even,
+            Parity::Odd => serialport::Parity::Odd,
+        };
+        let stop_bits = if settings.stop_bits == 2 { serialport::StopBits::Two } else { serialport::StopBits::One };
+        let port = serialport::new(&settings.path, settings.baud_rate).data_bits(data_bits).parity(parity).stop_bits(stop_bits).timeout(READ_TIMEOUT).open()?;
+        Ok(Port { port })
+    }
+}
+
+#[cfg(feature = "serialport-backend")]
+impl AsyncRead for Port {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+        let port = &mut self.get_mut().port;
+        match tokio::task::block_in_place(|| port.read(buf)) {
+            // Nothing arrived yet - ask to be polled again rather than holding the worker thread
+            Err(err) if err.kind() == std::io::ErrorKind::TimedOut => {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+            result => Poll::Ready(result),
+        }
+    }
+}
+
+#[cfg(feature = "serialport-backend")]
+impl AsyncWrite for Port {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let port = &mut self.get_mut().port;
+        Poll::Ready(tokio::task::block_in_place(|| port.write(buf)))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let port = &mut self.get_mut().port;
+        Poll::Ready(tokio::task::block_in_place(|| port.flush()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
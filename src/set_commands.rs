@@ -0,0 +1,318 @@
+use crate::commands::local_time_for_dat;
+use crate::error::MpqttError;
+use crate::publish_update;
+use crate::publisher::Publisher;
+use crate::queue::{CommandQueue, Query};
+use crate::settings::Settings;
+
+use log::{error, info, warn};
+use mqtt_async_client::client::{Client, QoS, Subscribe, SubscribeTopic};
+use serde_derive::Serialize;
+use tokio::sync::mpsc;
+
+/// A write requested over `{topic}/set/<command>`
+#[derive(Debug)]
+pub struct SetRequest {
+    pub command: String,
+    pub payload: String,
+}
+
+#[derive(Serialize, Debug)]
+struct SetResult {
+    command: String,
+    payload: String,
+    success: bool,
+    response: String,
+}
+
+// Reported on {topic}/set/<command>/confirmed when confirm_writes reads a write back
+#[derive(Serialize, Debug)]
+struct Confirmed {
+    command: String,
+    payload: String,
+    applied: bool,
+    // The field as the verification query read it back, null if it couldn't be read
+    value: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+struct SetCommand {
+    name: &'static str,
+    // Validates the payload and builds the raw inverter command
+    encode: fn(&str) -> Result<String, String>,
+}
+
+const OUTPUT_SOURCES: &[&str] = &["utility", "solar", "sbu"];
+const CHARGER_SOURCES: &[&str] = &["utility", "solar", "solar_utility", "solar_only"];
+
+const SET_COMMANDS: &[SetCommand] = &[
+    SetCommand {
+        name: "output_source_priority",
+        encode: |payload| Ok(format!("POP{:02}", choice(payload, OUTPUT_SOURCES)?)),
+    },
+    SetCommand {
+        name: "charger_source_priority",
+        encode: |payload| Ok(format!("PCP{:02}", choice(payload, CHARGER_SOURCES)?)),
+    },
+    SetCommand {
+        name: "max_charging_current",
+        encode: |payload| Ok(format!("MCHGC0{:02}", integer(payload, 1, 99)?)),
+    },
+    SetCommand {
+        name: "max_ac_charging_current",
+        encode: |payload| Ok(format!("MUCHGC0{:02}", integer(payload, 1, 99)?)),
+    },
+    SetCommand {
+        name: "battery_recharge_voltage",
+        encode: |payload| Ok(format!("PBCV{:04.1}", voltage(payload)?)),
+    },
+    SetCommand {
+        name: "battery_redischarge_voltage",
+        encode: |payload| Ok(format!("PBDV{:04.1}", voltage(payload)?)),
+    },
+    SetCommand {
+        name: "battery_bulk_voltage",
+        encode: |payload| Ok(format!("PCVV{:04.1}", voltage(payload)?)),
+    },
+    SetCommand {
+        name: "battery_float_voltage",
+        encode: |payload| Ok(format!("PBFT{:04.1}", voltage(payload)?)),
+    },
+    SetCommand {
+        name: "qt",
+        encode: |payload| match payload.trim() {
+            "" | "now" => Ok(format!("DAT{}", local_time_for_dat())),
+            _ => Err(String::from("expected \"now\" to sync the inverter clock to the system clock")),
+        },
+    },
+];
+
+// How a write is read back - the query to ask and the field of its response that should then hold the new value.
+// The clock has nothing to compare against so qt isn't confirmed.
+struct Confirmation {
+    command: &'static str,
+    query: &'static str,
+    field: &'static str,
+    // For choices, the option names firmware may report in place of the index
+    options: &'static [&'static str],
+}
+
+const CONFIRMATIONS: &[Confirmation] = &[
+    Confirmation {
+        command: "output_source_priority",
+        query: "qpiri",
+        field: "output_source_priority",
+        options: OUTPUT_SOURCES,
+    },
+    Confirmation {
+        command: "charger_source_priority",
+        query: "qpiri",
+        field: "charge_source_priority",
+        options: CHARGER_SOURCES,
+    },
+    Confirmation {
+        command: "max_charging_current",
+        query: "qpiri",
+        field: "max_charging_current",
+        options: &[],
+    },
+    Confirmation {
+        command: "max_ac_charging_current",
+        query: "qpiri",
+        field: "max_ac_charging_current",
+        options: &[],
+    },
+    Confirmation {
+        command: "battery_recharge_voltage",
+        query: "qpiri",
+        field: "battery_recharge_voltage",
+        options: &[],
+    },
+    Confirmation {
+        command: "battery_redischarge_voltage",
+        query: "qpiri",
+        field: "battery_redischarge_voltage",
+        options: &[],
+    },
+    Confirmation {
+        command: "battery_bulk_voltage",
+        query: "qpiri",
+        field: "battery_bulk_voltage",
+        options: &[],
+    },
+    Confirmation {
+        command: "battery_float_voltage",
+        query: "qpiri",
+        field: "battery_float_voltage",
+        options: &[],
+    },
+];
+
+impl Confirmation {
+    // Whether the read back value is what the raw write set. The value is the number after the command's letters,
+    // e.g. 30 for MCHGC030 or the option index 1 for POP01.
+    fn applied(&self, raw: &str, value: &serde_json::Value) -> bool {
+        let expected = match raw.trim_start_matches(|c: char| c.is_ascii_alphabetic()).parse::<f64>() {
+            Ok(expected) => expected,
+            Err(_) => return false,
+        };
+        let actual = match value {
+            serde_json::Value::Number(number) => number.as_f64(),
+            serde_json::Value::String(text) => text.trim().parse().ok().or_else(|| option_index(text, self.options).map(|index| index as f64)),
+            _ => None,
+        };
+        actual.map_or(false, |actual| (actual - expected).abs() < 0.05)
+    }
+}
+
+// The option a firmware's name for it refers to, e.g. SolarAndUtility is solar_utility - the option with the most of
+// its words in the name, the one with fewer words on a tie
+fn option_index(name: &str, options: &[&str]) -> Option<usize> {
+    let name = name.to_ascii_lowercase();
+    options
+        .iter()
+        .enumerate()
+        .map(|(index, option)| (index, option.split('_').filter(|word| name.contains(word)).count(), option.split('_').count()))
+        .filter(|(_, matched, _)| *matched > 0)
+        .max_by(|a, b| a.1.cmp(&b.1).then(b.2.cmp(&a.2)))
+        .map(|(index, _, _)| index)
+}
+
+// Accepts either the option name or its index
+fn choice(payload: &str, options: &[&str]) -> Result<usize, String> {
+    let payload = payload.trim().to_ascii_lowercase();
+    match options.iter().position(|option| *option == payload) {
+        Some(index) => Ok(index),
+        None => match payload.parse::<usize>() {
+            Ok(index) if index < options.len() => Ok(index),
+            _ => Err(format!("expected one of {:?} or 0-{}", options, options.len() - 1)),
+        },
+    }
+}
+
+fn integer(payload: &str, min: u32, max: u32) -> Result<u32, String> {
+    match payload.trim().parse::<u32>() {
+        Ok(value) if (min..=max).contains(&value) => Ok(value),
+        _ => Err(format!("expected a whole number between {} and {}", min, max)),
+    }
+}
+
+fn voltage(payload: &str) -> Result<f32, String> {
+    match payload.trim().parse::<f32>() {
+        Ok(value) if (10.0..=65.0).contains(&value) => Ok(value),
+        _ => Err(String::from("expected a voltage between 10.0 and 65.0")),
+    }
+}
+
+/// Subscribes to `{topic}/set/+` on a dedicated client and forwards requests to the poll loop
+pub async fn spawn_listener(mut client: Client, topic: String, mut sender: mpsc::Sender<SetRequest>) -> Result<(), MpqttError> {
+    client.connect().await?;
+    let prefix = format!("{}/set/", topic);
+    let subscription = Subscribe::new(vec![SubscribeTopic {
+        qos: QoS::AtLeastOnce,
+        topic_path: format!("{}+", prefix),
+    }]);
+    client.subscribe(subscription).await?.any_failures()?;
+    info!("Listening for write commands on {}+", prefix);
+
+    tokio::spawn(async move {
+        loop {
+            match client.read_subscriptions().await {
+                Ok(message) => {
+                    let command = message.topic().trim_start_matches(&prefix).to_string();
+                    let payload = String::from_utf8_lossy(message.payload()).to_string();
+                    if sender.send(SetRequest { command, payload }).await.is_err() {
+                        break;
+                    }
+                }
+                Err(err) => error!("Error reading write commands: {}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Validates requested writes and hands them to the command queue, which keeps them from interleaving with polls
+pub struct SetHandler {
+    receiver: mpsc::Receiver<SetRequest>,
+}
+
+impl SetHandler {
+    pub fn new(receiver: mpsc::Receiver<SetRequest>) -> Self {
+        SetHandler { receiver }
+    }
+
+    pub async fn process(&mut self, queue: &CommandQueue, mqtt_client: &Publisher, settings: &Settings) -> Result<(), MpqttError> {
+        let mqtt = &settings.mqtt;
+        while let Ok(request) = self.receiver.try_recv() {
+            let (success, response, written) = match SET_COMMANDS.iter().find(|command| command.name == request.command) {
+                None => (false, format!("unknown command {}", request.command), None),
+                Some(command) => match (command.encode)(&request.payload) {
+                    Err(invalid) => (false, format!("invalid value {:?}: {}", request.payload, invalid), None),
+                    Ok(raw) => {
+                        info!("Setting {} to {} ({})", request.command, request.payload, raw);
+                        match queue.execute(Query::Raw(raw.clone())).await {
+                            Ok(response) => (response == "ACK", response, Some(raw)),
+                            Err(err) => (false, err.to_string(), None),
+                        }
+                    }
+                },
+            };
+            if !success {
+                warn!("Failed to set {}: {}", request.command, response);
+            }
+            let result = SetResult {
+                command: request.command.clone(),
+                payload: request.payload,
+                success,
+                response,
+            };
+            publish_update(mqtt_client, mqtt, &format!("set/{}/result", request.command), serde_json::to_string(&result)?).await?;
+
+            // Some firmware ACKs a write it then ignores, so read it back when asked to
+            let confirmation = CONFIRMATIONS.iter().find(|confirmation| confirmation.command == request.command);
+            if let (true, true, Some(raw), Some(confirmation)) = (settings.confirm_writes, success, written, confirmation) {
+                let confirmed = confirm(queue, settings, confirmation, &raw, result.payload).await;
+                if !confirmed.applied {
+                    warn!("{} was acknowledged but reads back as {} - the inverter may have ignored it", request.command, confirmed.value);
+                }
+                publish_update(mqtt_client, mqtt, &format!("set/{}/confirmed", request.command), serde_json::to_string(&confirmed)?).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Runs the write's verification query and compares the field it should have changed
+async fn confirm(queue: &CommandQueue, settings: &Settings, confirmation: &Confirmation, raw: &str, payload: String) -> Confirmed {
+    let mut confirmed = Confirmed {
+        command: confirmation.command.to_string(),
+        payload,
+        applied: false,
+        value: serde_json::Value::Null,
+        error: None,
+    };
+    let query = match Query::from_name(confirmation.query, settings.mode) {
+        Some(query) => query,
+        None => {
+            confirmed.error = Some(format!("unknown query {}", confirmation.query));
+            return confirmed;
+        }
+    };
+    let response = match queue.execute(query).await {
+        Ok(response) => response,
+        Err(err) => {
+            confirmed.error = Some(format!("{}: {}", confirmation.query, err));
+            return confirmed;
+        }
+    };
+    match serde_json::from_str::<serde_json::Value>(&response).ok().and_then(|response| response.get(confirmation.field).cloned()) {
+        Some(value) => {
+            confirmed.applied = confirmation.applied(raw, &value);
+            confirmed.value = value;
+        }
+        None => confirmed.error = Some(format!("{} has no {}", confirmation.query, confirmation.field)),
+    }
+    confirmed
+}
@@ -1,5 +1,19 @@
-use config::{Config, ConfigError, File};
-use serde_derive::Deserialize;
+use crate::derived;
+use crate::queue::MAX_QPGS_INDEX;
+use crate::tls;
+use config::{Config, ConfigError, Environment, File};
+use mqtt_async_client::client::QoS;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+const LOG_LEVELS: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+
+// Commands that can be listed in disabled_commands, qpgs covers every parallel unit
+const POLLED_COMMANDS: [&str; 12] = ["qid", "qpi", "qvfw", "qmn", "qmod", "qpiws", "qpiri", "qpigs", "qpigs2", "qpgs", "qflag", "qt"];
+
+const SUPPORTED_BAUD_RATES: [u32; 8] = [1200, 2400, 4800, 9600, 19200, 38400, 57600, 115200];
 
 #[cfg(not(feature = "build-for-deb"))]
 const CONFIG_PATH: &'static str = "config.yaml";
@@ -7,49 +21,1206 @@ const CONFIG_PATH: &'static str = "config.yaml";
 #[cfg(feature = "build-for-deb")]
 const CONFIG_PATH: &'static str = "/etc/mpqtt/config.yaml";
 
-#[derive(Debug, Deserialize)]
+// The config file can be YAML, TOML or JSON, picked by its extension
+const CONFIG_EXTENSIONS: [&str; 4] = ["yaml", "yml", "toml", "json"];
+
+#[cfg(not(feature = "build-for-deb"))]
+const SIMULATION_PATH: &'static str = "simulation.yaml";
+
+#[cfg(feature = "build-for-deb")]
+const SIMULATION_PATH: &'static str = "/etc/mpqtt/simulation.yaml";
+
+#[derive(Debug)]
+pub enum SettingsError {
+    // The file couldn't be read or doesn't match the expected structure
+    Load(ConfigError),
+    // The file parsed but a value is out of range
+    Invalid { field: String, message: String },
+}
+
+impl SettingsError {
+    fn invalid<M: Into<String>>(field: &str, message: M) -> Self {
+        SettingsError::Invalid { field: field.to_string(), message: message.into() }
+    }
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingsError::Load(err) => write!(f, "could not be loaded: {}", err),
+            SettingsError::Invalid { field, message } => write!(f, "{} {}", field, message),
+        }
+    }
+}
+
+impl std::error::Error for SettingsError {}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+/// The protocol family the inverter speaks. Unknown names fail to load with the list of valid ones.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    Standard,
+    // Reduced QPIRI and parallel units over QPGS, no QPIGS or QFLAG
+    Phocos,
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mode::Standard => write!(f, "standard"),
+            Mode::Phocos => write!(f, "phocos"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    // Timestamped, coloured output from pretty_env_logger
+    Pretty,
+    // One JSON object per line for log collectors such as Loki
+    Json,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorFormat {
+    // `<command>: <message>`, or just the message when no command was involved
+    Text,
+    // {"command": ..., "message": ..., "timestamp": ...}
+    Json,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum ClearError {
+    // Leave the last error in place
+    None,
+    // An empty payload
+    OnSuccess,
+    // {"cleared": true}
+    Structured,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PublishMode {
+    // Every command on its own topic
+    Individual,
+    // Everything from an update cycle in one JSON object on {topic}/bundle
+    Bundle,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum TopicCase {
+    Lower,
+    Upper,
+    AsIs,
+}
+
+#[derive(Debug, Deserialize, Clone)]
 pub struct InverterSettings {
+    // Required when more than one inverter is configured, used to namespace its topics
+    pub id: Option<String>,
     pub path: String,
+    // Line settings only apply to tty devices, hidraw devices ignore them
+    #[serde(default = "default_baud_rate")]
+    pub baud_rate: u32,
+    #[serde(default = "default_data_bits")]
+    pub data_bits: u8,
+    #[serde(default = "default_parity")]
+    pub parity: Parity,
+    #[serde(default = "default_stop_bits")]
+    pub stop_bits: u8,
+    // Open with O_NOCTTY so the device never becomes our controlling terminal
+    #[serde(default = "default_noctty")]
+    pub noctty: bool,
+    // Open with O_NONBLOCK so open doesn't hang waiting for carrier detect, reads block again once it's open
+    #[serde(default)]
+    pub nonblock: bool,
+    // Take exclusive access (TIOCEXCL) so nothing else can open the tty while we hold it
+    #[serde(default)]
+    pub exclusive: bool,
+    #[serde(default = "default_open_retry_delay")]
+    pub open_retry_delay: u64,
+    #[serde(default = "default_open_retry_attempts")]
+    pub open_retry_attempts: u32,
+    // Seconds to wait after opening the device before init, for firmware that isn't ready as soon as the device appears
+    #[serde(default)]
+    pub startup_delay_secs: u64,
+    // Times the device is reopened after the serial line fails before giving up, reset once it initialises again
+    #[serde(default = "default_max_reconnect_attempts")]
+    pub max_reconnect_attempts: u32,
+}
+
+impl InverterSettings {
+    fn validate(&self, field: &str, simulate: bool) -> Result<(), SettingsError> {
+        if !simulate {
+            // The device node itself can show up late (USB enumeration) which open_with_retry deals with,
+            // but a directory that doesn't exist means the path is wrong
+            match Path::new(&self.path).parent() {
+                Some(parent) if !self.path.is_empty() && parent.is_dir() => (),
+                _ => return Err(SettingsError::invalid(&format!("{}.path", field), format!("{:?} is not in an existing directory", self.path))),
+            }
+        }
+        if !SUPPORTED_BAUD_RATES.contains(&self.baud_rate) {
+            return Err(SettingsError::invalid(&format!("{}.baud_rate", field), format!("must be one of {:?} (got {})", SUPPORTED_BAUD_RATES, self.baud_rate)));
+        }
+        if !(5..=8).contains(&self.data_bits) {
+            return Err(SettingsError::invalid(&format!("{}.data_bits", field), format!("must be between 5 and 8 (got {})", self.data_bits)));
+        }
+        if self.stop_bits != 1 && self.stop_bits != 2 {
+            return Err(SettingsError::invalid(&format!("{}.stop_bits", field), format!("must be 1 or 2 (got {})", self.stop_bits)));
+        }
+        if self.open_retry_attempts == 0 {
+            return Err(SettingsError::invalid(&format!("{}.open_retry_attempts", field), "must be at least 1"));
+        }
+        Ok(())
+    }
 }
 
-#[derive(Debug, Deserialize)]
+/// `inverter` is either a single mapping or a list of them, one per serial port
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Inverters {
+    Single(InverterSettings),
+    Multiple(Vec<InverterSettings>),
+}
+
+#[derive(Debug, Deserialize, Clone)]
 pub struct MqttDiscovery {
     pub prefix: String,
     pub node_name: String,
     pub device_name: String,
+    // "auto" uses the serial number QID reports at init, or client_id if it doesn't answer
     pub device_id: String,
+    // Set to false when the entities are configured in Home Assistant by hand
+    #[serde(default = "default_discovery_enabled")]
+    pub enabled: bool,
+    // Retained configs survive a broker restart, otherwise entities disappear until MPQTT restarts
+    #[serde(default = "default_discovery_retain")]
+    pub retain: bool,
+    // Publish empty configs on shutdown so Home Assistant removes the entities
+    #[serde(default)]
+    pub clear_on_exit: bool,
+    // Name of each parallel unit's device, {index} is replaced with its QPGS index
+    #[serde(default = "default_unit_name_template")]
+    pub unit_name_template: String,
+}
+
+impl MqttDiscovery {
+    pub fn unit_name(&self, index: u8) -> String {
+        self.unit_name_template.replace("{index}", &index.to_string())
+    }
+
+    /// Whether the device is identified by the inverter's serial number, which holds discovery back until init
+    pub fn auto_device_id(&self) -> bool {
+        self.device_id == "auto"
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct PublishOverride {
+    pub qos: Option<u8>,
+    pub retain: Option<bool>,
+    // Only these top level keys of the response are published, everything is when not set
+    pub fields: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct PublishSettings {
+    #[serde(default = "default_qos")]
+    pub qos: u8,
+    #[serde(default)]
+    pub retain: bool,
+    // Per-command overrides keyed by the topic suffix (qpigs, qid, error...)
+    #[serde(default)]
+    pub commands: HashMap<String, PublishOverride>,
+}
+
+impl Default for PublishSettings {
+    fn default() -> Self {
+        PublishSettings {
+            qos: default_qos(),
+            retain: false,
+            commands: HashMap::new(),
+        }
+    }
+}
+
+impl PublishSettings {
+    /// Resolves the QoS and retain flag for a command, falling back to the section defaults. Sub-topics like
+    /// qpigs/battery_soc take their command's override unless they have their own. QoS is set per message, so
+    /// topics at 0 and 1 mix freely on the one connection.
+    pub fn resolve(&self, command: &str) -> (QoS, bool) {
+        let over = self.commands.get(command).or_else(|| command.split('/').next().and_then(|parent| self.commands.get(parent)));
+        let (qos, retain) = match over {
+            Some(over) => (over.qos.unwrap_or(self.qos), over.retain.unwrap_or(self.retain)),
+            None => (self.qos, self.retain),
+        };
+        // Validated to be 0 or 1 when the settings were loaded
+        (qos_from_u8(qos).unwrap_or(QoS::AtLeastOnce), retain)
+    }
+
+    /// The keys a command's JSON is cut down to before publishing, if it has an allowlist
+    pub fn fields(&self, command: &str) -> Option<&[String]> {
+        self.commands.get(command).and_then(|over| over.fields.as_deref())
+    }
+
+    fn validate(&self) -> Result<(), SettingsError> {
+        validate_qos("mqtt.publish.qos", self.qos)?;
+        for (command, over) in &self.commands {
+            if let Some(qos) = over.qos {
+                validate_qos(&format!("mqtt.publish.commands.{}.qos", command), qos)?;
+            }
+            if over.fields.as_ref().map_or(false, Vec::is_empty) {
+                return Err(SettingsError::invalid(&format!("mqtt.publish.commands.{}.fields", command), "must list at least one field, or be left out to publish them all"));
+            }
+        }
+        Ok(())
+    }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
+pub struct AvailabilitySettings {
+    #[serde(default = "default_payload_online")]
+    pub payload_online: String,
+    #[serde(default = "default_payload_offline")]
+    pub payload_offline: String,
+}
+
+impl Default for AvailabilitySettings {
+    fn default() -> Self {
+        AvailabilitySettings {
+            payload_online: default_payload_online(),
+            payload_offline: default_payload_offline(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
 pub struct MqttSettings {
     pub host: String,
-    pub port: u16,
+    // Defaults to 1883, or 8883 with tls
+    pub port: Option<u16>,
     pub username: String,
     pub password: String,
     pub client_id: String,
+    // Appended to client_id as -<suffix> so copies of one config don't kick each other off the broker:
+    // auto for random hex picked at startup, hostname, none, or any other text to use as is
+    #[serde(default = "default_client_id_suffix")]
+    pub client_id_suffix: String,
     pub topic: String,
+    // Joins the segments after topic, e.g. qpigs/battery_soc
+    #[serde(default = "default_topic_separator")]
+    pub topic_separator: String,
+    // Casing of the segments after topic, topic itself is left as configured
+    #[serde(default = "default_topic_case")]
+    pub topic_case: TopicCase,
     pub discovery: MqttDiscovery,
+    #[serde(default)]
+    pub publish: PublishSettings,
+    #[serde(default = "default_publish_retries")]
+    pub publish_retries: u32,
+    #[serde(default = "default_publish_backoff_ms")]
+    pub publish_backoff_ms: u64,
+    // Defaults to {topic}/availability when not set
+    pub availability_topic: Option<String>,
+    #[serde(default)]
+    pub availability: AvailabilitySettings,
+    #[serde(default = "default_error_format")]
+    pub error_format: ErrorFormat,
+    // What {topic}/error gets once updates succeed again
+    #[serde(default = "default_clear_error")]
+    pub clear_error: ClearError,
+    #[serde(default = "default_keep_alive_secs")]
+    pub keep_alive_secs: u16,
+    // How long a publish or connect may take, must be longer than keep_alive_secs
+    #[serde(default = "default_operation_timeout_secs")]
+    pub operation_timeout_secs: u64,
+    #[serde(default = "default_connect_retry_delay_secs")]
+    pub connect_retry_delay_secs: u64,
+    // How long to keep retrying the first connect while the broker isn't up yet, 0 fails straight away
+    #[serde(default = "default_connect_give_up_secs")]
+    pub connect_give_up_secs: u64,
+    // How long to keep retrying a publish while reconnecting once publish_retries are used up, 0 fails straight away
+    #[serde(default = "default_reconnect_give_up_secs")]
+    pub reconnect_give_up_secs: u64,
+    // Deprecated - also publish stats to the old inner_stats and outer_stats topics, to be removed next release
+    #[serde(default = "default_legacy_stats_topics")]
+    pub legacy_stats_topics: bool,
+    // Publish command responses and stats as {"ts": <unix ms>, "data": <response>}
+    #[serde(default)]
+    pub timestamp_payloads: bool,
+    // Send the last value of every topic again once publishing recovers, e.g. after a broker restart
+    #[serde(default)]
+    pub republish_on_reconnect: bool,
+    // Bundle collects each update cycle's readings into one publish for constrained links
+    #[serde(default = "default_publish_mode")]
+    pub publish_mode: PublishMode,
+    // Gzip the bundle and publish it to {topic}/bundle/gzip instead, needs a build with --features gzip
+    #[serde(default)]
+    pub bundle_gzip: bool,
+    // Send each update's publishes from a queue while the next command is on the serial line, rather than waiting
+    // for every PUBACK before asking the inverter for more
+    #[serde(default)]
+    pub pipeline_publishes: bool,
+    // MQTT v5 message expiry for retained state, 0 for none. mqtt-async-client only speaks v3.1.1 so this
+    // can't be sent yet and is ignored with a warning.
+    #[serde(default)]
+    pub message_expiry_secs: u32,
+    // Publish a retained snapshot of the version and poll config to {topic}/info on connect
+    #[serde(default = "default_publish_info")]
+    pub publish_info: bool,
+    #[serde(default)]
+    pub tls: bool,
+    // PEM file the broker's certificate is checked against, the bundled web roots are used when not set.
+    // This and the client certificate and key can also be the PEM itself, starting with -----BEGIN
+    pub ca_cert: Option<String>,
+    // PEM certificate chain and key for brokers that authenticate clients, both or neither
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    // ALPN protocols offered in the handshake, e.g. x-amzn-mqtt-ca for AWS IoT on port 443
+    #[serde(default)]
+    pub alpn_protocols: Vec<String>,
+    // Name the broker's certificate is checked against instead of host, the handshake still sends host as its SNI
+    pub tls_server_name: Option<String>,
+    // Accept any broker certificate - only for testing against self signed brokers
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+}
+
+impl MqttSettings {
+    pub fn port(&self) -> u16 {
+        match self.port {
+            Some(port) => port,
+            None if self.tls => 8883,
+            None => 1883,
+        }
+    }
+
+    /// The topic a command's state is published to. Discovery builds its state_topic with this too so the two can't drift apart.
+    pub fn state_topic(&self, command: &str) -> String {
+        let segments: Vec<String> = command
+            .split('/')
+            .map(|segment| match self.topic_case {
+                TopicCase::Lower => segment.to_lowercase(),
+                TopicCase::Upper => segment.to_uppercase(),
+                TopicCase::AsIs => segment.to_string(),
+            })
+            .collect();
+        format!("{}{}{}", self.topic, self.topic_separator, segments.join(&self.topic_separator))
+    }
+
+    pub fn availability_topic(&self) -> String {
+        match self.availability_topic {
+            Some(ref topic) => topic.clone(),
+            None => self.state_topic("availability"),
+        }
+    }
+
+    fn validate(&self) -> Result<(), SettingsError> {
+        if self.keep_alive_secs == 0 {
+            return Err(SettingsError::invalid("mqtt.keep_alive_secs", "must be greater than 0"));
+        }
+        if u64::from(self.keep_alive_secs) >= self.operation_timeout_secs {
+            return Err(SettingsError::invalid("mqtt.operation_timeout_secs", format!("must be longer than mqtt.keep_alive_secs (got {} and {})", self.operation_timeout_secs, self.keep_alive_secs)));
+        }
+        if self.connect_retry_delay_secs == 0 {
+            return Err(SettingsError::invalid("mqtt.connect_retry_delay_secs", "must be greater than 0"));
+        }
+        if self.topic_separator.is_empty() || self.topic_separator.contains(|c| c == '+' || c == '#') {
+            return Err(SettingsError::invalid("mqtt.topic_separator", format!("must be set and can't contain the + or # wildcards (got {:?})", self.topic_separator)));
+        }
+        if self.bundle_gzip && !cfg!(feature = "gzip") {
+            return Err(SettingsError::invalid("mqtt.bundle_gzip", "needs a build with --features gzip"));
+        }
+        if self.client_cert.is_some() != self.client_key.is_some() {
+            return Err(SettingsError::invalid("mqtt.client_cert", "and mqtt.client_key must be set together"));
+        }
+        for (field, path) in &[("mqtt.ca_cert", &self.ca_cert), ("mqtt.client_cert", &self.client_cert), ("mqtt.client_key", &self.client_key)] {
+            if let Some(path) = path.as_ref().filter(|value| !tls::is_inline(value)) {
+                if let Err(err) = std::fs::File::open(path) {
+                    return Err(SettingsError::invalid(field, format!("{:?} could not be read: {}", path, err)));
+                }
+            }
+        }
+        if let Some(protocol) = self.alpn_protocols.iter().find(|protocol| protocol.is_empty() || protocol.len() > 255) {
+            return Err(SettingsError::invalid("mqtt.alpn_protocols", format!("each protocol must be 1-255 bytes (got {:?})", protocol)));
+        }
+        if self.tls {
+            if let Err(err) = tls::client_config(self) {
+                return Err(SettingsError::invalid("mqtt.tls", err.to_string()));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MetricsSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_metrics_bind")]
+    pub bind: String,
+    #[serde(default = "default_metrics_port")]
+    pub port: u16,
+}
+
+impl Default for MetricsSettings {
+    fn default() -> Self {
+        MetricsSettings {
+            enabled: false,
+            bind: default_metrics_bind(),
+            port: default_metrics_port(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct InfluxSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_influx_url")]
+    pub url: String,
+    #[serde(default)]
+    pub token: String,
+    #[serde(default)]
+    pub org: String,
+    #[serde(default)]
+    pub bucket: String,
+}
+
+impl Default for InfluxSettings {
+    fn default() -> Self {
+        InfluxSettings {
+            enabled: false,
+            url: default_influx_url(),
+            token: String::new(),
+            org: String::new(),
+            bucket: String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct HttpSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_http_bind")]
+    pub bind: String,
+    #[serde(default = "default_http_port")]
+    pub port: u16,
 }
 
-#[derive(Debug, Deserialize)]
+impl Default for HttpSettings {
+    fn default() -> Self {
+        HttpSettings {
+            enabled: false,
+            bind: default_http_bind(),
+            port: default_http_port(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct DerivedSensorSettings {
+    // Topic suffix and discovery id
+    pub name: String,
+    // Arithmetic over command.field references, e.g. qpigs.battery_voltage * qpigs.battery_charge_current
+    pub expression: String,
+    #[serde(default)]
+    pub unit: Option<String>,
+    #[serde(default)]
+    pub device_class: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SmoothingSettings {
+    // Number of readings averaged
+    #[serde(default = "default_smoothing_window")]
+    pub window: usize,
+    // QPIGS fields published averaged to {topic}/qpigs_smoothed, nothing is published when empty
+    #[serde(default)]
+    pub fields: Vec<String>,
+}
+
+impl Default for SmoothingSettings {
+    fn default() -> Self {
+        SmoothingSettings { window: default_smoothing_window(), fields: Vec::new() }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SanityRange {
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SanitySettings {
+    // Check the common QPIGS fields against built in ranges where none are configured
+    #[serde(default = "default_sanity_builtin")]
+    pub builtin: bool,
+    // Publish the dropped values to {topic}/rejected as well as logging them
+    #[serde(default)]
+    pub publish_rejected: bool,
+    // Ranges by command then field, e.g. qpigs: {battery_voltage: {min: 40, max: 60}}
+    #[serde(default)]
+    pub ranges: HashMap<String, HashMap<String, SanityRange>>,
+}
+
+impl Default for SanitySettings {
+    fn default() -> Self {
+        SanitySettings {
+            builtin: default_sanity_builtin(),
+            publish_rejected: false,
+            ranges: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
 pub struct Settings {
     pub debug: bool,
+    // Level for mpqtt and masterpower_api, overrides debug
+    pub log_level: Option<String>,
+    // Full env_logger filter string, overrides log_level and debug
+    pub log_filter: Option<String>,
+    #[serde(default = "default_log_format")]
+    pub log_format: LogFormat,
     pub outer_delay: u64,
     pub inner_delay: u64,
     pub error_delay: u64,
+    // error_delay doubles while errors persist, up to this many seconds
+    #[serde(default = "default_max_error_delay")]
+    pub max_error_delay: u64,
     pub inverter_count: u8,
+    // Parallel units to poll when they aren't numbered 1 to inverter_count, e.g. [1, 3, 5]
+    pub qpgs_indices: Option<Vec<u8>>,
     pub inner_iterations: u64,
-    pub inverter: InverterSettings,
+    pub inverter: Inverters,
     pub mqtt: MqttSettings,
-    pub mode: String,
+    // Overridden after init when QMN reports a known model
+    pub mode: Mode,
+    // Minimum seconds between polls per command, anything not listed uses default_interval
+    #[serde(default)]
+    pub intervals: HashMap<String, u64>,
+    #[serde(default)]
+    pub default_interval: u64,
+    // Poll QPIGS2 for units with a second PV tracker
+    #[serde(default)]
+    pub has_second_pv: bool,
+    // Commands the inverter doesn't support, never sent and left out of discovery
+    #[serde(default)]
+    pub disabled_commands: Vec<String>,
+    // Go straight to the update loop without asking for the serial number, protocol and firmware versions
+    #[serde(default)]
+    pub skip_init: bool,
+    // Seconds between asking the init queries again so their values stay populated without retain, 0 only asks on startup
+    #[serde(default)]
+    pub init_refresh_secs: u64,
+    // Randomly stretch or shrink inner_delay and outer_delay by up to this percentage so bridges sharing a broker drift apart, 0 disables
+    #[serde(default)]
+    pub poll_jitter_percent: u8,
+    // Seed for the jitter, derived from mqtt.client_id when not set
+    #[serde(default)]
+    pub poll_jitter_seed: Option<u64>,
+    // Publish each response's bytes as hex to {topic}/raw/<command> before they're parsed
+    #[serde(default)]
+    pub publish_raw: bool,
+    // Run init and a single update then exit, for cron snapshots
+    #[serde(default)]
+    pub run_once: bool,
+    // Seconds without a successful update before {topic}/stale is set, 0 disables the marker
+    #[serde(default = "default_stale_threshold_secs")]
+    pub stale_threshold_secs: u64,
+    // Seconds without a successful update before the serial device is closed and reopened, 0 disables it
+    #[serde(default)]
+    pub serial_watchdog_secs: u64,
+    // Seconds between "still failing" publishes while the same error keeps recurring
+    #[serde(default = "default_error_summary_interval")]
+    pub error_summary_interval: u64,
+    // Also publish every QPGS response from an iteration together to {topic}/qpgs_all, keyed by index
+    #[serde(default)]
+    pub aggregate_qpgs: bool,
+    // Publish the milliseconds each command took over an update cycle to {topic}/timings
+    #[serde(default)]
+    pub timings: bool,
+    // How long a single inverter command may take before it's skipped
+    #[serde(default = "default_command_timeout_ms")]
+    pub command_timeout_ms: u64,
+    // How many times a command is asked again after a garbled response or timeout
+    #[serde(default = "default_command_retries")]
+    pub command_retries: u32,
+    // Accept writes on {topic}/set/<command>
+    #[serde(default)]
+    pub write_commands: bool,
+    // Read each write back with its verification query and publish {topic}/set/<command>/confirmed
+    #[serde(default)]
+    pub confirm_writes: bool,
+    // Any message on {topic}/poll ends the wait after the current update, acknowledged on {topic}/poll/result
+    #[serde(default)]
+    pub poll_on_demand: bool,
+    // Answer commands from a fixtures file instead of opening the inverter
+    #[serde(default)]
+    pub simulate: bool,
+    #[serde(default = "default_simulation_fixtures")]
+    pub simulation_fixtures: String,
+    // Answer commands with the frames of a captured serial log instead of opening the inverter
+    pub replay: Option<String>,
+    // Touched after every successful update for `mpqtt --healthcheck`
+    pub heartbeat_file: Option<String>,
+    // Seconds since the last touch before the healthcheck fails
+    #[serde(default = "default_heartbeat_max_age")]
+    pub heartbeat_max_age: u64,
+    // Where the status counters are kept across restarts, empty leaves them in memory only
+    #[serde(default)]
+    pub state_file: String,
+    // Only used when built with the metrics feature
+    #[serde(default)]
+    pub metrics: MetricsSettings,
+    // Query API for single commands
+    #[serde(default)]
+    pub http: HttpSettings,
+    #[serde(default)]
+    pub smoothing: SmoothingSettings,
+    // Readings outside these ranges are dropped rather than published
+    #[serde(default)]
+    pub sanity: SanitySettings,
+    // Values computed from other fields, published to {topic}/{name} alongside the built in pv_power_total
+    #[serde(default)]
+    pub derived: Vec<DerivedSensorSettings>,
+    // Only used when built with the influx feature
+    #[serde(default)]
+    pub influx: InfluxSettings,
+}
+
+/// The config file to load - the given path, then $MPQTT_CONFIG, then the default for the build
+pub fn config_path(path: Option<&str>) -> String {
+    match path {
+        Some(path) => path.to_string(),
+        None => std::env::var("MPQTT_CONFIG").unwrap_or_else(|_| default_config_path()),
+    }
+}
+
+// config.yaml, unless there's only a config.toml or config.json where it would be
+fn default_config_path() -> String {
+    let default = Path::new(CONFIG_PATH);
+    if default.exists() {
+        return String::from(CONFIG_PATH);
+    }
+    ["toml", "json"]
+        .iter()
+        .map(|extension| default.with_extension(extension))
+        .find(|path| path.exists())
+        .map_or_else(|| String::from(CONFIG_PATH), |path| path.to_string_lossy().to_string())
 }
 
 impl Settings {
-    pub fn new() -> Result<Self, ConfigError> {
+    pub fn new(path: &str) -> Result<Self, SettingsError> {
+        Settings::with_overrides(path, &[])
+    }
+
+    /// Loads the settings with (key, value) pairs taking precedence over the file and environment
+    pub fn with_overrides(path: &str, overrides: &[(&str, &str)]) -> Result<Self, SettingsError> {
         let mut settings = Config::new();
 
-        settings.merge(File::with_name(CONFIG_PATH))?;
+        // Without an extension config looks for each format in turn, anything else it would only fail to find
+        if let Some(extension) = Path::new(path).extension().and_then(|extension| extension.to_str()) {
+            if !CONFIG_EXTENSIONS.contains(&extension) {
+                return Err(SettingsError::Load(ConfigError::Message(format!("{} should end in .{}", path, CONFIG_EXTENSIONS.join(", .")))));
+            }
+        }
+        settings.merge(File::with_name(path)).map_err(SettingsError::Load)?;
+        // MPQTT_MQTT__PASSWORD overrides mqtt.password - a double underscore separates the levels as keys contain single ones
+        settings.merge(Environment::with_prefix("MPQTT").separator("__")).map_err(SettingsError::Load)?;
+
+        // ${NAME} in any value is replaced by that environment variable, set back so config still converts types
+        let tree: serde_json::Value = settings.clone().try_into().map_err(SettingsError::Load)?;
+        let mut interpolated = Vec::new();
+        interpolate_env(&tree, String::new(), &mut interpolated)?;
+        for (key, value) in interpolated {
+            settings.set(&key, value).map_err(SettingsError::Load)?;
+        }
+        for (key, value) in overrides {
+            settings.set(key, *value).map_err(SettingsError::Load)?;
+        }
+
+        let mut settings: Settings = settings.try_into().map_err(SettingsError::Load)?;
+        settings.mqtt.client_id = with_client_id_suffix(&settings.mqtt.client_id, &settings.mqtt.client_id_suffix)?;
+        // The serial number isn't known until init, so an automatic device_id fills in topics as the client_id
+        let device_id = if settings.mqtt.discovery.auto_device_id() { settings.mqtt.client_id.clone() } else { settings.mqtt.discovery.device_id.clone() };
+        settings.mqtt.topic = expand_placeholders("mqtt.topic", &settings.mqtt.topic, &device_id)?;
+        if let Some(ref topic) = settings.mqtt.availability_topic {
+            settings.mqtt.availability_topic = Some(expand_placeholders("mqtt.availability_topic", topic, &device_id)?);
+        }
+        settings.validate()?;
+
+        Ok(settings)
+    }
+
+    fn validate(&self) -> Result<(), SettingsError> {
+        if !(1..=MAX_QPGS_INDEX).contains(&self.inverter_count) {
+            return Err(SettingsError::invalid("inverter_count", format!("must be between 1 and {} as QPGS can only address that many parallel units (got {})", MAX_QPGS_INDEX, self.inverter_count)));
+        }
+        if let Some(ref indices) = self.qpgs_indices {
+            if indices.is_empty() {
+                return Err(SettingsError::invalid("qpgs_indices", "must list at least one unit, or be left out to poll 1 to inverter_count"));
+            }
+            for (position, index) in indices.iter().enumerate() {
+                if *index > MAX_QPGS_INDEX {
+                    return Err(SettingsError::invalid("qpgs_indices", format!("can only contain 0 to {} (got {})", MAX_QPGS_INDEX, index)));
+                }
+                if indices[..position].contains(index) {
+                    return Err(SettingsError::invalid("qpgs_indices", format!("lists {} more than once", index)));
+                }
+            }
+        }
+        if self.inner_iterations == 0 {
+            return Err(SettingsError::invalid("inner_iterations", "must be at least 1"));
+        }
+        if self.error_delay == 0 {
+            return Err(SettingsError::invalid("error_delay", "must be greater than 0 so errors don't spin against the inverter"));
+        }
+        if self.max_error_delay < self.error_delay {
+            return Err(SettingsError::invalid("max_error_delay", format!("must be at least error_delay ({})", self.error_delay)));
+        }
+        if let Some(ref level) = self.log_level {
+            if !LOG_LEVELS.contains(&level.to_ascii_lowercase().as_str()) {
+                return Err(SettingsError::invalid("log_level", format!("must be one of {:?} (got {:?})", LOG_LEVELS, level)));
+            }
+        }
+        for command in &self.disabled_commands {
+            if !POLLED_COMMANDS.contains(&command.to_ascii_lowercase().as_str()) {
+                return Err(SettingsError::invalid("disabled_commands", format!("entries must be one of {:?} (got {:?})", POLLED_COMMANDS, command)));
+            }
+        }
+        if self.influx.enabled {
+            if !cfg!(feature = "influx") {
+                return Err(SettingsError::invalid("influx.enabled", "needs a build with --features influx"));
+            }
+            if self.influx.org.is_empty() || self.influx.bucket.is_empty() {
+                return Err(SettingsError::invalid("influx", "needs an org and bucket to write to"));
+            }
+        }
+        if self.poll_jitter_percent > 100 {
+            return Err(SettingsError::invalid("poll_jitter_percent", format!("must be between 0 and 100 (got {})", self.poll_jitter_percent)));
+        }
+        let mut derived_names: Vec<String> = derived::built_in(self).into_iter().map(|sensor| sensor.name).collect();
+        // Published from QPIGS at the same level as the derived sensors
+        derived_names.push(String::from("battery_power"));
+        for (index, sensor) in self.derived.iter().enumerate() {
+            let field = format!("derived[{}]", index);
+            if sensor.name.is_empty() || !sensor.name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_') {
+                return Err(SettingsError::invalid(&format!("{}.name", field), format!("must be lowercase letters, digits and underscores (got {:?})", sensor.name)));
+            }
+            if POLLED_COMMANDS.contains(&sensor.name.as_str()) || derived_names.contains(&sensor.name) {
+                return Err(SettingsError::invalid(&format!("{}.name", field), format!("{:?} is already published", sensor.name)));
+            }
+            if let Err(message) = derived::parse(&sensor.expression) {
+                return Err(SettingsError::invalid(&format!("{}.expression", field), message));
+            }
+            derived_names.push(sensor.name.clone());
+        }
+        if self.smoothing.window == 0 {
+            return Err(SettingsError::invalid("smoothing.window", "must be at least 1"));
+        }
+        for (command, fields) in &self.sanity.ranges {
+            for (field, range) in fields {
+                if let (Some(min), Some(max)) = (range.min, range.max) {
+                    if min > max {
+                        return Err(SettingsError::invalid(&format!("sanity.ranges.{}.{}", command, field), format!("min must not be above max (got {} and {})", min, max)));
+                    }
+                }
+            }
+        }
+        if self.command_timeout_ms == 0 {
+            return Err(SettingsError::invalid("command_timeout_ms", "must be greater than 0"));
+        }
+        if self.simulate && self.replay.is_some() {
+            return Err(SettingsError::invalid("replay", "can't be used together with simulate"));
+        }
+        if let Some(ref capture) = self.replay {
+            if let Err(err) = std::fs::File::open(capture) {
+                return Err(SettingsError::invalid("replay", format!("{:?} could not be read: {}", capture, err)));
+            }
+        }
+        // Nothing is opened when simulating or replaying, so the device path isn't checked
+        let standing_in = self.simulate || self.replay.is_some();
+        match &self.inverter {
+            Inverters::Single(inverter) => inverter.validate("inverter", standing_in)?,
+            Inverters::Multiple(inverters) => {
+                if inverters.is_empty() {
+                    return Err(SettingsError::invalid("inverter", "must list at least one inverter"));
+                }
+                let mut ids = Vec::new();
+                for (index, inverter) in inverters.iter().enumerate() {
+                    let field = format!("inverter[{}]", index);
+                    match inverter.id {
+                        Some(ref id) if id.is_empty() || id.contains(|c: char| c == '/' || c == '+' || c == '#') => {
+                            return Err(SettingsError::invalid(&format!("{}.id", field), format!("{:?} can't be used in a topic", id)));
+                        }
+                        Some(ref id) if ids.contains(&id) => return Err(SettingsError::invalid(&format!("{}.id", field), format!("{:?} is used by another inverter", id))),
+                        Some(ref id) => ids.push(id),
+                        None => return Err(SettingsError::invalid(&format!("{}.id", field), "is required when more than one inverter is listed")),
+                    }
+                    inverter.validate(&field, standing_in)?;
+                }
+            }
+        }
+        if self.mqtt.publish_retries == 0 {
+            return Err(SettingsError::invalid("mqtt.publish_retries", "must be at least 1"));
+        }
+        self.mqtt.validate()?;
+        self.mqtt.publish.validate()?;
+        Ok(())
+    }
+
+    pub fn command_enabled(&self, command: &str) -> bool {
+        !self.disabled_commands.iter().any(|disabled| disabled.eq_ignore_ascii_case(command))
+    }
+
+    /// The env_logger filter to use when RUST_LOG isn't set
+    pub fn log_filter(&self) -> String {
+        match (&self.log_filter, &self.log_level) {
+            (Some(filter), _) => filter.clone(),
+            (None, Some(level)) => format!("error,mpqtt={0},masterpower_api={0}", level.to_ascii_lowercase()),
+            (None, None) if self.debug => String::from("warn,mpqtt=trace,masterpower_api=trace"),
+            (None, None) => String::from("error,mpqtt=info,masterpower_api=info"),
+        }
+    }
+
+    /// The QPGS indexes polled each update, qpgs_indices when it's set and 1 to inverter_count otherwise
+    /// (unit 0 only answers in debug)
+    pub fn qpgs_indexes(&self) -> Vec<u8> {
+        match self.qpgs_indices {
+            Some(ref indices) => indices.clone(),
+            None => {
+                let start = if self.debug { 0 } else { 1 };
+                (start..=self.inverter_count.min(MAX_QPGS_INDEX)).collect()
+            }
+        }
+    }
+
+    /// The inverter these settings drive. Only meaningful after `split_inverters`, before that it's the first one listed.
+    pub fn inverter(&self) -> &InverterSettings {
+        match &self.inverter {
+            Inverters::Single(inverter) => inverter,
+            Inverters::Multiple(inverters) => &inverters[0],
+        }
+    }
+
+    /// One copy of the settings per inverter, with the MQTT topic, client id and discovery ids namespaced by the inverter id.
+    /// A single inverter keeps the settings as they are so existing topics don't move.
+    pub fn split_inverters(&self) -> Vec<Settings> {
+        let inverters = match &self.inverter {
+            Inverters::Single(_) => return vec![self.clone()],
+            Inverters::Multiple(inverters) => inverters,
+        };
+        inverters
+            .iter()
+            .map(|inverter| {
+                let id = inverter.id.clone().unwrap_or_default();
+                let mut settings = self.clone();
+                settings.inverter = Inverters::Single(inverter.clone());
+                settings.mqtt.topic = format!("{}/{}", self.mqtt.topic, id);
+                settings.mqtt.client_id = format!("{}-{}", self.mqtt.client_id, id);
+                settings.mqtt.availability_topic = self.mqtt.availability_topic.as_ref().map(|topic| format!("{}/{}", topic, id));
+                settings.mqtt.discovery.node_name = format!("{}_{}", self.mqtt.discovery.node_name, id);
+                if !self.mqtt.discovery.auto_device_id() {
+                    settings.mqtt.discovery.device_id = format!("{}_{}", self.mqtt.discovery.device_id, id);
+                }
+                settings.mqtt.discovery.device_name = format!("{} {}", self.mqtt.discovery.device_name, id);
+                settings.heartbeat_file = self.heartbeat_file.as_ref().map(|path| format!("{}.{}", path, id));
+                if !self.state_file.is_empty() {
+                    settings.state_file = format!("{}.{}", self.state_file, id);
+                }
+                settings
+            })
+            .collect()
+    }
+}
+
+// Collects the key and value of every string holding ${NAME} references, with the references resolved
+fn interpolate_env(value: &serde_json::Value, key: String, found: &mut Vec<(String, String)>) -> Result<(), SettingsError> {
+    match value {
+        serde_json::Value::Object(fields) => {
+            for (name, value) in fields {
+                let child = if key.is_empty() { name.clone() } else { format!("{}.{}", key, name) };
+                interpolate_env(value, child, found)?;
+            }
+        }
+        serde_json::Value::Array(values) => {
+            for (index, value) in values.iter().enumerate() {
+                interpolate_env(value, format!("{}[{}]", key, index), found)?;
+            }
+        }
+        serde_json::Value::String(text) if text.contains("${") => {
+            let mut resolved = String::new();
+            let mut rest = text.as_str();
+            while let Some(start) = rest.find("${") {
+                resolved.push_str(&rest[..start]);
+                let end = match rest[start..].find('}') {
+                    Some(end) => start + end,
+                    None => return Err(SettingsError::invalid(&key, format!("has an unclosed ${{ in {:?}", text))),
+                };
+                let name = &rest[start + 2..end];
+                let value = std::env::var(name).map_err(|_| SettingsError::invalid(&key, format!("uses ${{{}}} but {} is not set", name, name)))?;
+                resolved.push_str(&value);
+                rest = &rest[end + 1..];
+            }
+            resolved.push_str(rest);
+            found.push((key, resolved));
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/// Fills in `{hostname}`, `{device_id}` and `{env:NAME}` placeholders. Anything else in braces, or a value
+/// that can't be looked up, is an error rather than ending up in the topic.
+fn expand_placeholders(field: &str, template: &str, device_id: &str) -> Result<String, SettingsError> {
+    let mut expanded = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => return Err(SettingsError::invalid(field, format!("has an unclosed placeholder in {:?}", template))),
+        };
+        let placeholder = &rest[start + 1..end];
+        let value = match placeholder {
+            "hostname" => hostname().ok_or_else(|| SettingsError::invalid(field, "uses {hostname} but the hostname could not be read"))?,
+            "device_id" => device_id.to_string(),
+            _ => match placeholder.strip_prefix("env:") {
+                Some(name) => std::env::var(name).map_err(|_| SettingsError::invalid(field, format!("uses {{{}}} but {} is not set", placeholder, name)))?,
+                None => return Err(SettingsError::invalid(field, format!("has an unknown placeholder {{{}}}", placeholder))),
+            },
+        };
+        expanded.push_str(&value);
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+fn with_client_id_suffix(client_id: &str, suffix: &str) -> Result<String, SettingsError> {
+    let suffix = match suffix {
+        "" | "none" => return Ok(client_id.to_string()),
+        "auto" => format!("{:06x}", rand::random::<u32>() & 0xff_ffff),
+        "hostname" => hostname().ok_or_else(|| SettingsError::invalid("mqtt.client_id_suffix", "hostname isn't available"))?,
+        suffix => suffix.to_string(),
+    };
+    Ok(format!("{}-{}", client_id, suffix))
+}
+
+fn hostname() -> Option<String> {
+    let mut buffer = [0u8; 256];
+    if unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) } != 0 {
+        return None;
+    }
+    let length = buffer.iter().position(|&byte| byte == 0).unwrap_or(buffer.len());
+    String::from_utf8(buffer[..length].to_vec()).ok().filter(|name| !name.is_empty())
+}
+
+fn default_influx_url() -> String {
+    String::from("http://localhost:8086")
+}
+
+fn default_smoothing_window() -> usize {
+    5
+}
+
+fn default_sanity_builtin() -> bool {
+    true
+}
+
+fn default_keep_alive_secs() -> u16 {
+    5
+}
+
+fn default_operation_timeout_secs() -> u64 {
+    10
+}
+
+fn default_connect_retry_delay_secs() -> u64 {
+    1
+}
+
+fn default_discovery_enabled() -> bool {
+    true
+}
+
+fn default_unit_name_template() -> String {
+    String::from("Unit {index}")
+}
+
+fn default_discovery_retain() -> bool {
+    true
+}
+
+fn default_client_id_suffix() -> String {
+    String::from("none")
+}
+
+fn default_connect_give_up_secs() -> u64 {
+    300
+}
+
+fn default_reconnect_give_up_secs() -> u64 {
+    300
+}
+
+fn default_legacy_stats_topics() -> bool {
+    true
+}
+
+fn default_max_error_delay() -> u64 {
+    300
+}
+
+fn default_stale_threshold_secs() -> u64 {
+    600
+}
+
+fn default_error_summary_interval() -> u64 {
+    300
+}
+
+fn default_publish_info() -> bool {
+    true
+}
+
+fn default_log_format() -> LogFormat {
+    LogFormat::Pretty
+}
+
+fn default_baud_rate() -> u32 {
+    2400
+}
+
+fn default_data_bits() -> u8 {
+    8
+}
+
+fn default_parity() -> Parity {
+    Parity::None
+}
+
+fn default_stop_bits() -> u8 {
+    1
+}
+
+fn default_noctty() -> bool {
+    true
+}
+
+fn default_open_retry_delay() -> u64 {
+    5
+}
+
+fn default_open_retry_attempts() -> u32 {
+    12
+}
+
+fn default_command_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_command_retries() -> u32 {
+    2
+}
+
+fn default_max_reconnect_attempts() -> u32 {
+    10
+}
+
+fn default_heartbeat_max_age() -> u64 {
+    300
+}
+
+fn default_qos() -> u8 {
+    1
+}
+
+fn default_publish_retries() -> u32 {
+    5
+}
+
+fn default_publish_backoff_ms() -> u64 {
+    100
+}
+
+fn default_publish_mode() -> PublishMode {
+    PublishMode::Individual
+}
+
+fn default_topic_separator() -> String {
+    String::from("/")
+}
+
+fn default_topic_case() -> TopicCase {
+    TopicCase::AsIs
+}
+
+fn default_clear_error() -> ClearError {
+    ClearError::OnSuccess
+}
+
+fn default_error_format() -> ErrorFormat {
+    ErrorFormat::Text
+}
+
+fn default_payload_online() -> String {
+    String::from("online")
+}
+
+fn default_payload_offline() -> String {
+    String::from("offline")
+}
+
+fn default_simulation_fixtures() -> String {
+    String::from(SIMULATION_PATH)
+}
+
+fn default_metrics_bind() -> String {
+    String::from("0.0.0.0")
+}
+
+fn default_metrics_port() -> u16 {
+    9110
+}
+
+fn default_http_bind() -> String {
+    String::from("0.0.0.0")
+}
+
+fn default_http_port() -> u16 {
+    9111
+}
+
+fn validate_qos(field: &str, qos: u8) -> Result<(), SettingsError> {
+    match qos_from_u8(qos) {
+        Some(_) => Ok(()),
+        None => Err(SettingsError::invalid(field, "must be 0 or 1 (QoS 2 is not supported by the MQTT client)")),
+    }
+}
 
-        settings.try_into()
+// mqtt-async-client fails every QoS 2 publish, so only 0 and 1 are accepted
+fn qos_from_u8(qos: u8) -> Option<QoS> {
+    match qos {
+        0 => Some(QoS::AtMostOnce),
+        1 => Some(QoS::AtLeastOnce),
+        2..=u8::MAX => None,
     }
 }
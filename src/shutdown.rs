@@ -0,0 +1,53 @@
+use log::info;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use tokio::time::{delay_for, Duration};
+
+/// Tracks whether SIGINT or SIGTERM has been received
+pub struct Shutdown {
+    requested: bool,
+    receiver: mpsc::Receiver<()>,
+}
+
+impl Shutdown {
+    /// Spawns a task listening for SIGINT and SIGTERM
+    pub fn listen() -> std::io::Result<Self> {
+        let mut sigterm = signal(SignalKind::terminate())?;
+        let mut sigint = signal(SignalKind::interrupt())?;
+        let (mut sender, receiver) = mpsc::channel(1);
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = sigterm.recv() => info!("Received SIGTERM - shutting down"),
+                _ = sigint.recv() => info!("Received SIGINT - shutting down"),
+            }
+            let _ = sender.send(()).await;
+        });
+        Ok(Shutdown { requested: false, receiver })
+    }
+
+    pub fn is_requested(&mut self) -> bool {
+        if !self.requested && self.receiver.try_recv().is_ok() {
+            self.requested = true;
+        }
+        self.requested
+    }
+
+    /// Waits for the given duration, returning early with `true` if shutdown is requested in the meantime.
+    /// Only the delays between commands are interruptible so a serial transaction is never cut mid-frame.
+    ///
+    /// This has to be an async delay: `std::thread::sleep` blocks the runtime thread so the MQTT client
+    /// can't send keep-alives or process acks while we wait, which shows up as `Timeout waiting for Puback`.
+    pub async fn sleep(&mut self, duration: Duration) -> bool {
+        if self.is_requested() {
+            return true;
+        }
+        let requested = tokio::select! {
+            _ = delay_for(duration) => false,
+            _ = self.receiver.recv() => true,
+        };
+        if requested {
+            self.requested = true;
+        }
+        self.requested
+    }
+}
@@ -0,0 +1,94 @@
+use crate::protocol;
+
+use config::{Config, ConfigError, File};
+use log::debug;
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Stands in for the inverter by answering each request frame with a canned response.
+/// Fixtures map lowercase command names to the response data without the `(`, crc or `\r`.
+pub struct Simulator {
+    fixtures: Arc<HashMap<String, String>>,
+    request: Vec<u8>,
+    response: VecDeque<u8>,
+    waker: Option<Waker>,
+}
+
+impl Simulator {
+    pub fn load(path: &str) -> Result<Self, ConfigError> {
+        let mut fixtures = Config::new();
+        fixtures.merge(File::with_name(path))?;
+        let fixtures: HashMap<String, String> = fixtures.try_into()?;
+        Ok(Simulator::from_fixtures(Arc::new(fixtures)))
+    }
+
+    fn from_fixtures(fixtures: Arc<HashMap<String, String>>) -> Self {
+        Simulator {
+            fixtures,
+            request: Vec::new(),
+            response: VecDeque::new(),
+            waker: None,
+        }
+    }
+
+    fn respond(&mut self) {
+        // Drop any report padding, then strip the crc and terminator to get back to the command name
+        self.request.retain(|byte| *byte != 0);
+        let end = self.request.len().saturating_sub(3);
+        let command = String::from_utf8_lossy(&self.request[..end]).to_ascii_lowercase();
+        self.request.clear();
+
+        let data = match self.fixtures.get(&command) {
+            Some(data) => data.clone(),
+            None => String::from("NAK"),
+        };
+        debug!("Simulating {} -> ({}", command, data);
+        let mut frame = format!("({}", data).into_bytes();
+        frame.extend_from_slice(&protocol::crc16(&frame).to_be_bytes());
+        frame.push(b'\r');
+        self.response.extend(frame);
+
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl AsyncRead for Simulator {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if this.response.is_empty() {
+            this.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let read = buf.len().min(this.response.len());
+        for (slot, byte) in buf.iter_mut().zip(this.response.drain(..read)) {
+            *slot = byte;
+        }
+        Poll::Ready(Ok(read))
+    }
+}
+
+impl AsyncWrite for Simulator {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        for byte in buf {
+            this.request.push(*byte);
+            if *byte == b'\r' {
+                this.respond();
+            }
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
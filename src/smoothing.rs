@@ -0,0 +1,45 @@
+use crate::settings::SmoothingSettings;
+
+use serde_json::{Map, Value};
+use std::collections::{HashMap, VecDeque};
+
+/// Rolling average of the configured numeric fields over the last `window` readings
+pub struct Smoother {
+    window: usize,
+    fields: Vec<String>,
+    readings: HashMap<String, VecDeque<f64>>,
+}
+
+impl Smoother {
+    pub fn new(settings: &SmoothingSettings) -> Self {
+        Smoother {
+            window: settings.window,
+            fields: settings.fields.clone(),
+            readings: HashMap::new(),
+        }
+    }
+
+    /// Adds a reading and returns the averaged fields as JSON, or None when there's nothing to smooth.
+    /// Fields missing from the response or that aren't numbers are left out.
+    pub fn push(&mut self, json: &str) -> Option<String> {
+        if self.fields.is_empty() || self.window == 0 {
+            return None;
+        }
+        let response: Value = serde_json::from_str(json).ok()?;
+        let mut smoothed = Map::new();
+        for field in &self.fields {
+            let value = match response.get(field).and_then(Value::as_f64) {
+                Some(value) => value,
+                None => continue,
+            };
+            let readings = self.readings.entry(field.clone()).or_insert_with(VecDeque::new);
+            readings.push_back(value);
+            while readings.len() > self.window {
+                readings.pop_front();
+            }
+            let average = readings.iter().sum::<f64>() / readings.len() as f64;
+            smoothed.insert(field.clone(), Value::from(average));
+        }
+        Some(Value::Object(smoothed).to_string())
+    }
+}
@@ -0,0 +1,50 @@
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+
+// Counters kept in state_file so the totals in {topic}/status carry on across restarts
+
+/// The totals worth keeping, uptime and the last poll start over with the process
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+pub struct Counters {
+    pub update_cycles: u64,
+    pub serial_errors: u64,
+    pub publish_failures: u64,
+}
+
+/// Reads the saved counters, starting from zero when the file is missing or can't be parsed
+pub fn load(path: &str) -> Counters {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => return Counters::default(),
+        Err(err) => {
+            warn!("Could not read state file {}: {} - counters start from zero", path, err);
+            return Counters::default();
+        }
+    };
+    match serde_json::from_str(&contents) {
+        Ok(counters) => {
+            info!("Restored counters from {}", path);
+            counters
+        }
+        Err(err) => {
+            warn!("State file {} is not valid: {} - counters start from zero", path, err);
+            Counters::default()
+        }
+    }
+}
+
+/// Writes the counters next to the state file first and renames it over, so a crash mid write never leaves
+/// a truncated file behind. Failures are only logged since they don't affect polling.
+pub async fn save(path: &str, counters: &Counters) {
+    let temp = format!("{}.tmp", path);
+    let result = match serde_json::to_string(counters) {
+        Ok(json) => match tokio::fs::write(&temp, json).await {
+            Ok(()) => tokio::fs::rename(&temp, path).await,
+            Err(err) => Err(err),
+        },
+        Err(err) => Err(err.into()),
+    };
+    if let Err(err) = result {
+        warn!("Could not save state file {}: {}", path, err);
+    }
+}
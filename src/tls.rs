@@ -0,0 +1,122 @@
+use crate::settings::MqttSettings;
+
+use rustls::internal::pemfile;
+use rustls::sign;
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// The rustls config for a TLS broker connection. Without a `ca_cert` the broker is checked against the
+/// bundled web PKI roots. Settings validation builds this too so a bad certificate fails at startup.
+pub fn client_config(mqtt: &MqttSettings) -> Result<ClientConfig, Box<dyn std::error::Error>> {
+    let mut config = ClientConfig::new();
+    match mqtt.ca_cert {
+        Some(ref ca_cert) => match config.root_store.add_pem_file(&mut pem(ca_cert)?.as_slice()) {
+            Ok((added, _)) if added > 0 => (),
+            _ => return Err(format!("mqtt.ca_cert {} doesn't contain a usable PEM certificate", describe(ca_cert)).into()),
+        },
+        None => config.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS),
+    }
+
+    if let (Some(cert), Some(key)) = (&mqtt.client_cert, &mqtt.client_key) {
+        let certs = match pemfile::certs(&mut pem(cert)?.as_slice()) {
+            Ok(certs) if !certs.is_empty() => certs,
+            _ => return Err(format!("mqtt.client_cert {} isn't a PEM certificate chain", describe(cert)).into()),
+        };
+        config.set_single_client_cert(certs, private_key(key)?);
+    }
+
+    if !mqtt.alpn_protocols.is_empty() {
+        config.set_protocols(&mqtt.alpn_protocols.iter().map(|protocol| protocol.as_bytes().to_vec()).collect::<Vec<_>>());
+    }
+
+    if mqtt.insecure_skip_verify {
+        config.dangerous().set_certificate_verifier(Arc::new(NoVerification));
+    } else if let Some(ref name) = mqtt.tls_server_name {
+        webpki::DNSNameRef::try_from_ascii_str(name).map_err(|_| format!("mqtt.tls_server_name {:?} isn't a valid DNS name", name))?;
+        config.dangerous().set_certificate_verifier(Arc::new(ServerName { name: name.clone() }));
+    }
+    Ok(config)
+}
+
+/// Whether a certificate or key setting holds the PEM itself rather than a path to it
+pub fn is_inline(value: &str) -> bool {
+    value.trim_start().starts_with("-----BEGIN")
+}
+
+// How a certificate or key setting is named in errors, without dumping inline PEM into the log
+fn describe(value: &str) -> &str {
+    if is_inline(value) {
+        "(inline PEM)"
+    } else {
+        value
+    }
+}
+
+fn pem(value: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if is_inline(value) {
+        return Ok(value.as_bytes().to_vec());
+    }
+    Ok(std::fs::read(value).map_err(|err| format!("{} could not be read: {}", value, err))?)
+}
+
+// Keys come as either PKCS8 or the older RSA format
+fn private_key(key: &str) -> Result<PrivateKey, Box<dyn std::error::Error>> {
+    let contents = pem(key)?;
+    let mut keys = pemfile::pkcs8_private_keys(&mut contents.as_slice()).unwrap_or_default();
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut contents.as_slice()).unwrap_or_default();
+    }
+    // set_single_client_cert panics on a key it can't sign with, so that's checked here first
+    match keys.into_iter().next() {
+        Some(parsed) if sign::any_supported_type(&parsed).is_ok() => Ok(parsed),
+        Some(_) => Err(format!("mqtt.client_key {} isn't an RSA or ECDSA key rustls can sign with", describe(key)).into()),
+        None => Err(format!("mqtt.client_key {} doesn't contain a PEM private key", describe(key)).into()),
+    }
+}
+
+// For brokers with self signed certificates when there's no CA file to hand
+struct NoVerification;
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(&self, _roots: &RootCertStore, _presented_certs: &[Certificate], _dns_name: webpki::DNSNameRef<'_>, _ocsp_response: &[u8]) -> Result<ServerCertVerified, TLSError> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+// The signature algorithms rustls accepts on server certificates, which it doesn't export
+static SIGNATURE_ALGORITHMS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
+// Checks the broker's certificate as usual but against tls_server_name rather than the host connected to.
+// mqtt-async-client sends the host as the handshake's SNI, so this is for brokers whose certificate doesn't
+// name the address they're reached at, e.g. behind a tunnel or by IP. rustls 0.16 keeps its own WebPKIVerifier
+// private, so these are the same chain and name checks it makes.
+struct ServerName {
+    name: String,
+}
+
+impl ServerCertVerifier for ServerName {
+    fn verify_server_cert(&self, roots: &RootCertStore, presented_certs: &[Certificate], _dns_name: webpki::DNSNameRef<'_>, _ocsp_response: &[u8]) -> Result<ServerCertVerified, TLSError> {
+        let name = webpki::DNSNameRef::try_from_ascii_str(&self.name).map_err(|_| TLSError::General(format!("{:?} isn't a valid DNS name", self.name)))?;
+        let (end_entity, intermediates) = presented_certs.split_first().ok_or(TLSError::NoCertificatesPresented)?;
+        let cert = webpki::EndEntityCert::from(&end_entity.0).map_err(TLSError::WebPKIError)?;
+        let intermediates: Vec<&[u8]> = intermediates.iter().map(|cert| cert.0.as_slice()).collect();
+        let anchors: Vec<webpki::TrustAnchor> = roots.roots.iter().map(|root| root.to_trust_anchor()).collect();
+        let now = webpki::Time::try_from(SystemTime::now()).map_err(|_| TLSError::FailedToGetCurrentTime)?;
+        cert.verify_is_valid_tls_server_cert(SIGNATURE_ALGORITHMS, &webpki::TLSServerTrustAnchors(&anchors), &intermediates, now).map_err(TLSError::WebPKIError)?;
+        cert.verify_is_valid_for_dns_name(name).map_err(TLSError::WebPKIError)?;
+        Ok(ServerCertVerified::assertion())
+    }
+}
@@ -0,0 +1,137 @@
+use serde_json::Value;
+
+// Names for the QPIWS warning bits and the QPIGS device status bits. Kept on their own so other protocol versions
+// can add a table alongside.
+
+pub struct Warning {
+    // Field in the QPIWS JSON, also the topic suffix under {topic}/qpiws/
+    pub field: &'static str,
+    pub name: &'static str,
+}
+
+pub const QPIWS_WARNINGS: &[Warning] = &[
+    Warning { field: "inverter_fault", name: "Inverter fault" },
+    Warning { field: "bus_over", name: "Bus over" },
+    Warning { field: "bus_under", name: "Bus under" },
+    Warning { field: "bus_soft_fail", name: "Bus soft fail" },
+    Warning { field: "line_fail", name: "Line fail" },
+    Warning { field: "opv_short", name: "OPV Short" },
+    Warning {
+        field: "inverter_voltage_too_low",
+        name: "Inverter voltage too low",
+    },
+    Warning {
+        field: "inverter_voltage_too_high",
+        name: "Inverter voltage too high",
+    },
+    Warning { field: "over_temperature", name: "Over temperature" },
+    Warning { field: "fan_locked", name: "Fan locked" },
+    Warning {
+        field: "battery_voltage_high",
+        name: "Battery voltage high",
+    },
+    Warning { field: "battery_low_alarm", name: "Battery low alarm" },
+    Warning {
+        field: "battery_under_shutdown",
+        name: "Battery under shutdown",
+    },
+    Warning { field: "over_load", name: "Over load" },
+    Warning { field: "eeprom_fault", name: "EEPROM Fault" },
+    Warning {
+        field: "inverter_over_current",
+        name: "Inverter over current",
+    },
+    Warning {
+        field: "inverter_soft_fail",
+        name: "Inverter soft fail",
+    },
+    Warning { field: "self_test_fail", name: "Self test fail" },
+    Warning {
+        field: "op_dc_voltage_over",
+        name: "OP DC Voltage over",
+    },
+    Warning { field: "bat_open", name: "Bat open" },
+    Warning {
+        field: "current_sensor_fail",
+        name: "Current sensor fail",
+    },
+    Warning { field: "battery_short", name: "Battery short" },
+    Warning { field: "power_limit", name: "Power limit" },
+    Warning { field: "pv_voltage_high", name: "PV Voltage high" },
+    Warning {
+        field: "mppt_overload_fault",
+        name: "MPPT Overload fault",
+    },
+    Warning {
+        field: "mppt_overload_warning",
+        name: "MPPT Overload warning",
+    },
+    Warning {
+        field: "battery_too_low_to_charge",
+        name: "Battery too low to charge",
+    },
+];
+
+/// Pairs each known warning with whether it's set in a published QPIWS payload. Missing fields are left out.
+pub fn decode(json: &str) -> Vec<(&'static Warning, bool)> {
+    let value: Value = match serde_json::from_str(json) {
+        Ok(value) => value,
+        Err(_) => return Vec::new(),
+    };
+    QPIWS_WARNINGS.iter().filter_map(|warning| value.get(warning.field).and_then(Value::as_bool).map(|active| (warning, active))).collect()
+}
+
+pub struct StatusFlag {
+    // Field in the QPIGS device_status JSON, also the topic suffix under {topic}/qpigs/
+    pub field: &'static str,
+    pub name: &'static str,
+    pub icon: &'static str,
+}
+
+pub const QPIGS_STATUS_FLAGS: &[StatusFlag] = &[
+    StatusFlag {
+        field: "charging",
+        name: "Charging",
+        icon: "battery-charging",
+    },
+    StatusFlag {
+        field: "scc_charging",
+        name: "Charging from solar",
+        icon: "solar-power",
+    },
+    StatusFlag {
+        field: "ac_charging",
+        name: "Charging from grid",
+        icon: "transmission-tower",
+    },
+    StatusFlag { field: "active_load", name: "Load on", icon: "power" },
+    StatusFlag {
+        field: "battery_voltage_steady",
+        name: "Battery voltage steady while charging",
+        icon: "battery-heart-variant",
+    },
+    StatusFlag {
+        field: "sbu_priority_version",
+        name: "SBU priority version",
+        icon: "information-outline",
+    },
+    StatusFlag {
+        field: "configuration_changed",
+        name: "Configuration changed",
+        icon: "cog-outline",
+    },
+    StatusFlag {
+        field: "scc_firmware_updated",
+        name: "SCC firmware updated",
+        icon: "update",
+    },
+];
+
+/// Pairs each known QPIGS device status bit with whether it's set in a published QPIGS payload. Missing fields are left out.
+pub fn decode_status(json: &str) -> Vec<(&'static StatusFlag, bool)> {
+    let status = match serde_json::from_str::<Value>(json) {
+        Ok(value) => value.get("device_status").cloned().unwrap_or(Value::Null),
+        Err(_) => return Vec::new(),
+    };
+    QPIGS_STATUS_FLAGS.iter().filter_map(|flag| status.get(flag.field).and_then(Value::as_bool).map(|set| (flag, set))).collect()
+}
@@ -0,0 +1,128 @@
+//! The same settings written as YAML, TOML and JSON load the same way.
+
+use config::{Config, File};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const YAML: &str = r#"debug: false
+outer_delay: 10
+inner_delay: 2
+error_delay: 5
+inner_iterations: 3
+inverter_count: 1
+mode: standard
+intervals:
+  qpiri: 60
+disabled_commands:
+  - qt
+inverter:
+  path: /dev/ttyUSB0
+  baud_rate: 2400
+mqtt:
+  host: localhost
+  port: 1883
+  username: mpqtt
+  password: secret
+  client_id: mpqtt
+  topic: mpqtt
+  discovery:
+    prefix: homeassistant
+    node_name: mpqtt
+    device_name: MPQTT
+    device_id: mpqtt
+"#;
+
+const TOML: &str = r#"debug = false
+outer_delay = 10
+inner_delay = 2
+error_delay = 5
+inner_iterations = 3
+inverter_count = 1
+mode = "standard"
+disabled_commands = ["qt"]
+
+[intervals]
+qpiri = 60
+
+[inverter]
+path = "/dev/ttyUSB0"
+baud_rate = 2400
+
+[mqtt]
+host = "localhost"
+port = 1883
+username = "mpqtt"
+password = "secret"
+client_id = "mpqtt"
+topic = "mpqtt"
+
+[mqtt.discovery]
+prefix = "homeassistant"
+node_name = "mpqtt"
+device_name = "MPQTT"
+device_id = "mpqtt"
+"#;
+
+const JSON: &str = r#"{
+  "debug": false,
+  "outer_delay": 10,
+  "inner_delay": 2,
+  "error_delay": 5,
+  "inner_iterations": 3,
+  "inverter_count": 1,
+  "mode": "standard",
+  "intervals": { "qpiri": 60 },
+  "disabled_commands": ["qt"],
+  "inverter": { "path": "/dev/ttyUSB0", "baud_rate": 2400 },
+  "mqtt": {
+    "host": "localhost",
+    "port": 1883,
+    "username": "mpqtt",
+    "password": "secret",
+    "client_id": "mpqtt",
+    "topic": "mpqtt",
+    "discovery": { "prefix": "homeassistant", "node_name": "mpqtt", "device_name": "MPQTT", "device_id": "mpqtt" }
+  }
+}
+"#;
+
+#[test]
+fn formats_parse_identically() {
+    let yaml = load(&write("config.yaml", YAML));
+    assert_eq!(load(&write("config.toml", TOML)), yaml, "TOML differs from YAML");
+    assert_eq!(load(&write("config.json", JSON)), yaml, "JSON differs from YAML");
+}
+
+#[test]
+fn every_format_is_valid() {
+    for (name, contents) in &[("valid.yaml", YAML), ("valid.toml", TOML), ("valid.json", JSON)] {
+        let output = validate(&write(name, contents));
+        assert!(output.status.success(), "{} isn't valid: {}", name, String::from_utf8_lossy(&output.stdout));
+    }
+}
+
+#[test]
+fn unknown_extension_is_rejected() {
+    let output = validate(&write("config.conf", YAML));
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains(".toml"), "the error should list the supported formats");
+}
+
+fn write(name: &str, contents: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("mpqtt-config-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+// The settings tree as the config crate reads it, before mpqtt fills in defaults
+fn load(path: &Path) -> serde_json::Value {
+    let mut settings = Config::new();
+    settings.merge(File::from(path)).unwrap();
+    settings.try_into().unwrap()
+}
+
+fn validate(path: &Path) -> std::process::Output {
+    Command::new(env!("CARGO_BIN_EXE_mpqtt")).arg("--validate-config").arg("--config").arg(path).env_clear().output().unwrap()
+}
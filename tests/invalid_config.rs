@@ -0,0 +1,90 @@
+//! Malformed configs are rejected by `--validate-config` with an error naming what's wrong.
+
+use std::process::Command;
+
+const VALID: &str = r#"debug: false
+outer_delay: 10
+inner_delay: 2
+error_delay: 5
+inner_iterations: 3
+inverter_count: 1
+mode: standard
+inverter:
+  path: /dev/ttyUSB0
+mqtt:
+  host: localhost
+  port: 1883
+  username: mpqtt
+  password: secret
+  client_id: mpqtt
+  topic: mpqtt
+  discovery:
+    prefix: homeassistant
+    node_name: mpqtt
+    device_name: MPQTT
+    device_id: mpqtt
+"#;
+
+#[test]
+fn base_config_is_valid() {
+    let (valid, output) = validate("valid", VALID);
+    assert!(valid, "the base config should be valid: {}", output);
+}
+
+#[test]
+fn wrong_types_are_rejected() {
+    assert_rejected("outer_delay_string", &VALID.replace("outer_delay: 10", "outer_delay: ten"), &["`outer_delay`", "ten", "integer"]);
+    assert_rejected("port_string", &VALID.replace("port: 1883", "port: mqtt"), &["`mqtt.port`", "integer"]);
+    assert_rejected("inverter_list_of_numbers", &VALID.replace("  path: /dev/ttyUSB0", "  - 1\n  - 2"), &["Inverters"]);
+}
+
+#[test]
+fn unknown_values_are_rejected() {
+    assert_rejected("unknown_mode", &VALID.replace("mode: standard", "mode: turbo"), &["Mode", "turbo"]);
+    assert_rejected("unknown_log_level", &format!("{}log_level: loud\n", VALID), &["log_level", "loud"]);
+    assert_rejected("unknown_disabled_command", &format!("{}disabled_commands:\n  - qxyz\n", VALID), &["disabled_commands", "qxyz"]);
+}
+
+#[test]
+fn missing_required_keys_are_rejected() {
+    assert_rejected("missing_host", &VALID.replace("  host: localhost\n", ""), &["`host`"]);
+    assert_rejected("missing_inverter", &VALID.replace("inverter:\n  path: /dev/ttyUSB0\n", ""), &["`inverter`"]);
+    assert_rejected("missing_discovery_prefix", &VALID.replace("    prefix: homeassistant\n", ""), &["`prefix`"]);
+}
+
+#[test]
+fn out_of_range_values_are_rejected() {
+    assert_rejected("inverter_count_zero", &VALID.replace("inverter_count: 1", "inverter_count: 0"), &["inverter_count", "between 1 and 9"]);
+    assert_rejected("inverter_count_too_many", &VALID.replace("inverter_count: 1", "inverter_count: 12"), &["inverter_count", "(got 12)"]);
+    assert_rejected("inner_iterations_zero", &VALID.replace("inner_iterations: 3", "inner_iterations: 0"), &["inner_iterations", "at least 1"]);
+    assert_rejected("error_delay_zero", &VALID.replace("error_delay: 5", "error_delay: 0"), &["error_delay", "greater than 0"]);
+    assert_rejected("command_timeout_zero", &format!("{}command_timeout_ms: 0\n", VALID), &["command_timeout_ms", "greater than 0"]);
+    assert_rejected("jitter_over_100", &format!("{}poll_jitter_percent: 150\n", VALID), &["poll_jitter_percent", "(got 150)"]);
+    assert_rejected("qos_2", &VALID.replace("  topic: mpqtt\n", "  topic: mpqtt\n  publish:\n    qos: 2\n"), &["mqtt.publish.qos", "must be 0 or 1"]);
+    assert_rejected("command_qos_2", &VALID.replace("  topic: mpqtt\n", "  topic: mpqtt\n  publish:\n    commands:\n      qpigs:\n        qos: 2\n"), &["mqtt.publish.commands.qpigs.qos", "QoS 2"]);
+    assert_rejected("qpgs_index_repeated", &format!("{}qpgs_indices: [1, 3, 1]\n", VALID), &["qpgs_indices", "more than once"]);
+}
+
+#[test]
+fn broken_yaml_is_rejected() {
+    assert_rejected("bad_indentation", &VALID.replace("  port: 1883", " port: 1883"), &["could not be loaded", "line 12"]);
+    assert_rejected("unclosed_list", &format!("{}disabled_commands: [qt\n", VALID), &["could not be loaded"]);
+}
+
+// Validates the config and checks it failed with every one of expected in the error
+fn assert_rejected(name: &str, contents: &str, expected: &[&str]) {
+    let (valid, output) = validate(name, contents);
+    assert!(!valid, "{} should be rejected:\n{}", name, contents);
+    for part in expected {
+        assert!(output.contains(part), "{} was rejected without mentioning {:?}: {}", name, part, output);
+    }
+}
+
+fn validate(name: &str, contents: &str) -> (bool, String) {
+    let dir = std::env::temp_dir().join(format!("mpqtt-invalid-config-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(format!("{}.yaml", name));
+    std::fs::write(&path, contents).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_mpqtt")).arg("--validate-config").arg("--config").arg(&path).env_clear().output().unwrap();
+    (output.status.success(), String::from_utf8_lossy(&output.stdout).to_string())
+}
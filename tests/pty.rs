@@ -0,0 +1,305 @@
+//! End to end runs of the binary against a fake inverter on a pseudo-terminal and a fake MQTT broker, covering the
+//! serial backend, parsing and publishing together. The fixtures are the ones `simulate: true` answers with.
+#![cfg(target_os = "linux")]
+
+use crc_any::CRCu16;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How long a single --once run gets before the test gives up on it
+const RUN_TIMEOUT: Duration = Duration::from_secs(60);
+
+type Published = Arc<Mutex<Vec<(String, Vec<u8>)>>>;
+
+#[test]
+fn init_and_update_publish_readings() {
+    let (inverter, _slave) = start_inverter(&[]);
+    let (port, published) = start_broker();
+
+    let (status, log) = run_once("readings", &inverter, port);
+    assert!(status.success(), "mpqtt failed:\n{}", log);
+
+    let qid = payload(&published, "test/qid").unwrap_or_else(|| panic!("nothing published to test/qid:\n{}", log));
+    assert!(qid.contains("92931701100510"), "unexpected qid {}", qid);
+    let qpigs = payload(&published, "test/qpigs").unwrap_or_else(|| panic!("nothing published to test/qpigs:\n{}", log));
+    let qpigs: serde_json::Value = serde_json::from_str(&qpigs).unwrap();
+    // The fixture's grid input is down (000.0 00.0) while the output runs at 230.0 49.9
+    assert_eq!(qpigs["grid_voltage"], 0.0);
+    assert_eq!(qpigs["ac_out_voltage"], 230.0);
+    assert_eq!(qpigs["battery_capacity"], 100);
+    assert_eq!(payload(&published, "test/qpigs/battery_soc").as_deref(), Some("100"));
+    assert_eq!(payload(&published, "test/qpigs/ac_in_voltage").as_deref(), Some("0.0"));
+    assert_eq!(payload(&published, "test/qpigs/ac_in_freq").as_deref(), Some("0.0"));
+    assert_eq!(payload(&published, "test/qpigs/ac_out_voltage").as_deref(), Some("230.0"));
+    assert_eq!(payload(&published, "test/qpigs/ac_out_freq").as_deref(), Some("49.9"));
+    assert!(payload(&published, "test/status").is_some(), "nothing published to test/status:\n{}", log);
+}
+
+#[test]
+fn bad_crc_is_published_as_an_error() {
+    let (inverter, _slave) = start_inverter(&["qpigs"]);
+    let (port, published) = start_broker();
+
+    let (status, log) = run_once("bad_crc", &inverter, port);
+    assert!(!status.success(), "mpqtt should fail the update on a bad crc:\n{}", log);
+
+    // Init still went through before the update failed
+    assert!(payload(&published, "test/qid").is_some(), "nothing published to test/qid:\n{}", log);
+    assert!(payload(&published, "test/qpigs").is_none(), "the corrupt qpigs response was published");
+    let errors: Vec<String> = payloads(&published, "test/error").into_iter().filter(|error| !error.is_empty()).map(|error| error.to_lowercase()).collect();
+    assert!(errors.iter().any(|error| error.starts_with("qpigs") && error.contains("crc")), "no crc error for qpigs in {:?}:\n{}", errors, log);
+}
+
+// Runs `mpqtt --once` against the pty and broker, returning how it exited and everything it logged
+// The sleep is fine here: the test is synchronous and only polls the child process, there's no runtime to block
+#[allow(clippy::disallowed_methods)]
+fn run_once(name: &str, inverter: &str, port: u16) -> (ExitStatus, String) {
+    let dir = std::env::temp_dir().join(format!("mpqtt-test-{}-{}", std::process::id(), name));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = dir.join("config.yaml");
+    std::fs::write(&config, config_yaml(inverter, port)).unwrap();
+    let log = dir.join("mpqtt.log");
+    let output = File::create(&log).unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_mpqtt")).arg("--config").arg(&config).arg("--once").env_clear().stdout(output.try_clone().unwrap()).stderr(output).spawn().unwrap();
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if started.elapsed() > RUN_TIMEOUT {
+            let _ = child.kill();
+            panic!("mpqtt didn't finish within {:?}:\n{}", RUN_TIMEOUT, std::fs::read_to_string(&log).unwrap_or_default());
+        }
+        thread::sleep(Duration::from_millis(100));
+    };
+    (status, std::fs::read_to_string(&log).unwrap_or_default())
+}
+
+fn config_yaml(inverter: &str, port: u16) -> String {
+    format!(
+        r#"debug: false
+log_level: debug
+outer_delay: 1
+inner_delay: 0
+error_delay: 1
+inner_iterations: 1
+inverter_count: 1
+mode: standard
+command_timeout_ms: 2000
+command_retries: 0
+inverter:
+  path: {}
+  open_retry_delay: 1
+  open_retry_attempts: 1
+mqtt:
+  host: 127.0.0.1
+  port: {}
+  username: ""
+  password: ""
+  client_id: mpqtt-test
+  topic: test
+  discovery:
+    prefix: homeassistant
+    node_name: mpqtt_test
+    device_name: MPQTT Test
+    device_id: mpqtt_test
+"#,
+        inverter, port
+    )
+}
+
+fn payloads(published: &Published, topic: &str) -> Vec<String> {
+    published.lock().unwrap().iter().filter(|(published, _)| published == topic).map(|(_, payload)| String::from_utf8_lossy(payload).to_string()).collect()
+}
+
+// The last payload published to a topic
+fn payload(published: &Published, topic: &str) -> Option<String> {
+    payloads(published, topic).pop()
+}
+
+// The canned responses from debian/simulation.yaml, which are `command: "data"` lines
+fn fixtures() -> HashMap<String, String> {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("debian/simulation.yaml");
+    std::fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            let command = parts.next()?.trim();
+            let data = parts.next()?.trim().trim_matches('"');
+            Some((command.to_string(), data.to_string()))
+        })
+        .collect()
+}
+
+// CRC16/XMODEM with the protocol's adjustment for bytes that would clash with frame delimiters
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = CRCu16::crc16xmodem();
+    crc.digest(data);
+    let [mut high, mut low] = crc.get_crc().to_be_bytes();
+    if high == 0x28 || high == 0x0d || high == 0x0a {
+        high += 1;
+    }
+    if low == 0x28 || low == 0x0d || low == 0x0a {
+        low += 1;
+    }
+    u16::from_be_bytes([high, low])
+}
+
+// Opens a pty whose other end answers each request with its fixture, or NAK, and returns the device path for the
+// config. Commands listed in corrupt get a response whose data doesn't match its crc. The device end is kept open
+// so the pty isn't hung up between mpqtt opening and closing it.
+fn start_inverter(corrupt: &'static [&'static str]) -> (String, File) {
+    let (master, path) = unsafe {
+        let master = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+        assert!(master >= 0, "posix_openpt: {}", std::io::Error::last_os_error());
+        assert_eq!(libc::grantpt(master), 0, "grantpt: {}", std::io::Error::last_os_error());
+        assert_eq!(libc::unlockpt(master), 0, "unlockpt: {}", std::io::Error::last_os_error());
+        let mut name = [0 as libc::c_char; 128];
+        assert_eq!(libc::ptsname_r(master, name.as_mut_ptr(), name.len()), 0, "ptsname_r: {}", std::io::Error::last_os_error());
+        (File::from_raw_fd(master), CStr::from_ptr(name.as_ptr()).to_string_lossy().to_string())
+    };
+
+    // Raw from the start so nothing is echoed back before mpqtt configures the line itself
+    let slave = OpenOptions::new().read(true).write(true).custom_flags(libc::O_NOCTTY).open(&path).unwrap();
+    unsafe {
+        let mut tty: libc::termios = std::mem::zeroed();
+        assert_eq!(libc::tcgetattr(slave.as_raw_fd(), &mut tty), 0);
+        libc::cfmakeraw(&mut tty);
+        assert_eq!(libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &tty), 0);
+    }
+
+    let fixtures = fixtures();
+    thread::spawn(move || {
+        let mut master = master;
+        let mut request = Vec::new();
+        let mut buf = [0u8; 64];
+        while let Ok(read) = master.read(&mut buf) {
+            if read == 0 {
+                return;
+            }
+            for byte in &buf[..read] {
+                request.push(*byte);
+                if *byte != b'\r' {
+                    continue;
+                }
+                request.retain(|byte| *byte != 0);
+                let end = request.len().saturating_sub(3);
+                let command = String::from_utf8_lossy(&request[..end]).to_ascii_lowercase();
+                request.clear();
+
+                let data = fixtures.get(&command).cloned().unwrap_or_else(|| String::from("NAK"));
+                let mut frame = format!("({}", data).into_bytes();
+                let crc = crc16(&frame);
+                if corrupt.contains(&command.as_str()) {
+                    // Line noise flipping a digit after the crc was worked out
+                    frame[1] = if frame[1] == b'1' { b'2' } else { b'1' };
+                }
+                frame.extend_from_slice(&crc.to_be_bytes());
+                frame.push(b'\r');
+                if master.write_all(&frame).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    (path, slave)
+}
+
+// Accepts any number of clients, acknowledging everything an MQTT 3.1.1 client sends and recording the publishes
+fn start_broker() -> (u16, Published) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let published = Published::default();
+    let sink = published.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let sink = sink.clone();
+            thread::spawn(move || serve_client(stream, sink));
+        }
+    });
+    (port, published)
+}
+
+fn serve_client(mut stream: TcpStream, published: Published) {
+    while let Some((header, body)) = read_packet(&mut stream) {
+        let reply = match header >> 4 {
+            // CONNECT -> CONNACK
+            1 => vec![0x20, 0x02, 0x00, 0x00],
+            // PUBLISH -> PUBACK or PUBREC depending on its qos
+            3 => {
+                let qos = (header >> 1) & 0x03;
+                let topic_len = usize::from(u16::from_be_bytes([body[0], body[1]]));
+                let topic = String::from_utf8_lossy(&body[2..2 + topic_len]).to_string();
+                let mut rest = &body[2 + topic_len..];
+                let mut id = [0, 0];
+                if qos > 0 {
+                    id = [rest[0], rest[1]];
+                    rest = &rest[2..];
+                }
+                published.lock().unwrap().push((topic, rest.to_vec()));
+                match qos {
+                    0 => Vec::new(),
+                    1 => vec![0x40, 0x02, id[0], id[1]],
+                    _ => vec![0x50, 0x02, id[0], id[1]],
+                }
+            }
+            // PUBREL -> PUBCOMP
+            6 => vec![0x70, 0x02, body[0], body[1]],
+            // SUBSCRIBE -> SUBACK granting every requested qos
+            8 => {
+                let mut granted = Vec::new();
+                let mut rest = &body[2..];
+                while rest.len() >= 3 {
+                    let len = usize::from(u16::from_be_bytes([rest[0], rest[1]]));
+                    granted.push(rest[2 + len]);
+                    rest = &rest[3 + len..];
+                }
+                let mut reply = vec![0x90, 2 + granted.len() as u8, body[0], body[1]];
+                reply.extend(granted);
+                reply
+            }
+            // UNSUBSCRIBE -> UNSUBACK
+            10 => vec![0xB0, 0x02, body[0], body[1]],
+            // PINGREQ -> PINGRESP
+            12 => vec![0xD0, 0x00],
+            // DISCONNECT
+            _ => return,
+        };
+        if !reply.is_empty() && stream.write_all(&reply).is_err() {
+            return;
+        }
+    }
+}
+
+// A packet's fixed header byte and its body, None once the client has gone
+fn read_packet(stream: &mut TcpStream) -> Option<(u8, Vec<u8>)> {
+    let mut byte = [0u8; 1];
+    stream.read_exact(&mut byte).ok()?;
+    let header = byte[0];
+    let mut length = 0;
+    let mut shift = 0;
+    loop {
+        stream.read_exact(&mut byte).ok()?;
+        length |= usize::from(byte[0] & 0x7F) << shift;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    let mut body = vec![0u8; length];
+    stream.read_exact(&mut body).ok()?;
+    Some((header, body))
+}